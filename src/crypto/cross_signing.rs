@@ -0,0 +1,204 @@
+use crate::crypto::signature::{canonical_json, verify_signed_json};
+use crate::crypto::DeviceKey;
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use vodozemac::hazmat::Cipher;
+use vodozemac::Ed25519SecretKey;
+
+pub const MASTER: &str = "master";
+pub const SELF_SIGNING: &str = "self_signing";
+pub const USER_SIGNING: &str = "user_signing";
+
+/// Public part of a cross-signing key as uploaded to and returned by the
+/// server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CrossSigningKey {
+    pub user_id: String,
+    pub usage: Vec<String>,
+    pub keys: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signatures: Option<HashMap<String, HashMap<String, String>>>,
+}
+
+impl CrossSigningKey {
+    pub fn new(user_id: String, usage: &str, ed25519_key: String) -> Self {
+        CrossSigningKey {
+            user_id,
+            usage: vec![usage.to_owned()],
+            keys: HashMap::from([(format!("ed25519:{}", ed25519_key), ed25519_key)]),
+            signatures: None,
+        }
+    }
+
+    pub fn ed25519_key(&self) -> Option<&str> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| key_id.starts_with("ed25519:"))
+            .map(|(_, key)| key.as_str())
+    }
+
+    pub fn has_usage(&self, usage: &str) -> bool {
+        self.usage.iter().any(|u| u == usage)
+    }
+
+    /// Parses a key as returned by `/keys/query`, checking that it belongs
+    /// to `user_id`, is meant for `usage` and, unless it is the master key
+    /// itself, that `json` is signed by `master_key`.
+    pub fn from_signed_json(
+        json: &serde_json::Value,
+        user_id: &str,
+        usage: &str,
+        master_key: Option<&CrossSigningKey>,
+    ) -> Result<Self, Error> {
+        let key: CrossSigningKey = serde_json::from_value(json.clone())
+            .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+        if key.user_id != user_id || !key.has_usage(usage) {
+            return Err(Error::InvalidSignature(format!(
+                "{} key of {} was listed as {} key of {}",
+                key.usage.join(","),
+                key.user_id,
+                usage,
+                user_id
+            )));
+        }
+        if let Some(master_key) = master_key {
+            let master_ed25519 = master_key.ed25519_key().unwrap_or_default();
+            verify_signed_json(
+                json,
+                user_id,
+                &format!("ed25519:{}", master_ed25519),
+                master_ed25519,
+            )?;
+        }
+        Ok(key)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct CrossSigningPickle {
+    user_id: String,
+    master_key: String,
+    self_signing_key: String,
+    user_signing_key: String,
+}
+
+/// Our own master, self-signing and user-signing keys.
+pub struct CrossSigningIdentity {
+    pub user_id: String,
+    master_key: Ed25519SecretKey,
+    self_signing_key: Ed25519SecretKey,
+    user_signing_key: Ed25519SecretKey,
+}
+
+impl CrossSigningIdentity {
+    /// Generates a fresh set of keys for `user_id`.
+    pub fn new(user_id: String) -> Self {
+        CrossSigningIdentity {
+            user_id,
+            master_key: Ed25519SecretKey::new(),
+            self_signing_key: Ed25519SecretKey::new(),
+            user_signing_key: Ed25519SecretKey::new(),
+        }
+    }
+
+    pub fn master_key(&self) -> CrossSigningKey {
+        CrossSigningKey::new(
+            self.user_id.clone(),
+            MASTER,
+            self.master_key.public_key().to_base64(),
+        )
+    }
+
+    /// The self-signing key, signed by the master key.
+    pub fn self_signing_key(&self) -> Result<CrossSigningKey, Error> {
+        self.signed_by_master(CrossSigningKey::new(
+            self.user_id.clone(),
+            SELF_SIGNING,
+            self.self_signing_key.public_key().to_base64(),
+        ))
+    }
+
+    /// The user-signing key, signed by the master key.
+    pub fn user_signing_key(&self) -> Result<CrossSigningKey, Error> {
+        self.signed_by_master(CrossSigningKey::new(
+            self.user_id.clone(),
+            USER_SIGNING,
+            self.user_signing_key.public_key().to_base64(),
+        ))
+    }
+
+    /// Adds the self-signing key's signature to one of our devices.
+    pub fn sign_device(&self, mut device: DeviceKey) -> Result<DeviceKey, Error> {
+        let (key_id, signature) = sign(&self.self_signing_key, &device)?;
+        device
+            .signatures
+            .get_or_insert_with(HashMap::new)
+            .entry(self.user_id.clone())
+            .or_default()
+            .insert(key_id, signature);
+        Ok(device)
+    }
+
+    /// Adds the user-signing key's signature to another user's master key.
+    pub fn sign_user(&self, mut master_key: CrossSigningKey) -> Result<CrossSigningKey, Error> {
+        let (key_id, signature) = sign(&self.user_signing_key, &master_key)?;
+        master_key
+            .signatures
+            .get_or_insert_with(HashMap::new)
+            .entry(self.user_id.clone())
+            .or_default()
+            .insert(key_id, signature);
+        Ok(master_key)
+    }
+
+    pub fn pickle(&self, pickle_key: &[u8; 32]) -> Result<String, Error> {
+        let json = serde_json::to_vec(&CrossSigningPickle {
+            user_id: self.user_id.clone(),
+            master_key: self.master_key.to_base64(),
+            self_signing_key: self.self_signing_key.to_base64(),
+            user_signing_key: self.user_signing_key.to_base64(),
+        })?;
+        Ok(base64::encode_config(
+            Cipher::new_pickle(pickle_key).encrypt_pickle(&json),
+            base64::STANDARD_NO_PAD,
+        ))
+    }
+
+    pub fn from_pickle(pickle: &str, pickle_key: &[u8; 32]) -> Result<Self, Error> {
+        let ciphertext = base64::decode_config(pickle, base64::STANDARD_NO_PAD)
+            .map_err(|e| Error::PickleError(e.to_string()))?;
+        let json = Cipher::new_pickle(pickle_key)
+            .decrypt_pickle(&ciphertext)
+            .map_err(|e| Error::PickleError(e.to_string()))?;
+        let pickle: CrossSigningPickle = serde_json::from_slice(&json)?;
+        let secret_key = |key: &str| {
+            Ed25519SecretKey::from_base64(key).map_err(|e| Error::PickleError(e.to_string()))
+        };
+
+        Ok(CrossSigningIdentity {
+            user_id: pickle.user_id,
+            master_key: secret_key(&pickle.master_key)?,
+            self_signing_key: secret_key(&pickle.self_signing_key)?,
+            user_signing_key: secret_key(&pickle.user_signing_key)?,
+        })
+    }
+
+    fn signed_by_master(&self, mut key: CrossSigningKey) -> Result<CrossSigningKey, Error> {
+        let (key_id, signature) = sign(&self.master_key, &key)?;
+        key.signatures = Some(HashMap::from([(
+            self.user_id.clone(),
+            HashMap::from([(key_id, signature)]),
+        )]));
+        Ok(key)
+    }
+}
+
+/// Signs the canonical JSON of `object`, returning the key ID and signature.
+fn sign<T: Serialize>(key: &Ed25519SecretKey, object: &T) -> Result<(String, String), Error> {
+    let signature = key.sign(canonical_json(object)?.as_bytes());
+    Ok((
+        format!("ed25519:{}", key.public_key().to_base64()),
+        signature.to_base64(),
+    ))
+}
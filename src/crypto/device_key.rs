@@ -1,7 +1,9 @@
+use crate::crypto::signature::verify_signed_json;
+use crate::error::Error;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DeviceKey {
     pub algorithms: Vec<String>,
     pub device_id: String,
@@ -9,6 +11,8 @@ pub struct DeviceKey {
     pub keys: serde_json::Value,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub signatures: Option<HashMap<String, HashMap<String, String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unsigned: Option<serde_json::Value>,
 }
 
 impl DeviceKey {
@@ -29,14 +33,52 @@ impl DeviceKey {
         });
 
         DeviceKey {
-            algorithms: algorithms,
-            device_id: device_id,
-            user_id: user_id,
-            keys: keys,
+            algorithms,
+            device_id,
+            user_id,
+            keys,
             signatures: None,
+            unsigned: None,
         }
     }
 
+    pub fn curve25519_key(&self) -> Option<&str> {
+        self.keys[format!("curve25519:{}", self.device_id)].as_str()
+    }
+
+    pub fn ed25519_key(&self) -> Option<&str> {
+        self.keys[format!("ed25519:{}", self.device_id)].as_str()
+    }
+
+    /// Parses a device key as returned by `/keys/query`, checking that it
+    /// really belongs to `user_id` / `device_id` and that `json` carries a
+    /// valid self-signature from its own ed25519 key.
+    pub fn from_signed_json(
+        json: &serde_json::Value,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<Self, Error> {
+        let device_key: DeviceKey = serde_json::from_value(json.clone())
+            .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+        if device_key.user_id != user_id || device_key.device_id != device_id {
+            return Err(Error::InvalidSignature(format!(
+                "key for {} ({}) was listed under {} ({})",
+                device_key.user_id, device_key.device_id, user_id, device_id
+            )));
+        }
+
+        let ed25519_key = device_key
+            .ed25519_key()
+            .ok_or_else(|| Error::InvalidSignature(format!("{} has no ed25519 key", device_id)))?;
+        verify_signed_json(
+            json,
+            user_id,
+            &format!("ed25519:{}", device_id),
+            ed25519_key,
+        )?;
+        Ok(device_key)
+    }
+
     pub fn sign(mut self, olm: &vodozemac::olm::Account) -> Self {
         let signature = olm.sign(&cjson::to_string(&self).unwrap());
 
@@ -1,18 +1,123 @@
-use serde::Serialize;
+use crate::crypto::DeviceKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 use vodozemac::megolm;
 
+/// Spec default for `rotation_period_ms`: one week.
+pub const DEFAULT_ROTATION_PERIOD_MS: u64 = 604_800_000;
+/// Spec default for `rotation_period_msgs`.
+pub const DEFAULT_ROTATION_PERIOD_MSGS: u64 = 100;
+
 pub struct MegolmSession {
     pub room_id: String,
     pub ratchet: megolm::GroupSession,
+    /// Devices that received the session key, by user and device ID.
+    pub shared_with: HashMap<String, HashMap<String, ShareInfo>>,
+    /// Devices we deliberately did not share the session with, and why.
+    pub withheld_from: HashMap<String, HashMap<String, WithheldCode>>,
+    /// Milliseconds since the Unix epoch at which the session was created.
+    pub created_at: u64,
+    pub rotation: RotationPolicy,
 }
 
-#[derive(Debug, Serialize)]
+/// Why a session key was not sent to a device, as used in
+/// `m.room_key.withheld`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WithheldCode {
+    #[serde(rename = "m.blacklisted")]
+    Blacklisted,
+    #[serde(rename = "m.unverified")]
+    Unverified,
+    #[serde(rename = "m.unauthorised")]
+    Unauthorised,
+    #[serde(rename = "m.unavailable")]
+    Unavailable,
+    #[serde(rename = "m.no_olm")]
+    NoOlm,
+}
+
+/// Content of an `m.room_key.withheld` to-device event.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoomKeyWithheld {
+    pub algorithm: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    pub sender_key: String,
+    pub code: WithheldCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Content of an `m.room_key_request` to-device event.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoomKeyRequest {
+    /// `request` or `request_cancellation`.
+    pub action: String,
+    /// Only present when `action` is `request`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<RequestedKeyInfo>,
+    pub request_id: String,
+    pub requesting_device_id: String,
+}
+
+/// The session a room key request asks for.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RequestedKeyInfo {
+    pub algorithm: String,
+    pub room_id: String,
+    pub sender_key: String,
+    pub session_id: String,
+}
+
+/// Content of an `m.forwarded_room_key` to-device event.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ForwardedRoomKey {
+    pub algorithm: String,
+    pub room_id: String,
+    pub sender_key: String,
+    pub session_id: String,
+    /// The session exported at some message index, see
+    /// `megolm::ExportedSessionKey`.
+    pub session_key: String,
+    pub sender_claimed_ed25519_key: String,
+    #[serde(default)]
+    pub forwarding_curve25519_key_chain: Vec<String>,
+}
+
+/// When an outbound session has to be replaced, as configured by the
+/// room's `m.room.encryption` state event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RotationPolicy {
+    pub rotation_period_ms: u64,
+    pub rotation_period_msgs: u64,
+}
+
+/// Records that a device got the session key, and from which message index
+/// on it is able to decrypt.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ShareInfo {
+    pub curve25519_key: String,
+    pub message_index: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MegolmMessage {
     pub algorithm: String,
     pub sender_key: String,
     pub ciphertext: String,
     pub session_id: String,
     pub device_id: String,
+    /// Copied from the encrypted content, so the server can aggregate
+    /// relations without decrypting anything.
+    #[serde(
+        rename = "m.relates_to",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub relates_to: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize)]
@@ -22,17 +127,118 @@ pub struct PlainTextContent {
 }
 
 #[derive(Debug, Serialize)]
-pub struct PlainTextMessage {
+pub struct PlainTextMessage<C = PlainTextContent> {
     pub r#type: String,
-    pub content: PlainTextContent,
+    pub content: C,
     pub room_id: String,
 }
 
+/// An `m.room.encrypted` room event after the Megolm layer has been removed.
+#[derive(Debug)]
+pub struct DecryptedRoomEvent {
+    pub event_id: String,
+    pub room_id: String,
+    pub sender: String,
+    pub sender_key: String,
+    pub session_id: String,
+    pub message_index: u32,
+    pub r#type: String,
+    pub content: serde_json::Value,
+}
+
 impl MegolmSession {
     pub fn new(room_id: String, ratchet: megolm::GroupSession) -> Self {
         MegolmSession {
-            room_id: room_id,
-            ratchet: ratchet,
+            room_id,
+            ratchet,
+            shared_with: HashMap::new(),
+            withheld_from: HashMap::new(),
+            created_at: now_ms(),
+            rotation: RotationPolicy::default(),
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: RotationPolicy) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Whether the session is older than the rotation period or has
+    /// encrypted as many messages as the policy allows.
+    pub fn needs_rotation(&self) -> bool {
+        u64::from(self.ratchet.message_index()) >= self.rotation.rotation_period_msgs
+            || now_ms().saturating_sub(self.created_at) >= self.rotation.rotation_period_ms
+    }
+
+    /// Whether a device that received the session key is not among `devices`
+    /// any more, because its user left the room, the device was removed or
+    /// its identity key changed.
+    pub fn has_removed_recipients(&self, devices: &[DeviceKey]) -> bool {
+        self.shared_with.iter().any(|(user_id, shared_devices)| {
+            shared_devices.iter().any(|(device_id, share_info)| {
+                !devices.iter().any(|device| {
+                    &device.user_id == user_id
+                        && &device.device_id == device_id
+                        && device.curve25519_key() == Some(share_info.curve25519_key.as_str())
+                })
+            })
+        })
+    }
+
+    pub fn is_shared_with(&self, user_id: &str, device_id: &str) -> bool {
+        self.shared_with
+            .get(user_id)
+            .map(|devices| devices.contains_key(device_id))
+            .unwrap_or(false)
+    }
+
+    /// Remembers that `device` now holds the session key at the current
+    /// message index.
+    pub fn mark_shared_with(&mut self, device: &DeviceKey) {
+        self.shared_with
+            .entry(device.user_id.clone())
+            .or_default()
+            .insert(
+                device.device_id.clone(),
+                ShareInfo {
+                    curve25519_key: device.curve25519_key().unwrap_or_default().to_owned(),
+                    message_index: self.ratchet.message_index(),
+                },
+            );
+    }
+
+    /// Whether `code` was already sent to the device as the reason for not
+    /// sharing this session with it.
+    pub fn is_withheld_from(&self, user_id: &str, device_id: &str, code: WithheldCode) -> bool {
+        self.withheld_from
+            .get(user_id)
+            .and_then(|devices| devices.get(device_id))
+            == Some(&code)
+    }
+
+    pub fn mark_withheld_from(&mut self, device: &DeviceKey, code: WithheldCode) {
+        self.withheld_from
+            .entry(device.user_id.clone())
+            .or_default()
+            .insert(device.device_id.clone(), code);
+    }
+
+    /// The withheld notice telling a device that it will not get this
+    /// session's key.
+    /// `m.no_olm` is about the device rather than this session, so it leaves
+    /// out the room and session ID.
+    pub fn withheld_notice(&self, sender_key: String, code: WithheldCode) -> RoomKeyWithheld {
+        let (room_id, session_id) = match code {
+            WithheldCode::NoOlm => (None, None),
+            _ => (Some(self.room_id.clone()), Some(self.ratchet.session_id())),
+        };
+        RoomKeyWithheld {
+            algorithm: String::from("m.megolm.v1.aes-sha2"),
+            room_id,
+            session_id,
+            sender_key,
+            code,
+            reason: Some(code.to_string()),
         }
     }
 
@@ -42,12 +248,32 @@ impl MegolmSession {
         device_id: String,
         content: &str,
     ) -> MegolmMessage {
-        let message_payload = PlainTextMessage {
-            r#type: String::from("m.room.message"),
-            content: PlainTextContent {
+        self.create_event(
+            sender_key,
+            device_id,
+            "m.room.message",
+            PlainTextContent {
                 msgtype: String::from("m.text"),
                 body: content.to_owned(),
             },
+        )
+    }
+
+    /// Encrypts a room event of any type, keeping its `m.relates_to` in the
+    /// clear.
+    pub fn create_event<C: Serialize>(
+        &mut self,
+        sender_key: String,
+        device_id: String,
+        event_type: &str,
+        content: C,
+    ) -> MegolmMessage {
+        let relates_to = serde_json::to_value(&content)
+            .ok()
+            .and_then(|content| content.get("m.relates_to").cloned());
+        let message_payload = PlainTextMessage {
+            r#type: String::from(event_type),
+            content,
             room_id: self.room_id.clone(),
         };
         let json_string = serde_json::to_string(&message_payload).unwrap();
@@ -55,10 +281,62 @@ impl MegolmSession {
 
         MegolmMessage {
             algorithm: String::from("m.megolm.v1.aes-sha2"),
-            sender_key: sender_key,
-            ciphertext: ciphertext,
+            sender_key,
+            ciphertext,
             session_id: self.ratchet.session_id(),
-            device_id: device_id,
+            device_id,
+            relates_to,
+        }
+    }
+}
+
+impl RotationPolicy {
+    /// Builds the policy from the optional `m.room.encryption` fields,
+    /// using the spec defaults for the ones that are missing.
+    pub fn new(rotation_period_ms: Option<u64>, rotation_period_msgs: Option<u64>) -> Self {
+        RotationPolicy {
+            rotation_period_ms: rotation_period_ms.unwrap_or(DEFAULT_ROTATION_PERIOD_MS),
+            rotation_period_msgs: rotation_period_msgs.unwrap_or(DEFAULT_ROTATION_PERIOD_MSGS),
+        }
+    }
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy::new(None, None)
+    }
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl WithheldCode {
+    /// The code as it appears on the wire, e.g. `m.blacklisted`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WithheldCode::Blacklisted => "m.blacklisted",
+            WithheldCode::Unverified => "m.unverified",
+            WithheldCode::Unauthorised => "m.unauthorised",
+            WithheldCode::Unavailable => "m.unavailable",
+            WithheldCode::NoOlm => "m.no_olm",
+        }
+    }
+}
+
+impl std::fmt::Display for WithheldCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WithheldCode::Blacklisted => write!(f, "The sender has blocked this device"),
+            WithheldCode::Unverified => {
+                write!(f, "The sender does not share keys with unverified devices")
+            }
+            WithheldCode::Unauthorised => write!(f, "This device is not allowed to see the key"),
+            WithheldCode::Unavailable => write!(f, "The requested key is not available"),
+            WithheldCode::NoOlm => write!(f, "Unable to establish a secure channel"),
         }
     }
 }
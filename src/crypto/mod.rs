@@ -1,3 +1,6 @@
+pub mod cross_signing;
+pub use cross_signing::{CrossSigningIdentity, CrossSigningKey};
+
 pub mod device_key;
 pub use device_key::DeviceKey;
 
@@ -5,7 +8,21 @@ pub mod one_time_key;
 pub use one_time_key::OneTimeKey;
 
 pub mod megolm_sha2;
-pub use megolm_sha2::{MegolmMessage, MegolmSession};
+pub use megolm_sha2::{
+    DecryptedRoomEvent, ForwardedRoomKey, MegolmMessage, MegolmSession, RequestedKeyInfo,
+    RoomKeyRequest, RoomKeyWithheld, RotationPolicy, ShareInfo, WithheldCode,
+};
 
 pub mod olm_sha256;
-pub use olm_sha256::OlmExchange;
+pub use olm_sha256::{DecryptedOlmEvent, OlmExchange};
+
+pub mod pickle;
+pub use pickle::DevicePickle;
+
+pub mod qr_code;
+pub use qr_code::{QrCode, QrMode};
+
+pub mod signature;
+
+pub mod verification;
+pub use verification::{Verification, VerificationState};
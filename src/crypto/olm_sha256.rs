@@ -1,9 +1,9 @@
 use crate::crypto::DeviceKey;
 use crate::device::Device;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct KeyExchangeData {
     pub algorithm: String,
     pub room_id: String,
@@ -12,29 +12,46 @@ pub struct KeyExchangeData {
 }
 
 #[derive(Debug, Serialize)]
-pub struct KeyExchangeEvent {
+pub struct KeyExchangeEvent<C = KeyExchangeData> {
     pub sender: String,
     pub sender_device: String,
     pub keys: HashMap<String, String>,
     pub recipient: String,
     pub recipient_keys: HashMap<String, String>,
     pub r#type: String,
-    pub content: KeyExchangeData,
+    pub content: C,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct RoomEncryptedOLM {
     pub r#type: i8,
     pub body: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct OlmExchange {
     pub algorithm: String,
     pub sender_key: String,
     pub ciphertext: HashMap<String, RoomEncryptedOLM>,
 }
 
+/// Plaintext of an `m.room.encrypted` to-device event once the Olm layer has
+/// been removed.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DecryptedOlmEvent {
+    pub sender: String,
+    #[serde(default)]
+    pub sender_device: Option<String>,
+    pub keys: HashMap<String, String>,
+    pub recipient: String,
+    pub recipient_keys: HashMap<String, String>,
+    pub r#type: String,
+    pub content: serde_json::Value,
+    /// Curve25519 identity key of the Olm session the event arrived on.
+    #[serde(skip)]
+    pub sender_key: String,
+}
+
 impl OlmExchange {
     pub fn new(
         sender_device: &Device,
@@ -45,11 +62,29 @@ impl OlmExchange {
     ) -> Self {
         let key_exchange_data = KeyExchangeData {
             algorithm: String::from("m.megolm.v1.aes-sha2"),
-            room_id: room_id,
+            room_id,
             session_id: megolm_session.session_id(),
             session_key: megolm_session.session_key().to_base64(),
         };
 
+        OlmExchange::encrypt_event(
+            sender_device,
+            recipient_device,
+            olm_session,
+            "m.room_key",
+            key_exchange_data,
+        )
+    }
+
+    /// Encrypts a to-device event of type `event_type` for `recipient_device`
+    /// over `olm_session`.
+    pub fn encrypt_event<C: Serialize>(
+        sender_device: &Device,
+        recipient_device: &DeviceKey,
+        olm_session: &mut vodozemac::olm::Session,
+        event_type: &str,
+        content: C,
+    ) -> Self {
         let key_exchange_event = KeyExchangeEvent {
             sender: sender_device.user_id.clone(),
             sender_device: sender_device.device_id.clone(),
@@ -62,15 +97,15 @@ impl OlmExchange {
                     .unwrap()
                     .to_owned(),
             )]),
-            r#type: String::from("m.room_key"),
-            content: key_exchange_data,
+            r#type: String::from(event_type),
+            content,
         };
 
         let json_payload = serde_json::to_string(&key_exchange_event).unwrap();
-        let encrypted_payload = olm_session.encrypt(json_payload).to_parts().1;
+        let (message_type, encrypted_payload) = olm_session.encrypt(json_payload).to_parts();
 
         let room_olm = RoomEncryptedOLM {
-            r#type: 0,
+            r#type: message_type as i8,
             body: encrypted_payload,
         };
 
@@ -84,7 +119,7 @@ impl OlmExchange {
         OlmExchange {
             algorithm: String::from("m.olm.v1.curve25519-aes-sha2"),
             sender_key: sender_device.curve25519_key(),
-            ciphertext: ciphertext,
+            ciphertext,
         }
     }
 }
@@ -1,3 +1,5 @@
+use crate::crypto::signature::verify_signed_json;
+use crate::error::Error;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -7,6 +9,8 @@ pub struct OneTimeKey {
     pub id: String,
     #[serde(rename = "key")]
     pub curve25519_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub signatures: Option<HashMap<String, HashMap<String, String>>>,
 }
@@ -14,12 +18,40 @@ pub struct OneTimeKey {
 impl OneTimeKey {
     pub fn new(id: String, curve25519_key: String) -> Self {
         OneTimeKey {
-            id: id,
-            curve25519_key: curve25519_key,
+            id,
+            curve25519_key,
+            fallback: None,
             signatures: None,
         }
     }
 
+    pub fn new_fallback(id: String, curve25519_key: String) -> Self {
+        OneTimeKey {
+            id,
+            curve25519_key,
+            fallback: Some(true),
+            signatures: None,
+        }
+    }
+
+    /// Parses a key as returned by `/keys/claim`, checking the signature
+    /// the owning device made over `json`.
+    pub fn from_signed_json(
+        json: &serde_json::Value,
+        user_id: &str,
+        device_id: &str,
+        ed25519_key: &str,
+    ) -> Result<Self, Error> {
+        verify_signed_json(
+            json,
+            user_id,
+            &format!("ed25519:{}", device_id),
+            ed25519_key,
+        )
+        .map_err(|e| Error::InvalidOneTimeKey(e.to_string()))?;
+        serde_json::from_value(json.clone()).map_err(|e| Error::InvalidOneTimeKey(e.to_string()))
+    }
+
     pub fn sign(
         mut self,
         olm: &vodozemac::olm::Account,
@@ -0,0 +1,99 @@
+use crate::error::Error;
+use hmac::Hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use vodozemac::{megolm, olm};
+
+const PBKDF2_ROUNDS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+
+/// Derives the 32 byte key vodozemac expects for encrypting pickles
+/// from a user supplied passphrase.
+pub fn derive_pickle_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+pub fn generate_salt() -> [u8; SALT_LENGTH] {
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+pub fn encode_salt(salt: &[u8]) -> String {
+    base64::encode_config(salt, base64::STANDARD_NO_PAD)
+}
+
+pub fn decode_salt(salt: &str) -> Result<Vec<u8>, Error> {
+    base64::decode_config(salt, base64::STANDARD_NO_PAD)
+        .map_err(|e| Error::PickleError(format!("Invalid salt: {}", e)))
+}
+
+/// Passphrase-encrypted snapshot of a `Device`'s crypto state.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DevicePickle {
+    pub user_id: String,
+    pub device_id: String,
+    pub salt: String,
+    pub olm_account: String,
+    pub megolm_sessions: HashMap<String, String>,
+}
+
+impl DevicePickle {
+    pub fn new<'a>(
+        user_id: String,
+        device_id: String,
+        passphrase: &str,
+        olm_account: &olm::Account,
+        megolm_sessions: impl IntoIterator<Item = (&'a String, &'a megolm::GroupSession)>,
+    ) -> Self {
+        let salt = generate_salt();
+        let pickle_key = derive_pickle_key(passphrase, &salt);
+
+        DevicePickle {
+            user_id,
+            device_id,
+            salt: encode_salt(&salt),
+            olm_account: olm_account.pickle().encrypt(&pickle_key),
+            megolm_sessions: megolm_sessions
+                .into_iter()
+                .map(|(room_id, session)| (room_id.clone(), session.pickle().encrypt(&pickle_key)))
+                .collect(),
+        }
+    }
+
+    pub fn unpickle(
+        &self,
+        passphrase: &str,
+    ) -> Result<(olm::Account, HashMap<String, megolm::GroupSession>), Error> {
+        let pickle_key = derive_pickle_key(passphrase, &decode_salt(&self.salt)?);
+
+        let olm_account = olm::Account::from_pickle(olm::AccountPickle::from_encrypted(
+            &self.olm_account,
+            &pickle_key,
+        )?);
+
+        let mut megolm_sessions = HashMap::new();
+        for (room_id, pickle) in &self.megolm_sessions {
+            let session = megolm::GroupSession::from_pickle(
+                megolm::GroupSessionPickle::from_encrypted(pickle, &pickle_key)?,
+            );
+            megolm_sessions.insert(room_id.clone(), session);
+        }
+
+        Ok((olm_account, megolm_sessions))
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<(), Error> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &std::path::Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| Error::PickleError(e.to_string()))
+    }
+}
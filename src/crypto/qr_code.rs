@@ -0,0 +1,181 @@
+use crate::error::Error;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::Color;
+use vodozemac::Ed25519PublicKey;
+
+const PREFIX: &[u8] = b"MATRIX";
+const VERSION: u8 = 0x02;
+/// Pixels per module and modules of quiet zone in the PNG.
+const PNG_SCALE: usize = 8;
+const PNG_QUIET_ZONE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QrMode {
+    /// Verifying another user. The keys are the master key of the showing
+    /// user and the master key it thinks the scanning user has.
+    VerifyingAnotherUser,
+    /// Verifying our own device, from a device that trusts the master key.
+    /// The keys are the master key and the key it thinks the scanning
+    /// device has.
+    SelfVerifyingMasterKeyTrusted,
+    /// Verifying our own device, from a device that doesn't trust the master
+    /// key yet. The keys are its device key and the master key it thinks
+    /// the user has.
+    SelfVerifyingMasterKeyUntrusted,
+}
+
+impl QrMode {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            QrMode::VerifyingAnotherUser => 0x00,
+            QrMode::SelfVerifyingMasterKeyTrusted => 0x01,
+            QrMode::SelfVerifyingMasterKeyUntrusted => 0x02,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(QrMode::VerifyingAnotherUser),
+            0x01 => Some(QrMode::SelfVerifyingMasterKeyTrusted),
+            0x02 => Some(QrMode::SelfVerifyingMasterKeyUntrusted),
+            _ => None,
+        }
+    }
+}
+
+/// The `MATRIX` payload of a verification QR code. Keys are unpadded base64
+/// like everywhere else.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QrCode {
+    pub mode: QrMode,
+    pub flow_id: String,
+    pub first_key: String,
+    pub second_key: String,
+    pub shared_secret: Vec<u8>,
+}
+
+impl QrCode {
+    /// A code with a fresh random shared secret.
+    pub fn new(mode: QrMode, flow_id: String, first_key: String, second_key: String) -> Self {
+        QrCode {
+            mode,
+            flow_id,
+            first_key,
+            second_key,
+            shared_secret: rand::random::<[u8; 16]>().to_vec(),
+        }
+    }
+
+    /// The shared secret as sent in `m.key.verification.start`.
+    pub fn secret(&self) -> String {
+        base64::encode_config(&self.shared_secret, base64::STANDARD_NO_PAD)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let flow_id_length = u16::try_from(self.flow_id.len())
+            .map_err(|_| Error::VerificationError(String::from("flow ID too long")))?;
+        let mut bytes = PREFIX.to_vec();
+        bytes.push(VERSION);
+        bytes.push(self.mode.as_byte());
+        bytes.extend(flow_id_length.to_be_bytes());
+        bytes.extend(self.flow_id.as_bytes());
+        for key in [&self.first_key, &self.second_key] {
+            let key = Ed25519PublicKey::from_base64(key)
+                .map_err(|e| Error::VerificationError(e.to_string()))?;
+            bytes.extend(key.as_bytes());
+        }
+        bytes.extend(&self.shared_secret);
+        Ok(bytes)
+    }
+
+    /// Parses a scanned payload, checking its header and lengths.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid =
+            |reason: &str| Error::VerificationError(format!("Invalid QR code: {}", reason));
+
+        let rest = bytes
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| invalid("missing MATRIX prefix"))?;
+        let (header, rest) = split(rest, 4).ok_or_else(|| invalid("truncated header"))?;
+        if header[0] != VERSION {
+            return Err(invalid(&format!("unsupported version {}", header[0])));
+        }
+        let mode = QrMode::from_byte(header[1])
+            .ok_or_else(|| invalid(&format!("unknown mode {}", header[1])))?;
+        let flow_id_length = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+        let (flow_id, rest) =
+            split(rest, flow_id_length).ok_or_else(|| invalid("truncated flow ID"))?;
+        let flow_id =
+            String::from_utf8(flow_id.to_vec()).map_err(|_| invalid("flow ID isn't UTF-8"))?;
+        let (first_key, rest) = split(rest, 32).ok_or_else(|| invalid("truncated key"))?;
+        let (second_key, shared_secret) =
+            split(rest, 32).ok_or_else(|| invalid("truncated key"))?;
+        if shared_secret.len() < 8 {
+            return Err(invalid("shared secret is too short"));
+        }
+
+        let key = |bytes: &[u8]| {
+            Ed25519PublicKey::from_slice(bytes)
+                .map(|key| key.to_base64())
+                .map_err(|e| invalid(&e.to_string()))
+        };
+        Ok(QrCode {
+            mode,
+            flow_id,
+            first_key: key(first_key)?,
+            second_key: key(second_key)?,
+            shared_secret: shared_secret.to_vec(),
+        })
+    }
+
+    /// Renders the code with block characters, to print in a terminal.
+    pub fn to_terminal(&self) -> Result<String, Error> {
+        Ok(self
+            .encode()?
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build())
+    }
+
+    /// Renders the code as a grayscale PNG image.
+    pub fn to_png(&self) -> Result<Vec<u8>, Error> {
+        let code = self.encode()?;
+        let colors = code.to_colors();
+        let width = code.width();
+        let size = (width + 2 * PNG_QUIET_ZONE) * PNG_SCALE;
+
+        let mut pixels = vec![0xff; size * size];
+        for (index, color) in colors.iter().enumerate() {
+            if *color == Color::Light {
+                continue;
+            }
+            let (x, y) = (
+                index % width + PNG_QUIET_ZONE,
+                index / width + PNG_QUIET_ZONE,
+            );
+            for row in y * PNG_SCALE..(y + 1) * PNG_SCALE {
+                pixels[row * size + x * PNG_SCALE..row * size + (x + 1) * PNG_SCALE].fill(0);
+            }
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(|e| Error::IOError(e.to_string()))?;
+        Ok(png)
+    }
+
+    fn encode(&self) -> Result<qrcode::QrCode, Error> {
+        qrcode::QrCode::new(self.to_bytes()?).map_err(|e| Error::VerificationError(e.to_string()))
+    }
+}
+
+fn split(bytes: &[u8], at: usize) -> Option<(&[u8], &[u8])> {
+    (bytes.len() >= at).then(|| bytes.split_at(at))
+}
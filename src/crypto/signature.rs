@@ -0,0 +1,54 @@
+use crate::error::Error;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Canonical JSON of a signed object, without the `signatures` and
+/// `unsigned` fields that are not covered by the signature.
+pub fn canonical_json<T: Serialize>(object: &T) -> Result<String, Error> {
+    let mut value =
+        serde_json::to_value(object).map_err(|e| Error::InvalidSignature(e.to_string()))?;
+    if let Some(map) = value.as_object_mut() {
+        map.remove("signatures");
+        map.remove("unsigned");
+    }
+    cjson::to_string(&value).map_err(|e| Error::InvalidSignature(format!("{:?}", e)))
+}
+
+/// Checks the ed25519 signature `signer` made with `key_id` over `object`.
+pub fn verify_json<T: Serialize>(
+    object: &T,
+    signatures: Option<
+        &std::collections::HashMap<String, std::collections::HashMap<String, String>>,
+    >,
+    signer: &str,
+    key_id: &str,
+    ed25519_key: &str,
+) -> Result<(), Error> {
+    let signature = signatures
+        .and_then(|signatures| signatures.get(signer))
+        .and_then(|signatures| signatures.get(key_id))
+        .ok_or_else(|| Error::InvalidSignature(format!("no signature by {} {}", signer, key_id)))?;
+
+    let public_key = vodozemac::Ed25519PublicKey::from_base64(ed25519_key)
+        .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+    let signature = vodozemac::Ed25519Signature::from_base64(signature)
+        .map_err(|e| Error::InvalidSignature(e.to_string()))?;
+
+    public_key
+        .verify(canonical_json(object)?.as_bytes(), &signature)
+        .map_err(|e| Error::InvalidSignature(format!("{} {}: {}", signer, key_id, e)))
+}
+
+/// Same as `verify_json` for a signed object as it was received. Checking
+/// the received JSON rather than a parsed copy also covers the fields we
+/// don't know about.
+pub fn verify_signed_json(
+    json: &serde_json::Value,
+    signer: &str,
+    key_id: &str,
+    ed25519_key: &str,
+) -> Result<(), Error> {
+    let signatures: Option<HashMap<String, HashMap<String, String>>> =
+        serde_json::from_value(json["signatures"].clone()).unwrap_or_default();
+    verify_json(json, signatures.as_ref(), signer, key_id, ed25519_key)
+}
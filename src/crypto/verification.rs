@@ -0,0 +1,842 @@
+use crate::crypto::qr_code::{QrCode, QrMode};
+use crate::crypto::DeviceKey;
+use crate::device::Device;
+use crate::error::Error;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use vodozemac::sas::{EstablishedSas, Mac, Sas};
+use vodozemac::Curve25519PublicKey;
+
+pub const SAS_V1: &str = "m.sas.v1";
+pub const QR_CODE_SHOW_V1: &str = "m.qr_code.show.v1";
+pub const QR_CODE_SCAN_V1: &str = "m.qr_code.scan.v1";
+pub const RECIPROCATE_V1: &str = "m.reciprocate.v1";
+const KEY_AGREEMENT_PROTOCOL: &str = "curve25519-hkdf-sha256";
+const HASH: &str = "sha256";
+const MAC_HKDF_HMAC_SHA256_V2: &str = "hkdf-hmac-sha256.v2";
+const MAC_HKDF_HMAC_SHA256: &str = "hkdf-hmac-sha256";
+const SHORT_AUTHENTICATION_STRINGS: [&str; 2] = ["decimal", "emoji"];
+
+/// The emoji table of the spec, indexed by the 6 bit groups of the SAS.
+pub const SAS_EMOJI: [(&str, &str); 64] = [
+    ("🐶", "Dog"),
+    ("🐱", "Cat"),
+    ("🦁", "Lion"),
+    ("🐎", "Horse"),
+    ("🦄", "Unicorn"),
+    ("🐷", "Pig"),
+    ("🐘", "Elephant"),
+    ("🐰", "Rabbit"),
+    ("🐼", "Panda"),
+    ("🐓", "Rooster"),
+    ("🐧", "Penguin"),
+    ("🐢", "Turtle"),
+    ("🐟", "Fish"),
+    ("🐙", "Octopus"),
+    ("🦋", "Butterfly"),
+    ("🌷", "Flower"),
+    ("🌳", "Tree"),
+    ("🌵", "Cactus"),
+    ("🍄", "Mushroom"),
+    ("🌏", "Globe"),
+    ("🌙", "Moon"),
+    ("☁️", "Cloud"),
+    ("🔥", "Fire"),
+    ("🍌", "Banana"),
+    ("🍎", "Apple"),
+    ("🍓", "Strawberry"),
+    ("🌽", "Corn"),
+    ("🍕", "Pizza"),
+    ("🎂", "Cake"),
+    ("❤️", "Heart"),
+    ("😀", "Smiley"),
+    ("🤖", "Robot"),
+    ("🎩", "Hat"),
+    ("👓", "Glasses"),
+    ("🔧", "Spanner"),
+    ("🎅", "Santa"),
+    ("👍", "Thumbs Up"),
+    ("☂️", "Umbrella"),
+    ("⌛", "Hourglass"),
+    ("⏰", "Clock"),
+    ("🎁", "Gift"),
+    ("💡", "Light Bulb"),
+    ("📕", "Book"),
+    ("✏️", "Pencil"),
+    ("📎", "Paperclip"),
+    ("✂️", "Scissors"),
+    ("🔒", "Lock"),
+    ("🔑", "Key"),
+    ("🔨", "Hammer"),
+    ("☎️", "Telephone"),
+    ("🏁", "Flag"),
+    ("🚂", "Train"),
+    ("🚲", "Bicycle"),
+    ("✈️", "Aeroplane"),
+    ("🚀", "Rocket"),
+    ("🏆", "Trophy"),
+    ("⚽", "Ball"),
+    ("🎸", "Guitar"),
+    ("🎺", "Trumpet"),
+    ("🔔", "Bell"),
+    ("⚓", "Anchor"),
+    ("🎧", "Headphones"),
+    ("📁", "Folder"),
+    ("📌", "Pin"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerificationState {
+    /// A request was sent or received, nobody answered it yet.
+    Requested,
+    /// Both sides agreed to verify, nobody picked a method yet.
+    Ready,
+    /// `m.key.verification.start` was sent or received.
+    Started,
+    /// Both SAS keys are known, the emoji or decimals can be compared.
+    KeysExchanged,
+    /// A QR code was scanned and its shared secret sent back. The showing
+    /// side has to confirm that the other device scanned it.
+    Reciprocated,
+    /// We confirmed that the short authentication strings match, or that
+    /// our QR code was scanned.
+    Confirmed,
+    Done,
+    Cancelled {
+        code: String,
+        reason: String,
+    },
+}
+
+/// One `m.key.verification.*` flow with another device, using `m.sas.v1`.
+///
+/// The state machine does no I/O: every step returns the events to send to
+/// the other device as `(event type, content)` pairs.
+pub struct Verification {
+    /// The `transaction_id` shared by all events of the flow, or the event
+    /// ID of the request when the flow runs in a room.
+    pub flow_id: String,
+    /// The DM the flow runs in, `None` for to-device flows.
+    pub room_id: Option<String>,
+    pub other_user_id: String,
+    pub other_device_id: Option<String>,
+    pub we_requested: bool,
+    pub state: VerificationState,
+    user_id: String,
+    device_id: String,
+    ed25519_key: String,
+    other_device: Option<DeviceKey>,
+    we_started: bool,
+    start_content: Option<Value>,
+    sas: Option<Sas>,
+    established: Option<EstablishedSas>,
+    commitment: Option<String>,
+    mac_method: String,
+    their_mac: Option<Value>,
+    verified: bool,
+    verified_device: bool,
+    verified_master_key: Option<String>,
+    done_received: bool,
+    qr_code: Option<QrCode>,
+    scanned_qr_mode: Option<QrMode>,
+    own_master_key: Option<String>,
+    own_master_key_trusted: bool,
+    other_master_key: Option<String>,
+}
+
+impl Verification {
+    pub fn new(
+        device: &Device,
+        flow_id: String,
+        other_user_id: String,
+        other_device_id: Option<String>,
+        we_requested: bool,
+    ) -> Self {
+        Verification {
+            flow_id,
+            room_id: None,
+            other_user_id,
+            other_device_id,
+            we_requested,
+            state: VerificationState::Requested,
+            user_id: device.user_id.clone(),
+            device_id: device.device_id.clone(),
+            ed25519_key: device.ed25519_key(),
+            other_device: None,
+            we_started: false,
+            start_content: None,
+            sas: None,
+            established: None,
+            commitment: None,
+            mac_method: String::from(MAC_HKDF_HMAC_SHA256_V2),
+            their_mac: None,
+            verified: false,
+            verified_device: false,
+            verified_master_key: None,
+            done_received: false,
+            qr_code: None,
+            scanned_qr_mode: None,
+            own_master_key: None,
+            own_master_key_trusted: false,
+            other_master_key: None,
+        }
+    }
+
+    /// Runs the flow as events in `room_id` instead of to-device messages.
+    pub fn in_room(mut self, room_id: &str) -> Self {
+        self.room_id = Some(room_id.to_owned());
+        self
+    }
+
+    /// The keys of the other device, needed to check its MAC.
+    pub fn set_other_device(&mut self, device: DeviceKey) {
+        self.other_device_id = Some(device.device_id.clone());
+        self.other_device = Some(device);
+    }
+
+    /// The cross-signing master keys QR codes are built from and checked
+    /// against: ours, whether this device trusts it, and the other user's.
+    /// `request` and `accept` only offer QR codes once they are known.
+    pub fn set_master_keys(
+        &mut self,
+        own_master_key: Option<String>,
+        own_master_key_trusted: bool,
+        other_master_key: Option<String>,
+    ) {
+        self.own_master_key = own_master_key;
+        self.own_master_key_trusted = own_master_key_trusted;
+        self.other_master_key = other_master_key;
+    }
+
+    pub fn other_device(&self) -> Option<&DeviceKey> {
+        self.other_device.as_ref()
+    }
+
+    /// Whether the flow succeeded on our side. What it proved is told by
+    /// `is_device_verified` and `verified_master_key`.
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    /// Whether the other device proved it holds the keys we know for it,
+    /// by SAS or by a QR code holding its device key.
+    pub fn is_device_verified(&self) -> bool {
+        self.verified_device
+    }
+
+    /// The master key of the other user a QR code proved, for the modes
+    /// that vouch for a master key rather than for the other device. When
+    /// verifying our own devices it is our own master key.
+    pub fn verified_master_key(&self) -> Option<&str> {
+        self.verified_master_key.as_deref()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.state, VerificationState::Cancelled { .. })
+    }
+
+    /// The event asking the other user to verify. In a room it's an
+    /// `m.room.message` whose event ID becomes the flow ID.
+    pub fn request(&self) -> (String, Value) {
+        if self.room_id.is_some() {
+            return (
+                String::from("m.room.message"),
+                json!({
+                    "msgtype": "m.key.verification.request",
+                    "body": format!("{} is requesting to verify your device", self.user_id),
+                    "from_device": self.device_id,
+                    "methods": self.methods(),
+                    "to": self.other_user_id,
+                }),
+            );
+        }
+        self.message(
+            "m.key.verification.request",
+            json!({
+                "from_device": self.device_id,
+                "methods": self.methods(),
+                "timestamp": crate::crypto::megolm_sha2::now_ms(),
+            }),
+        )
+    }
+
+    /// Answers an incoming request with `m.key.verification.ready`.
+    pub fn accept(&mut self) -> Vec<(String, Value)> {
+        if self.we_requested || self.state != VerificationState::Requested {
+            return self.unexpected("accept");
+        }
+        self.state = VerificationState::Ready;
+        vec![self.message(
+            "m.key.verification.ready",
+            json!({"from_device": self.device_id, "methods": self.methods()}),
+        )]
+    }
+
+    /// Starts the SAS exchange once both sides are ready.
+    pub fn start_sas(&mut self) -> Vec<(String, Value)> {
+        if self.state != VerificationState::Ready {
+            return self.unexpected("start");
+        }
+        let (event_type, content) = self.message(
+            "m.key.verification.start",
+            json!({
+                "from_device": self.device_id,
+                "method": SAS_V1,
+                "key_agreement_protocols": [KEY_AGREEMENT_PROTOCOL],
+                "hashes": [HASH],
+                "message_authentication_codes": [MAC_HKDF_HMAC_SHA256_V2, MAC_HKDF_HMAC_SHA256],
+                "short_authentication_string": SHORT_AUTHENTICATION_STRINGS,
+            }),
+        );
+        self.state = VerificationState::Started;
+        self.we_started = true;
+        self.sas = Some(Sas::new());
+        self.start_content = Some(content.clone());
+        vec![(event_type, content)]
+    }
+
+    /// The QR code for the other device to scan, once both sides are ready.
+    pub fn show_qr_code(&mut self) -> Result<QrCode, Error> {
+        if self.state != VerificationState::Ready {
+            return Err(Error::VerificationError(format!(
+                "Can't show a QR code in state {:?}",
+                self.state
+            )));
+        }
+        let missing = |key: &str| Error::VerificationError(format!("{} is unknown", key));
+        let own_master_key = self
+            .own_master_key
+            .clone()
+            .ok_or_else(|| missing("Our master key"))?;
+        let (mode, first_key, second_key) = if self.other_user_id != self.user_id {
+            let other_master_key = self
+                .other_master_key
+                .clone()
+                .ok_or_else(|| missing("The other user's master key"))?;
+            (
+                QrMode::VerifyingAnotherUser,
+                own_master_key,
+                other_master_key,
+            )
+        } else if self.own_master_key_trusted {
+            let other_device_key = self
+                .other_device
+                .as_ref()
+                .and_then(DeviceKey::ed25519_key)
+                .ok_or_else(|| missing("The other device's key"))?
+                .to_owned();
+            (
+                QrMode::SelfVerifyingMasterKeyTrusted,
+                own_master_key,
+                other_device_key,
+            )
+        } else {
+            (
+                QrMode::SelfVerifyingMasterKeyUntrusted,
+                self.ed25519_key.clone(),
+                own_master_key,
+            )
+        };
+        let qr_code = QrCode::new(mode, self.flow_id.clone(), first_key, second_key);
+        self.qr_code = Some(qr_code.clone());
+        Ok(qr_code)
+    }
+
+    /// Checks a QR code the other device shows against the keys we know and
+    /// answers it with `m.reciprocate.v1`.
+    pub fn scan_qr_code(&mut self, qr_code: &QrCode) -> Vec<(String, Value)> {
+        if self.state != VerificationState::Ready
+            && !(self.state == VerificationState::Started && self.commitment.is_none())
+        {
+            return self.unexpected("scan");
+        }
+        if qr_code.flow_id != self.flow_id {
+            return self.cancel("m.unknown_transaction", "QR code is for another flow");
+        }
+        let other_device_key = self
+            .other_device
+            .as_ref()
+            .and_then(DeviceKey::ed25519_key)
+            .map(str::to_owned);
+        let expected = match qr_code.mode {
+            QrMode::VerifyingAnotherUser if self.other_user_id != self.user_id => {
+                (self.other_master_key.clone(), self.own_master_key.clone())
+            }
+            QrMode::SelfVerifyingMasterKeyTrusted if self.other_user_id == self.user_id => {
+                (self.own_master_key.clone(), Some(self.ed25519_key.clone()))
+            }
+            QrMode::SelfVerifyingMasterKeyUntrusted if self.other_user_id == self.user_id => {
+                (other_device_key, self.own_master_key.clone())
+            }
+            _ => return self.cancel("m.key_mismatch", "QR code mode doesn't match the flow"),
+        };
+        if expected
+            != (
+                Some(qr_code.first_key.clone()),
+                Some(qr_code.second_key.clone()),
+            )
+        {
+            return self.cancel("m.key_mismatch", "QR code keys don't match");
+        }
+
+        let (event_type, content) = self.message(
+            "m.key.verification.start",
+            json!({
+                "from_device": self.device_id,
+                "method": RECIPROCATE_V1,
+                "secret": qr_code.secret(),
+            }),
+        );
+        self.state = VerificationState::Reciprocated;
+        self.we_started = true;
+        self.sas = None;
+        self.scanned_qr_mode = Some(qr_code.mode);
+        vec![(event_type, content)]
+    }
+
+    /// The seven emoji to compare, once the keys are exchanged.
+    pub fn emoji(&self) -> Option<Vec<(&'static str, &'static str)>> {
+        let established = self.established.as_ref()?;
+        Some(
+            established
+                .bytes(&self.sas_info(established))
+                .emoji_indices()
+                .iter()
+                .map(|index| SAS_EMOJI[*index as usize])
+                .collect(),
+        )
+    }
+
+    /// The three numbers to compare, once the keys are exchanged.
+    pub fn decimals(&self) -> Option<(u16, u16, u16)> {
+        let established = self.established.as_ref()?;
+        Some(established.bytes(&self.sas_info(established)).decimals())
+    }
+
+    /// Tells the other device the short authentication strings match by
+    /// sending our MAC.
+    pub fn confirm(&mut self) -> Vec<(String, Value)> {
+        if self.state == VerificationState::Reciprocated && !self.we_started {
+            if let Some(mode) = self.qr_code.as_ref().map(|qr_code| qr_code.mode) {
+                self.qr_code_verified(mode);
+            }
+            self.state = if self.done_received {
+                VerificationState::Done
+            } else {
+                VerificationState::Confirmed
+            };
+            return vec![self.message("m.key.verification.done", json!({}))];
+        }
+        let established = match &self.established {
+            Some(established) if self.state == VerificationState::KeysExchanged => established,
+            _ => return self.unexpected("confirm"),
+        };
+        let key_id = format!("ed25519:{}", self.device_id);
+        let info = self.mac_info(
+            &self.user_id,
+            &self.device_id,
+            &self.other_user_id,
+            self.other_device_id.as_deref().unwrap_or_default(),
+        );
+        let mac = self.calculate_mac(
+            established,
+            &self.ed25519_key,
+            &format!("{}{}", info, key_id),
+        );
+        let keys = self.calculate_mac(established, &key_id, &format!("{}KEY_IDS", info));
+
+        self.state = VerificationState::Confirmed;
+        let mut messages = vec![self.message(
+            "m.key.verification.mac",
+            json!({"mac": {key_id: mac}, "keys": keys}),
+        )];
+        if let Some(their_mac) = self.their_mac.take() {
+            messages.extend(self.receive_mac(&their_mac));
+        }
+        messages
+    }
+
+    pub fn cancel(&mut self, code: &str, reason: &str) -> Vec<(String, Value)> {
+        self.state = VerificationState::Cancelled {
+            code: code.to_owned(),
+            reason: reason.to_owned(),
+        };
+        vec![self.message(
+            "m.key.verification.cancel",
+            json!({"code": code, "reason": reason}),
+        )]
+    }
+
+    /// Advances the flow with an event the other device sent.
+    pub fn receive(&mut self, event_type: &str, content: &Value) -> Vec<(String, Value)> {
+        if self.is_cancelled() {
+            return Vec::new();
+        }
+        match event_type {
+            "m.key.verification.ready" => self.receive_ready(content),
+            "m.key.verification.start" => self.receive_start(content),
+            "m.key.verification.accept" => self.receive_accept(content),
+            "m.key.verification.key" => self.receive_key(content),
+            // Only a SAS flow has MACs to check.
+            "m.key.verification.mac" => match self.state {
+                _ if self.established.is_none() => self.unexpected(event_type),
+                VerificationState::KeysExchanged => {
+                    self.their_mac = Some(content.clone());
+                    Vec::new()
+                }
+                VerificationState::Confirmed => self.receive_mac(content),
+                _ => self.unexpected(event_type),
+            },
+            // The device that scanned trusts the keys it checked once the
+            // showing side confirmed.
+            "m.key.verification.done"
+                if self.state == VerificationState::Reciprocated && self.we_started =>
+            {
+                if let Some(mode) = self.scanned_qr_mode {
+                    self.qr_code_verified(mode);
+                }
+                self.done_received = true;
+                self.state = VerificationState::Done;
+                vec![self.message("m.key.verification.done", json!({}))]
+            }
+            "m.key.verification.done" => {
+                self.done_received = true;
+                if self.verified {
+                    self.state = VerificationState::Done;
+                }
+                Vec::new()
+            }
+            "m.key.verification.cancel" => {
+                self.state = VerificationState::Cancelled {
+                    code: content["code"].as_str().unwrap_or_default().to_owned(),
+                    reason: content["reason"].as_str().unwrap_or_default().to_owned(),
+                };
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn receive_ready(&mut self, content: &Value) -> Vec<(String, Value)> {
+        if !self.we_requested || self.state != VerificationState::Requested {
+            return self.unexpected("m.key.verification.ready");
+        }
+        if self.other_device_id.is_none() {
+            self.other_device_id = content["from_device"].as_str().map(str::to_owned);
+        }
+        self.state = VerificationState::Ready;
+        if contains(&content["methods"], SAS_V1) {
+            self.start_sas()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn receive_start(&mut self, content: &Value) -> Vec<(String, Value)> {
+        if content["method"].as_str() == Some(RECIPROCATE_V1) {
+            return self.receive_reciprocate(content);
+        }
+        match self.state {
+            VerificationState::Requested | VerificationState::Ready => {}
+            // Both sides started at once, the lexicographically smaller
+            // user and device ID wins.
+            VerificationState::Started if self.we_started => {
+                let their_device = content["from_device"].as_str().unwrap_or_default();
+                if (self.user_id.as_str(), self.device_id.as_str())
+                    < (self.other_user_id.as_str(), their_device)
+                {
+                    return Vec::new();
+                }
+            }
+            _ => return self.unexpected("m.key.verification.start"),
+        }
+
+        if content["method"].as_str() != Some(SAS_V1)
+            || !contains(&content["key_agreement_protocols"], KEY_AGREEMENT_PROTOCOL)
+            || !contains(&content["hashes"], HASH)
+        {
+            return self.cancel("m.unknown_method", "Unsupported verification method");
+        }
+        let mac_method = if contains(
+            &content["message_authentication_codes"],
+            MAC_HKDF_HMAC_SHA256_V2,
+        ) {
+            MAC_HKDF_HMAC_SHA256_V2
+        } else if contains(
+            &content["message_authentication_codes"],
+            MAC_HKDF_HMAC_SHA256,
+        ) {
+            MAC_HKDF_HMAC_SHA256
+        } else {
+            return self.cancel("m.unknown_method", "Unsupported MAC method");
+        };
+        let short_authentication_string: Vec<&str> = SHORT_AUTHENTICATION_STRINGS
+            .into_iter()
+            .filter(|method| contains(&content["short_authentication_string"], method))
+            .collect();
+        if !short_authentication_string.contains(&"decimal") {
+            return self.cancel("m.unknown_method", "Decimal SAS is required");
+        }
+
+        if self.other_device_id.is_none() {
+            self.other_device_id = content["from_device"].as_str().map(str::to_owned);
+        }
+        let sas = Sas::new();
+        let commitment = commitment(&sas.public_key().to_base64(), content);
+        self.sas = Some(sas);
+        self.we_started = false;
+        self.start_content = Some(content.clone());
+        self.mac_method = String::from(mac_method);
+        self.state = VerificationState::Started;
+
+        vec![self.message(
+            "m.key.verification.accept",
+            json!({
+                "method": SAS_V1,
+                "key_agreement_protocol": KEY_AGREEMENT_PROTOCOL,
+                "hash": HASH,
+                "message_authentication_code": mac_method,
+                "short_authentication_string": short_authentication_string,
+                "commitment": commitment,
+            }),
+        )]
+    }
+
+    /// The other device scanned our QR code. A SAS start of ours is
+    /// dropped, scanning wins.
+    fn receive_reciprocate(&mut self, content: &Value) -> Vec<(String, Value)> {
+        let secret = match &self.qr_code {
+            Some(qr_code)
+                if self.state == VerificationState::Ready
+                    || (self.state == VerificationState::Started && self.we_started) =>
+            {
+                qr_code.secret()
+            }
+            _ => return self.unexpected("m.key.verification.start"),
+        };
+        if content["secret"].as_str() != Some(secret.as_str()) {
+            return self.cancel("m.key_mismatch", "QR code secret doesn't match");
+        }
+        self.state = VerificationState::Reciprocated;
+        self.we_started = false;
+        self.sas = None;
+        Vec::new()
+    }
+
+    fn receive_accept(&mut self, content: &Value) -> Vec<(String, Value)> {
+        if !self.we_started || self.state != VerificationState::Started || self.commitment.is_some()
+        {
+            return self.unexpected("m.key.verification.accept");
+        }
+        let mac_method = content["message_authentication_code"]
+            .as_str()
+            .unwrap_or_default();
+        if content["key_agreement_protocol"].as_str() != Some(KEY_AGREEMENT_PROTOCOL)
+            || content["hash"].as_str() != Some(HASH)
+            || ![MAC_HKDF_HMAC_SHA256_V2, MAC_HKDF_HMAC_SHA256].contains(&mac_method)
+        {
+            return self.cancel("m.unknown_method", "Unsupported verification method");
+        }
+        let commitment = match content["commitment"].as_str() {
+            Some(commitment) => commitment.to_owned(),
+            None => return self.cancel("m.invalid_message", "Missing commitment"),
+        };
+
+        self.commitment = Some(commitment);
+        self.mac_method = mac_method.to_owned();
+        let public_key = self.sas.as_ref().unwrap().public_key().to_base64();
+        vec![self.message("m.key.verification.key", json!({ "key": public_key }))]
+    }
+
+    fn receive_key(&mut self, content: &Value) -> Vec<(String, Value)> {
+        if self.state != VerificationState::Started
+            || (self.we_started && self.commitment.is_none())
+        {
+            return self.unexpected("m.key.verification.key");
+        }
+        let their_key = content["key"].as_str().unwrap_or_default();
+        if self.we_started
+            && self.commitment.as_deref()
+                != Some(&commitment(their_key, self.start_content.as_ref().unwrap()))
+        {
+            return self.cancel("m.mismatched_commitment", "Commitment doesn't match");
+        }
+
+        let sas = self.sas.take().unwrap();
+        let our_key = sas.public_key().to_base64();
+        let established = match Curve25519PublicKey::from_base64(their_key)
+            .map_err(|e| e.to_string())
+            .and_then(|key| sas.diffie_hellman(key).map_err(|e| e.to_string()))
+        {
+            Ok(established) => established,
+            Err(e) => return self.cancel("m.invalid_message", &e),
+        };
+        self.established = Some(established);
+        self.state = VerificationState::KeysExchanged;
+
+        if self.we_started {
+            Vec::new()
+        } else {
+            vec![self.message("m.key.verification.key", json!({ "key": our_key }))]
+        }
+    }
+
+    fn receive_mac(&mut self, content: &Value) -> Vec<(String, Value)> {
+        let established = match &self.established {
+            Some(established) => established,
+            None => return self.unexpected("m.key.verification.mac"),
+        };
+        let other_device = match &self.other_device {
+            Some(other_device) => other_device,
+            None => return self.cancel("m.key_mismatch", "Unknown device keys"),
+        };
+        let info = self.mac_info(
+            &self.other_user_id,
+            &other_device.device_id,
+            &self.user_id,
+            &self.device_id,
+        );
+        let macs = content["mac"].as_object().cloned().unwrap_or_default();
+
+        let mut key_ids: Vec<&str> = macs.keys().map(String::as_str).collect();
+        key_ids.sort_unstable();
+        if !self.check_mac(
+            established,
+            &key_ids.join(","),
+            &format!("{}KEY_IDS", info),
+            content["keys"].as_str().unwrap_or_default(),
+        ) {
+            return self.cancel("m.key_mismatch", "MAC of the key IDs doesn't match");
+        }
+
+        // Keys we know nothing about are ignored, but the device key has to
+        // be there and match.
+        let device_key_id = format!("ed25519:{}", other_device.device_id);
+        let device_key = other_device.ed25519_key().unwrap_or_default().to_owned();
+        match macs.get(&device_key_id).and_then(Value::as_str) {
+            Some(mac)
+                if self.check_mac(
+                    established,
+                    &device_key,
+                    &format!("{}{}", info, device_key_id),
+                    mac,
+                ) => {}
+            _ => return self.cancel("m.key_mismatch", "MAC of the device key doesn't match"),
+        }
+
+        self.verified = true;
+        self.verified_device = true;
+        if self.done_received {
+            self.state = VerificationState::Done;
+        }
+        vec![self.message("m.key.verification.done", json!({}))]
+    }
+
+    /// Records what a QR code flow proved once both sides agree: the other
+    /// device if the code held its key, or else the master key it held.
+    fn qr_code_verified(&mut self, mode: QrMode) {
+        let showing = !self.we_started;
+        match (mode, showing) {
+            (QrMode::VerifyingAnotherUser, _) => {
+                self.verified_master_key = self.other_master_key.clone()
+            }
+            (QrMode::SelfVerifyingMasterKeyTrusted, true)
+            | (QrMode::SelfVerifyingMasterKeyUntrusted, false) => self.verified_device = true,
+            (QrMode::SelfVerifyingMasterKeyTrusted, false)
+            | (QrMode::SelfVerifyingMasterKeyUntrusted, true) => {
+                self.verified_master_key = self.own_master_key.clone()
+            }
+        }
+        self.verified = true;
+    }
+
+    /// `MATRIX_KEY_VERIFICATION_SAS` info, listing the starting device first.
+    fn sas_info(&self, established: &EstablishedSas) -> String {
+        let ours = (
+            self.user_id.as_str(),
+            self.device_id.as_str(),
+            established.our_public_key().to_base64(),
+        );
+        let theirs = (
+            self.other_user_id.as_str(),
+            self.other_device_id.as_deref().unwrap_or_default(),
+            established.their_public_key().to_base64(),
+        );
+        let (starter, accepter) = if self.we_started {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+        format!(
+            "MATRIX_KEY_VERIFICATION_SAS|{}|{}|{}|{}|{}|{}|{}",
+            starter.0, starter.1, starter.2, accepter.0, accepter.1, accepter.2, self.flow_id
+        )
+    }
+
+    fn mac_info(&self, sender: &str, sender_device: &str, recipient: &str, device: &str) -> String {
+        format!(
+            "MATRIX_KEY_VERIFICATION_MAC{}{}{}{}{}",
+            sender, sender_device, recipient, device, self.flow_id
+        )
+    }
+
+    fn calculate_mac(&self, established: &EstablishedSas, input: &str, info: &str) -> String {
+        if self.mac_method == MAC_HKDF_HMAC_SHA256 {
+            established.calculate_mac_invalid_base64(input, info)
+        } else {
+            established.calculate_mac(input, info).to_base64()
+        }
+    }
+
+    fn check_mac(&self, established: &EstablishedSas, input: &str, info: &str, mac: &str) -> bool {
+        if self.mac_method == MAC_HKDF_HMAC_SHA256 {
+            established.calculate_mac_invalid_base64(input, info) == mac
+        } else {
+            Mac::from_base64(mac)
+                .map(|mac| established.verify_mac(input, info, &mac).is_ok())
+                .unwrap_or(false)
+        }
+    }
+
+    /// SAS, plus the QR code methods when we know the master keys to build
+    /// and check codes with.
+    fn methods(&self) -> Vec<&'static str> {
+        let qr_code_possible = self.own_master_key.is_some()
+            && (self.other_user_id == self.user_id || self.other_master_key.is_some());
+        if qr_code_possible {
+            vec![SAS_V1, QR_CODE_SHOW_V1, QR_CODE_SCAN_V1, RECIPROCATE_V1]
+        } else {
+            vec![SAS_V1]
+        }
+    }
+
+    fn unexpected(&mut self, event_type: &str) -> Vec<(String, Value)> {
+        self.cancel(
+            "m.unexpected_message",
+            &format!("Unexpected {} in state {:?}", event_type, self.state),
+        )
+    }
+
+    /// Adds the fields that tie `content` to this flow.
+    fn message(&self, event_type: &str, mut content: Value) -> (String, Value) {
+        if self.room_id.is_some() {
+            content["m.relates_to"] = json!({"rel_type": "m.reference", "event_id": self.flow_id});
+        } else {
+            content["transaction_id"] = Value::from(self.flow_id.clone());
+        }
+        (event_type.to_owned(), content)
+    }
+}
+
+fn contains(list: &Value, item: &str) -> bool {
+    list.as_array()
+        .map(|list| list.iter().any(|value| value.as_str() == Some(item)))
+        .unwrap_or(false)
+}
+
+/// Hash of the accepting device's SAS key and the canonical start content.
+fn commitment(public_key: &str, start_content: &Value) -> String {
+    let canonical_start = cjson::to_string(start_content).unwrap_or_default();
+    let hash = Sha256::digest(format!("{}{}", public_key, canonical_start).as_bytes());
+    base64::encode_config(hash, base64::STANDARD_NO_PAD)
+}
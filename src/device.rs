@@ -1,18 +1,103 @@
-use crate::crypto::{DeviceKey, MegolmSession, OlmExchange, OneTimeKey};
+use crate::crypto::cross_signing::{MASTER, SELF_SIGNING, USER_SIGNING};
+use crate::crypto::megolm_sha2::now_ms;
+use crate::crypto::olm_sha256::KeyExchangeData;
+use crate::crypto::signature::verify_signed_json;
+use crate::crypto::{
+    CrossSigningIdentity, CrossSigningKey, DecryptedOlmEvent, DecryptedRoomEvent, DeviceKey,
+    DevicePickle, ForwardedRoomKey, MegolmMessage, MegolmSession, OlmExchange, OneTimeKey, QrCode,
+    RequestedKeyInfo, RoomKeyRequest, RoomKeyWithheld, RotationPolicy, Verification,
+    VerificationState, WithheldCode,
+};
 use crate::error::Error;
 use crate::http::HTTPBackend;
-use std::collections::HashMap;
+use crate::payload::CrossSigningUploadPayload;
+use crate::response::{
+    KeyUploadResponse, OneTimeKeyCounts, RoomEvent, SyncResponse, ToDeviceEvent,
+};
+use crate::store::{store_key, CryptoStore, MemoryStore, TrustState};
+use std::collections::{HashMap, HashSet};
 use vodozemac::megolm;
 use vodozemac::olm;
 
+/// The spec recommends unwedging an Olm session at most once an hour.
+const UNWEDGING_INTERVAL_MS: u64 = 3_600_000;
+/// Message indexes are kept this long after we received their event to detect
+/// replays, and pruned at most once per interval.
+const MESSAGE_INDEX_RETENTION_MS: u64 = 30 * 86_400_000;
+const MESSAGE_INDEX_PRUNING_INTERVAL_MS: u64 = 86_400_000;
+/// Verification requests older than this, or from further in the future,
+/// are ignored, as the spec asks.
+const VERIFICATION_REQUEST_MAX_AGE_MS: u64 = 600_000;
+const VERIFICATION_REQUEST_MAX_SKEW_MS: u64 = 300_000;
+
+/// Devices excluded from a room key, with the reason sent to them.
+type WithheldDevices = Vec<(DeviceKey, WithheldCode)>;
+
+/// Which devices of a room receive our Megolm session keys. Blacklisted
+/// devices never do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SharingStrategy {
+    /// Every device that is not blacklisted.
+    #[default]
+    AllDevices,
+    /// Only devices we verified, directly or through cross-signing; the
+    /// others get an `m.unverified` notice.
+    VerifiedOnly,
+    /// Refuse to send anything while the room contains an unverified device.
+    ErrorOnUnverified,
+}
+
+/// How much we trust a device, from strongest to weakest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceTrust {
+    /// We verified the device ourselves, e.g. interactively.
+    Verified,
+    /// The device is signed by its user's self-signing key, and we verified
+    /// that user's master key: it is our own, or signed by our user-signing
+    /// key.
+    CrossSigned,
+    /// The device is signed by its user's self-signing key, under the master
+    /// key we saw first for the user, but nobody verified that key.
+    Pinned,
+    /// None of the above, including blacklisted and unknown devices.
+    Unverified,
+}
+
+/// The keys the server holds for this device, as found by
+/// `from_access_token`.
+enum PublishedKeys {
+    /// The device never uploaded keys, e.g. it just logged in.
+    NotUploaded,
+    /// The server holds the keys of the local account.
+    Matching,
+}
+
 pub struct Device {
     pub user_id: String,
     pub device_id: String,
     pub access_token: String,
     pub homeserver_uri: String,
     pub backend_api: HTTPBackend,
+    pub sharing_strategy: SharingStrategy,
     olm_account: olm::Account,
-    megolm_sessions: HashMap<String, megolm::GroupSession>,
+    megolm_sessions: HashMap<String, MegolmSession>,
+    olm_sessions: HashMap<String, olm::Session>,
+    /// Room events waiting for a requested key, by room, sender key and
+    /// session ID.
+    pending_room_events: HashMap<String, Vec<RoomEvent>>,
+    verifications: HashMap<String, Verification>,
+    /// Joined members of the rooms we sent to, until sync reports a
+    /// membership change.
+    room_members: HashMap<String, Vec<String>>,
+    /// Users whose stored device keys may be stale and are queried again
+    /// before we share a room key with them.
+    outdated_device_lists: HashSet<String>,
+    cross_signing: Option<CrossSigningIdentity>,
+    store: Box<dyn CryptoStore>,
+    uploaded_key_count: Option<usize>,
+    unused_fallback_key_types: Option<Vec<String>>,
+    sync_token: Option<String>,
+    message_indexes_pruned_at: u64,
 }
 
 impl Device {
@@ -22,33 +107,254 @@ impl Device {
         access_token: String,
         homeserver_uri: String,
     ) -> Self {
-        let mut olm_account = olm::Account::new();
-        olm_account.generate_one_time_keys(50);
-        Device {
-            user_id: user_id,
-            device_id: device_id,
+        Device::with_store(
+            user_id,
+            device_id,
+            access_token,
+            homeserver_uri,
+            Box::new(MemoryStore::new()),
+        )
+        .expect("the in-memory store never fails")
+    }
+
+    pub fn with_store(
+        user_id: String,
+        device_id: String,
+        access_token: String,
+        homeserver_uri: String,
+        store: Box<dyn CryptoStore>,
+    ) -> Result<Self, Error> {
+        let (mut device, is_new) =
+            Device::open_store(user_id, device_id, access_token, homeserver_uri, store)?;
+        if is_new {
+            device.save_new_account()?;
+        }
+        Ok(device)
+    }
+
+    /// Loads the device kept in `store`, or creates a new account if the store
+    /// is empty. A new account is not saved yet; also returns whether it is
+    /// new.
+    fn open_store(
+        user_id: String,
+        device_id: String,
+        access_token: String,
+        homeserver_uri: String,
+        store: Box<dyn CryptoStore>,
+    ) -> Result<(Self, bool), Error> {
+        let mut is_new = false;
+        match store.load_identity()? {
+            Some((stored_user_id, stored_device_id)) => {
+                if stored_user_id != user_id || stored_device_id != device_id {
+                    return Err(Error::DeviceMismatch(format!(
+                        "store belongs to {} ({}), not {} ({})",
+                        stored_user_id, stored_device_id, user_id, device_id
+                    )));
+                }
+            }
+            None => is_new = true,
+        }
+
+        let olm_account = match store.load_account()? {
+            Some(olm_account) => olm_account,
+            None => {
+                is_new = true;
+                olm::Account::new()
+            }
+        };
+        let cross_signing = store.load_cross_signing_identity()?;
+        let mut megolm_sessions = HashMap::new();
+        for (room_id, ratchet) in store.load_outbound_group_sessions()? {
+            let session_id = ratchet.session_id();
+            let mut megolm_session = MegolmSession::new(room_id.clone(), ratchet);
+            megolm_session.shared_with = store.load_shared_with(&room_id, &session_id)?;
+            match store.load_outbound_session_info(&room_id)? {
+                Some((stored_session_id, created_at, rotation))
+                    if stored_session_id == session_id =>
+                {
+                    megolm_session.created_at = created_at;
+                    megolm_session.rotation = rotation;
+                }
+                // Without a known creation time, rotate on first use.
+                _ => megolm_session.created_at = 0,
+            }
+            megolm_sessions.insert(room_id, megolm_session);
+        }
+
+        // Devices may have changed while we weren't syncing.
+        let outdated_device_lists = store.tracked_users()?.into_iter().collect();
+
+        let device = Device {
+            user_id,
+            device_id,
             access_token: access_token.clone(),
             homeserver_uri: homeserver_uri.clone(),
             backend_api: HTTPBackend::new(homeserver_uri, access_token),
-            olm_account: olm_account,
-            megolm_sessions: HashMap::new(),
-        }
+            sharing_strategy: SharingStrategy::default(),
+            olm_account,
+            megolm_sessions,
+            olm_sessions: HashMap::new(),
+            pending_room_events: HashMap::new(),
+            verifications: HashMap::new(),
+            room_members: HashMap::new(),
+            outdated_device_lists,
+            cross_signing,
+            store,
+            uploaded_key_count: None,
+            unused_fallback_key_types: None,
+            sync_token: None,
+            message_indexes_pruned_at: 0,
+        };
+        Ok((device, is_new))
+    }
+
+    fn save_new_account(&mut self) -> Result<(), Error> {
+        self.store.save_identity(&self.user_id, &self.device_id)?;
+        self.store.save_account(&self.olm_account)
     }
 
     pub async fn from_login(
         user_id: String,
         password: String,
         homeserver_uri: String,
+    ) -> Result<Self, Error> {
+        Device::from_login_with_store(
+            user_id,
+            password,
+            homeserver_uri,
+            Box::new(MemoryStore::new()),
+        )
+        .await
+    }
+
+    pub async fn from_login_with_store(
+        user_id: String,
+        password: String,
+        homeserver_uri: String,
+        store: Box<dyn CryptoStore>,
     ) -> Result<Self, Error> {
         let response =
             HTTPBackend::raw_login(homeserver_uri.clone(), user_id.clone(), password.clone())
                 .await?;
-        Ok(Device::new(
+        Device::with_store(
             user_id,
             response.device_id,
             response.access_token,
             homeserver_uri,
-        ))
+            store,
+        )
+    }
+
+    /// Resumes an existing session, the access token must belong to the
+    /// device whose account is kept in `store`.
+    pub async fn from_access_token(
+        homeserver_uri: String,
+        access_token: String,
+        store: Box<dyn CryptoStore>,
+    ) -> Result<Self, Error> {
+        let whoami = HTTPBackend::new(homeserver_uri.clone(), access_token.clone())
+            .whoami()
+            .await?;
+        let device_id = whoami.device_id.ok_or_else(|| {
+            Error::DeviceMismatch(String::from("access token is not bound to a device"))
+        })?;
+
+        let (mut device, is_new) = Device::open_store(
+            whoami.user_id,
+            device_id,
+            access_token,
+            homeserver_uri,
+            store,
+        )?;
+        match device.check_published_keys().await? {
+            PublishedKeys::NotUploaded => log::info!(
+                "No keys uploaded for {} yet, publish_keypair will upload them",
+                device.device_id
+            ),
+            PublishedKeys::Matching => {}
+        }
+        if is_new {
+            device.save_new_account()?;
+        }
+        Ok(device)
+    }
+
+    /// Compares the keys the server holds for this device with the local
+    /// account, which fails if they differ.
+    async fn check_published_keys(&self) -> Result<PublishedKeys, Error> {
+        let queried_keys = self.backend_api.query_keys(self.user_id.clone()).await?;
+        let published = match queried_keys
+            .device_keys
+            .get(&self.user_id)
+            .and_then(|devices| devices.get(&self.device_id))
+        {
+            Some(published) => {
+                DeviceKey::from_signed_json(published, &self.user_id, &self.device_id)?
+            }
+            None => return Ok(PublishedKeys::NotUploaded),
+        };
+
+        if published.curve25519_key() != Some(self.curve25519_key().as_str())
+            || published.ed25519_key() != Some(self.ed25519_key().as_str())
+        {
+            return Err(Error::DeviceMismatch(format!(
+                "keys published for {} differ from the local account",
+                self.device_id
+            )));
+        }
+        Ok(PublishedKeys::Matching)
+    }
+
+    pub fn from_pickle(
+        pickle: &DevicePickle,
+        passphrase: &str,
+        access_token: String,
+        homeserver_uri: String,
+    ) -> Result<Self, Error> {
+        let (olm_account, megolm_sessions) = pickle.unpickle(passphrase)?;
+        let mut store = MemoryStore::new();
+        store.save_account(&olm_account)?;
+        for (room_id, session) in &megolm_sessions {
+            store.save_outbound_group_session(room_id, session)?;
+        }
+
+        Device::with_store(
+            pickle.user_id.clone(),
+            pickle.device_id.clone(),
+            access_token,
+            homeserver_uri,
+            Box::new(store),
+        )
+    }
+
+    pub fn pickle(&self, passphrase: &str) -> DevicePickle {
+        DevicePickle::new(
+            self.user_id.clone(),
+            self.device_id.clone(),
+            passphrase,
+            &self.olm_account,
+            self.megolm_sessions
+                .iter()
+                .map(|(room_id, megolm_session)| (room_id, &megolm_session.ratchet)),
+        )
+    }
+
+    pub fn save(&self, path: &std::path::Path, passphrase: &str) -> Result<(), Error> {
+        self.pickle(passphrase).save(path)
+    }
+
+    pub fn load(
+        path: &std::path::Path,
+        passphrase: &str,
+        access_token: String,
+        homeserver_uri: String,
+    ) -> Result<Self, Error> {
+        Device::from_pickle(
+            &DevicePickle::load(path)?,
+            passphrase,
+            access_token,
+            homeserver_uri,
+        )
     }
 
     pub fn curve25519_key(&self) -> String {
@@ -59,7 +365,168 @@ impl Device {
         self.olm_account.ed25519_key().to_base64()
     }
 
-    pub async fn publish_keypair(&self) -> Result<i16, Error> {
+    /// Our cross-signing master key, once `bootstrap_cross_signing` ran.
+    pub fn master_key(&self) -> Option<String> {
+        self.cross_signing.as_ref().map(|identity| {
+            identity
+                .master_key()
+                .ed25519_key()
+                .unwrap_or_default()
+                .to_owned()
+        })
+    }
+
+    /// Creates our master, self-signing and user-signing keys, uploads them
+    /// and signs this device with the self-signing key. `password` answers
+    /// the server's `m.login.password` authentication if it asks for one.
+    /// Keys that were already created are reused, so a failed upload can be
+    /// retried. Fails with `CrossSigningKeysExist` if the server holds
+    /// another master key for us, see `reset_cross_signing`.
+    pub async fn bootstrap_cross_signing(&mut self, password: Option<&str>) -> Result<(), Error> {
+        if let Some(published) = self.published_master_key().await? {
+            if Some(&published) != self.master_key().as_ref() {
+                return Err(Error::CrossSigningKeysExist(format!(
+                    "{} already has master key {}",
+                    self.user_id, published
+                )));
+            }
+        }
+        let identity = match self.cross_signing.take() {
+            Some(identity) => identity,
+            None => {
+                let identity = CrossSigningIdentity::new(self.user_id.clone());
+                self.store.save_cross_signing_identity(&identity)?;
+                identity
+            }
+        };
+        let result = self.upload_cross_signing(&identity, password).await;
+        self.cross_signing = Some(identity);
+        result
+    }
+
+    /// Replaces our cross-signing keys with new ones, whatever the server
+    /// holds. Everybody who verified the old master key has to verify us
+    /// again.
+    pub async fn reset_cross_signing(&mut self, password: Option<&str>) -> Result<(), Error> {
+        let identity = CrossSigningIdentity::new(self.user_id.clone());
+        self.store.save_cross_signing_identity(&identity)?;
+        let result = self.upload_cross_signing(&identity, password).await;
+        self.cross_signing = Some(identity);
+        result
+    }
+
+    /// The master key the server has for us, if any.
+    async fn published_master_key(&self) -> Result<Option<String>, Error> {
+        let response = self.backend_api.query_keys(self.user_id.clone()).await?;
+        match response.master_keys.get(&self.user_id) {
+            Some(json) => Ok(
+                CrossSigningKey::from_signed_json(json, &self.user_id, MASTER, None)?
+                    .ed25519_key()
+                    .map(str::to_owned),
+            ),
+            None => Ok(None),
+        }
+    }
+
+    async fn upload_cross_signing(
+        &self,
+        identity: &CrossSigningIdentity,
+        password: Option<&str>,
+    ) -> Result<(), Error> {
+        let payload = |auth| -> Result<CrossSigningUploadPayload, Error> {
+            Ok(CrossSigningUploadPayload {
+                master_key: identity.master_key(),
+                self_signing_key: identity.self_signing_key()?,
+                user_signing_key: identity.user_signing_key()?,
+                auth,
+            })
+        };
+
+        if let Some(uia) = self
+            .backend_api
+            .upload_cross_signing_keys(payload(None)?)
+            .await?
+        {
+            let has_flow = |stage: &str| {
+                uia.flows
+                    .iter()
+                    .any(|flow| flow.stages.iter().map(String::as_str).eq([stage]))
+            };
+            let auth = match password {
+                Some(password) if has_flow("m.login.password") => serde_json::json!({
+                    "type": "m.login.password",
+                    "identifier": {"type": "m.id.user", "user": self.user_id},
+                    "password": password,
+                    "session": uia.session,
+                }),
+                _ if has_flow("m.login.dummy") => serde_json::json!({
+                    "type": "m.login.dummy",
+                    "session": uia.session,
+                }),
+                _ => {
+                    let flows: Vec<String> = uia
+                        .flows
+                        .iter()
+                        .map(|flow| flow.stages.join(" -> "))
+                        .collect();
+                    return Err(Error::AuthRequired(flows.join(", ")));
+                }
+            };
+            if let Some(uia) = self
+                .backend_api
+                .upload_cross_signing_keys(payload(Some(auth))?)
+                .await?
+            {
+                return Err(Error::AuthRequired(format!(
+                    "authentication was not accepted, completed {:?}",
+                    uia.completed
+                )));
+            }
+        }
+        log::info!("Uploaded cross-signing keys of {}", self.user_id);
+
+        let device_key = DeviceKey::new(
+            self.device_id.clone(),
+            self.user_id.clone(),
+            self.curve25519_key(),
+            self.ed25519_key(),
+        )
+        .sign(&self.olm_account);
+        let device_key = identity.sign_device(device_key)?;
+        let response = self
+            .backend_api
+            .upload_signatures(HashMap::from([(
+                self.user_id.clone(),
+                HashMap::from([(self.device_id.clone(), serde_json::to_value(device_key)?)]),
+            )]))
+            .await?;
+        if !response.failures.is_empty() {
+            return Err(Error::ApiError(format!(
+                "signature upload failed: {:?}",
+                response.failures
+            )));
+        }
+        Ok(())
+    }
+
+    /// Uploads the device keys together with enough fresh one-time keys to
+    /// fill the server side pool up to the account's maximum, and a new
+    /// fallback key if the previous one got used.
+    pub async fn publish_keypair(&mut self) -> Result<KeyUploadResponse, Error> {
+        let uploaded_key_count = match self.uploaded_key_count {
+            Some(count) => count,
+            None => {
+                let response = self
+                    .backend_api
+                    .send_keys(None, HashMap::new(), HashMap::new())
+                    .await?;
+                self.receive_one_time_key_counts(&response.one_time_key_counts);
+                self.uploaded_key_count.unwrap_or(0)
+            }
+        };
+        self.generate_one_time_keys(uploaded_key_count)?;
+        self.generate_fallback_key()?;
+
         let device_key = DeviceKey::new(
             self.device_id.clone(),
             self.user_id.clone(),
@@ -81,93 +548,2300 @@ impl Device {
             );
         }
 
+        let mut fallback_keys: HashMap<String, OneTimeKey> = HashMap::new();
+        for (id, curve_key) in self.olm_account.fallback_key() {
+            let fallback_key = OneTimeKey::new_fallback(id.to_base64(), curve_key.to_base64())
+                .sign(
+                    &self.olm_account,
+                    self.user_id.clone(),
+                    self.device_id.clone(),
+                );
+            fallback_keys.insert(
+                format!("signed_curve25519:{}", fallback_key.id),
+                fallback_key,
+            );
+        }
+        let publishes_fallback_key = !fallback_keys.is_empty();
+
+        let mut response = self
+            .backend_api
+            .send_keys(Some(device_key), one_time_keys, fallback_keys)
+            .await?;
+        self.olm_account.mark_keys_as_published();
+        self.store.save_account(&self.olm_account)?;
+        if publishes_fallback_key {
+            self.store.set_fallback_key_published(true)?;
+            self.unused_fallback_key_types = Some(vec![String::from("signed_curve25519")]);
+        }
+
+        self.receive_one_time_key_counts(&response.one_time_key_counts);
+        response.unused_fallback_key_published = self.has_unused_fallback_key()?;
+        Ok(response)
+    }
+
+    fn has_unused_fallback_key(&self) -> Result<bool, Error> {
+        match &self.unused_fallback_key_types {
+            Some(types) => Ok(types.iter().any(|t| t == "signed_curve25519")),
+            None => self.store.fallback_key_published(),
+        }
+    }
+
+    /// Generates a fallback key when the server doesn't hold an unused one,
+    /// either because we never uploaded one or because it got claimed.
+    fn generate_fallback_key(&mut self) -> Result<(), Error> {
+        if self.has_unused_fallback_key()? || !self.olm_account.fallback_key().is_empty() {
+            return Ok(());
+        }
+        self.olm_account.generate_fallback_key();
+        self.store.set_fallback_key_published(false)?;
+        self.store.save_account(&self.olm_account)
+    }
+
+    /// Records how many of our one-time keys the server still holds, as
+    /// reported by `/keys/upload` and `/sync`.
+    pub fn receive_one_time_key_counts(&mut self, counts: &OneTimeKeyCounts) {
+        self.uploaded_key_count = Some(counts.signed_curve25519.unwrap_or(0).max(0) as usize);
+    }
+
+    /// True once the server side pool dropped below half of the maximum,
+    /// or when we never heard from the server at all.
+    pub fn should_upload_keys(&self) -> bool {
+        match self.uploaded_key_count {
+            Some(count) => count < self.olm_account.max_number_of_one_time_keys() / 2,
+            None => true,
+        }
+    }
+
+    fn generate_one_time_keys(&mut self, uploaded_key_count: usize) -> Result<(), Error> {
+        let unpublished_key_count = self.olm_account.one_time_keys().len();
+        let missing = self
+            .olm_account
+            .max_number_of_one_time_keys()
+            .saturating_sub(uploaded_key_count + unpublished_key_count);
+
+        if missing > 0 {
+            self.olm_account.generate_one_time_keys(missing);
+            self.store.save_account(&self.olm_account)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a single `/sync` request and handles the crypto related parts
+    /// of the response.
+    pub async fn sync(&mut self) -> Result<SyncResponse, Error> {
+        let mut response = self.backend_api.sync(self.sync_token.clone()).await?;
+        self.sync_token = Some(response.next_batch.clone());
+        self.receive_sync_response(&mut response).await?;
+        Ok(response)
+    }
+
+    /// Handles the crypto related parts of a sync response, for callers that
+    /// run their own sync loop.
+    pub async fn receive_sync_response(
+        &mut self,
+        response: &mut SyncResponse,
+    ) -> Result<(), Error> {
+        for event in &response.to_device.events {
+            if event.r#type == "m.room_key.withheld" {
+                let withheld =
+                    match serde_json::from_value::<RoomKeyWithheld>(event.content.clone()) {
+                        Ok(withheld) => {
+                            self.receive_room_key_withheld(&event.sender, &withheld)
+                                .await
+                        }
+                        Err(e) => Err(Error::from(e)),
+                    };
+                if let Err(e) = withheld {
+                    log::warn!("Ignoring withheld notice from {}: {}", event.sender, e);
+                }
+                continue;
+            }
+            if event.r#type == "m.room_key_request" {
+                let handled = match serde_json::from_value::<RoomKeyRequest>(event.content.clone())
+                {
+                    Ok(request) => self.receive_room_key_request(&event.sender, &request).await,
+                    Err(e) => Err(Error::from(e)),
+                };
+                if let Err(e) = handled {
+                    log::warn!("Failed to answer key request from {}: {}", event.sender, e);
+                }
+                continue;
+            }
+            if event.r#type.starts_with("m.key.verification.") {
+                if let Err(e) = self
+                    .receive_verification_event(&event.sender, &event.r#type, &event.content)
+                    .await
+                {
+                    log::warn!(
+                        "Failed to handle {} from {}: {}",
+                        event.r#type,
+                        event.sender,
+                        e
+                    );
+                }
+                continue;
+            }
+            if event.r#type != "m.room.encrypted" {
+                continue;
+            }
+            match self.decrypt_olm_event(event).await {
+                Ok(decrypted) => {
+                    if decrypted.r#type == "m.room_key" {
+                        match self.receive_room_key(&decrypted) {
+                            Ok(()) => response.decrypted_room_events.extend(
+                                self.retry_pending_room_events(
+                                    decrypted.content["room_id"].as_str().unwrap_or_default(),
+                                    &decrypted.sender_key,
+                                    decrypted.content["session_id"].as_str().unwrap_or_default(),
+                                )
+                                .await,
+                            ),
+                            Err(e) => {
+                                log::warn!("Ignoring room key from {}: {}", decrypted.sender, e)
+                            }
+                        }
+                    }
+                    if decrypted.r#type == "m.forwarded_room_key" {
+                        match self.receive_forwarded_room_key(&decrypted).await {
+                            Ok(events) => response.decrypted_room_events.extend(events),
+                            Err(e) => log::warn!(
+                                "Ignoring forwarded room key from {}: {}",
+                                decrypted.sender,
+                                e
+                            ),
+                        }
+                    }
+                    response.decrypted_to_device.push(decrypted)
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to decrypt to-device event from {}: {}",
+                        event.sender,
+                        e
+                    );
+                    // Only a session we lost or that got out of sync with the
+                    // sender's is worth replacing, not a malformed or replayed
+                    // message.
+                    if let (Error::UnknownOlmSession(_) | Error::InvalidMac(_), Some(sender_key)) =
+                        (&e, event.content["sender_key"].as_str())
+                    {
+                        if let Err(e) = self.unwedge_olm_session(&event.sender, sender_key).await {
+                            log::warn!(
+                                "Failed to unwedge Olm session with {}: {}",
+                                event.sender,
+                                e
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        self.outdated_device_lists.extend(
+            response
+                .device_lists
+                .changed
+                .iter()
+                .chain(&response.device_lists.left)
+                .cloned(),
+        );
+        for (room_id, room) in &response.rooms.join {
+            if room
+                .state
+                .events
+                .iter()
+                .chain(&room.timeline.events)
+                .any(|event| event["type"] == "m.room.member")
+            {
+                self.room_members.remove(room_id);
+            }
+        }
+        for room_id in response.rooms.leave.keys() {
+            self.room_members.remove(room_id);
+        }
+
+        if let Some(counts) = &response.device_one_time_keys_count {
+            self.receive_one_time_key_counts(counts);
+        }
+        if let Some(types) = &response.device_unused_fallback_key_types {
+            self.unused_fallback_key_types = Some(types.clone());
+        }
+        if self.should_upload_keys() || !self.has_unused_fallback_key()? {
+            self.publish_keypair().await?;
+        }
+
+        let now = now_ms();
+        if now.saturating_sub(self.message_indexes_pruned_at) >= MESSAGE_INDEX_PRUNING_INTERVAL_MS {
+            let pruned = self
+                .store
+                .prune_message_indexes(now.saturating_sub(MESSAGE_INDEX_RETENTION_MS))?;
+            log::debug!("Pruned {} message indexes", pruned);
+            self.message_indexes_pruned_at = now;
+        }
+        self.store.flush()
+    }
+
+    /// Removes the Olm layer of an `m.room.encrypted` to-device event. The
+    /// sender device is looked up, querying it if needed, and must own the
+    /// ed25519 key the event claims.
+    pub async fn decrypt_olm_event(
+        &mut self,
+        event: &ToDeviceEvent,
+    ) -> Result<DecryptedOlmEvent, Error> {
+        let content: OlmExchange = serde_json::from_value(event.content.clone())
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        if content.algorithm != "m.olm.v1.curve25519-aes-sha2" {
+            return Err(Error::DecryptionError(format!(
+                "unsupported algorithm {}",
+                content.algorithm
+            )));
+        }
+
+        let ciphertext = content
+            .ciphertext
+            .get(&self.curve25519_key())
+            .ok_or_else(|| {
+                Error::DecryptionError(String::from("event is not encrypted for this device"))
+            })?;
+        let message = olm::OlmMessage::from_parts(ciphertext.r#type as usize, &ciphertext.body)
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        let sender_key = vodozemac::Curve25519PublicKey::from_base64(&content.sender_key)
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+
+        let plaintext = self.decrypt_olm_message(&event.sender, sender_key, &message)?;
+        let mut decrypted: DecryptedOlmEvent = serde_json::from_slice(&plaintext)
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+
+        if decrypted.sender != event.sender {
+            return Err(Error::DecryptionError(format!(
+                "event claims to be sent by {} but came from {}",
+                decrypted.sender, event.sender
+            )));
+        }
+        if decrypted.recipient != self.user_id
+            || decrypted.recipient_keys.get("ed25519") != Some(&self.ed25519_key())
+        {
+            return Err(Error::DecryptionError(String::from(
+                "event was meant for another recipient",
+            )));
+        }
+
+        // The signing key in the plaintext ends up as the claimed key of room
+        // keys we store and forward, so it has to be the sender device's.
+        let sender_device = self
+            .known_device_by_curve25519_key(&event.sender, &content.sender_key)
+            .await?
+            .ok_or_else(|| {
+                Error::UnknownDevice(format!("{} ({})", event.sender, content.sender_key))
+            })?;
+        if decrypted.keys.get("ed25519").map(String::as_str) != sender_device.ed25519_key() {
+            return Err(Error::DecryptionError(format!(
+                "event claims an ed25519 key {} ({}) doesn't have",
+                event.sender, sender_device.device_id
+            )));
+        }
+
+        decrypted.sender_key = content.sender_key;
+        Ok(decrypted)
+    }
+
+    fn decrypt_olm_message(
+        &mut self,
+        sender: &str,
+        sender_key: vodozemac::Curve25519PublicKey,
+        message: &olm::OlmMessage,
+    ) -> Result<Vec<u8>, Error> {
+        let sender_key_base64 = sender_key.to_base64();
+        // The store always holds the latest state of cached sessions, drop the
+        // cached copy since decrypting is going to advance the stored one.
+        self.olm_sessions
+            .remove(&store_key(&[sender, &sender_key_base64]));
+        let mut sessions = self.store.load_olm_sessions(&sender_key_base64)?;
+        let mut invalid_mac = false;
+
+        for (session, _) in sessions.iter_mut() {
+            if let olm::OlmMessage::PreKey(pre_key) = message {
+                if session.session_keys() != pre_key.session_keys() {
+                    continue;
+                }
+            }
+            match session.decrypt(message) {
+                Ok(plaintext) => {
+                    self.store
+                        .save_olm_session(&sender_key_base64, session, now_ms())?;
+                    return Ok(plaintext);
+                }
+                // The session this pre-key message created already used it.
+                Err(_) if matches!(message, olm::OlmMessage::PreKey(_)) => {
+                    return Err(Error::ReplayedMessage(sender_key_base64));
+                }
+                Err(olm::DecryptionError::InvalidMAC(_)) => invalid_mac = true,
+                Err(_) => {}
+            }
+        }
+
+        match message {
+            olm::OlmMessage::PreKey(pre_key) => {
+                let result = self
+                    .olm_account
+                    .create_inbound_session(sender_key, pre_key)
+                    .map_err(|e| match e {
+                        olm::SessionCreationError::Decryption(
+                            olm::DecryptionError::InvalidMAC(_),
+                        ) => Error::InvalidMac(sender_key_base64.clone()),
+                        olm::SessionCreationError::MissingOneTimeKey(_) => {
+                            Error::UnknownOlmSession(format!(
+                                "{} used an unknown one-time key",
+                                sender_key_base64
+                            ))
+                        }
+                        e => Error::DecryptionError(e.to_string()),
+                    })?;
+                self.store.save_account(&self.olm_account)?;
+                self.store
+                    .save_olm_session(&sender_key_base64, &result.session, now_ms())?;
+                Ok(result.plaintext)
+            }
+            olm::OlmMessage::Normal(_) if invalid_mac => Err(Error::InvalidMac(sender_key_base64)),
+            olm::OlmMessage::Normal(_) if sessions.is_empty() => {
+                Err(Error::UnknownOlmSession(sender_key_base64))
+            }
+            olm::OlmMessage::Normal(_) => Err(Error::DecryptionError(format!(
+                "no Olm session with {} could decrypt the message",
+                sender_key_base64
+            ))),
+        }
+    }
+
+    /// Stores the Megolm session carried by a decrypted `m.room_key` event.
+    pub fn receive_room_key(&mut self, event: &DecryptedOlmEvent) -> Result<(), Error> {
+        let content: KeyExchangeData = serde_json::from_value(event.content.clone())
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        if content.algorithm != "m.megolm.v1.aes-sha2" {
+            return Err(Error::DecryptionError(format!(
+                "unsupported algorithm {}",
+                content.algorithm
+            )));
+        }
+
+        let session_key = megolm::SessionKey::from_base64(&content.session_key)
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        let session =
+            megolm::InboundGroupSession::new(&session_key, megolm::SessionConfig::version_1());
+        if session.session_id() != content.session_id {
+            return Err(Error::DecryptionError(String::from(
+                "session ID doesn't match the session key",
+            )));
+        }
+
+        let sender_claimed_ed25519_key = event.keys.get("ed25519").cloned().unwrap_or_default();
+        self.add_inbound_group_session(
+            &content.room_id,
+            &event.sender_key,
+            session,
+            &sender_claimed_ed25519_key,
+            &[],
+        )
+    }
+
+    /// Stores an inbound session unless we already know the same session
+    /// from an earlier or equal message index.
+    fn add_inbound_group_session(
+        &mut self,
+        room_id: &str,
+        sender_key: &str,
+        mut session: megolm::InboundGroupSession,
+        sender_claimed_ed25519_key: &str,
+        forwarding_curve25519_key_chain: &[String],
+    ) -> Result<(), Error> {
+        if let Some(mut existing) =
+            self.store
+                .load_inbound_group_session(room_id, sender_key, &session.session_id())?
+        {
+            if existing.compare(&mut session) != megolm::SessionOrdering::Worse {
+                return Ok(());
+            }
+        }
+        self.store
+            .save_inbound_group_session(room_id, sender_key, &session)?;
+        self.store.save_inbound_session_info(
+            room_id,
+            sender_key,
+            &session.session_id(),
+            sender_claimed_ed25519_key,
+            forwarding_curve25519_key_chain,
+        )
+    }
+
+    /// Remembers why `sender` will not send us a session key, so that failing
+    /// to decrypt its messages can report the reason. Notices arrive
+    /// unencrypted, so `sender` must own the sender key they are about.
+    pub async fn receive_room_key_withheld(
+        &mut self,
+        sender: &str,
+        withheld: &RoomKeyWithheld,
+    ) -> Result<(), Error> {
+        if self
+            .known_device_by_curve25519_key(sender, &withheld.sender_key)
+            .await?
+            .is_none()
+        {
+            return Err(Error::UnknownDevice(format!(
+                "{} has no device with key {}",
+                sender, withheld.sender_key
+            )));
+        }
+        log::info!(
+            "{} withheld room key {} in {}: {}",
+            sender,
+            withheld.session_id.as_deref().unwrap_or("*"),
+            withheld.room_id.as_deref().unwrap_or("*"),
+            withheld.code.as_str()
+        );
+        self.store.save_withheld(withheld)
+    }
+
+    /// The error for a room event whose session we don't have, telling why
+    /// if the sender withheld the key from us.
+    fn missing_session_error(&self, room_id: &str, content: &MegolmMessage) -> Error {
+        let withheld =
+            match self
+                .store
+                .load_withheld(room_id, &content.sender_key, &content.session_id)
+            {
+                Ok(None) => self.store.load_withheld("", &content.sender_key, ""),
+                withheld => withheld,
+            };
+        match withheld {
+            Ok(Some(withheld)) => Error::KeyWithheld(format!(
+                "session {}: {} ({})",
+                content.session_id,
+                withheld.code.as_str(),
+                withheld.reason.unwrap_or_else(|| withheld.code.to_string())
+            )),
+            _ => Error::UnknownMegolmSession(content.session_id.clone()),
+        }
+    }
+
+    /// Answers an `m.room_key_request`. Our own verified devices get any
+    /// session we hold, other users only the sessions we created and shared
+    /// with the requesting device, from the index it originally got. Every
+    /// other request is declined with an `m.room_key.withheld` notice.
+    pub async fn receive_room_key_request(
+        &mut self,
+        sender: &str,
+        request: &RoomKeyRequest,
+    ) -> Result<(), Error> {
+        let body = match (&request.body, request.action.as_str()) {
+            (Some(body), "request") => body,
+            (_, action) => {
+                log::info!(
+                    "Key request {} from {} ({}): {}, nothing to do",
+                    request.request_id,
+                    sender,
+                    request.requesting_device_id,
+                    action
+                );
+                return Ok(());
+            }
+        };
+        log::info!(
+            "Key request {} from {} ({}) for session {} of {} in {}",
+            request.request_id,
+            sender,
+            request.requesting_device_id,
+            body.session_id,
+            body.sender_key,
+            body.room_id
+        );
+        if sender == self.user_id && request.requesting_device_id == self.device_id {
+            return Ok(());
+        }
+
+        let device = match self
+            .known_device(sender, &request.requesting_device_id)
+            .await?
+        {
+            Some(device) => device,
+            None => {
+                log::warn!(
+                    "Declining key request {}: unknown device {} ({})",
+                    request.request_id,
+                    sender,
+                    request.requesting_device_id
+                );
+                return Ok(());
+            }
+        };
+
+        match self.forwardable_key(sender, &device, body)? {
+            Ok(forwarded_room_key) => {
+                log::info!(
+                    "Forwarding session {} to {} ({})",
+                    body.session_id,
+                    sender,
+                    device.device_id
+                );
+                let mut olm_session = self.olm_session_for(&device).await?;
+                let olm_exchange = OlmExchange::encrypt_event(
+                    self,
+                    &device,
+                    &mut olm_session,
+                    "m.forwarded_room_key",
+                    forwarded_room_key,
+                );
+                self.cache_olm_session(&device, olm_session)?;
+                self.backend_api
+                    .send_olm(sender.to_owned(), device.device_id.clone(), olm_exchange)
+                    .await
+            }
+            Err(code) => {
+                log::info!(
+                    "Declining key request {} from {} ({}): {}",
+                    request.request_id,
+                    sender,
+                    device.device_id,
+                    code.as_str()
+                );
+                let withheld = RoomKeyWithheld {
+                    algorithm: body.algorithm.clone(),
+                    room_id: Some(body.room_id.clone()),
+                    session_id: Some(body.session_id.clone()),
+                    sender_key: body.sender_key.clone(),
+                    code,
+                    reason: Some(code.to_string()),
+                };
+                self.backend_api
+                    .send_to_device(
+                        "m.room_key.withheld",
+                        HashMap::from([(
+                            sender.to_owned(),
+                            HashMap::from([(device.device_id, withheld)]),
+                        )]),
+                    )
+                    .await
+            }
+        }
+    }
+
+    /// Asks our other devices for the key of the session `event` was encrypted
+    /// with, after decrypting it failed for lack of that session. The event
+    /// is kept and decrypted again once the key arrives.
+    pub async fn request_room_key(
+        &mut self,
+        room_id: &str,
+        event: &RoomEvent,
+    ) -> Result<(), Error> {
+        let content: MegolmMessage = serde_json::from_value(event.content.clone())
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        self.pending_room_events
+            .entry(store_key(&[
+                room_id,
+                &content.sender_key,
+                &content.session_id,
+            ]))
+            .or_default()
+            .push(event.clone());
+
+        if self
+            .store
+            .load_outgoing_key_request(room_id, &content.sender_key, &content.session_id)?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let body = RequestedKeyInfo {
+            algorithm: content.algorithm,
+            room_id: room_id.to_owned(),
+            sender_key: content.sender_key,
+            session_id: content.session_id,
+        };
+        let request_id = uuid::Uuid::new_v4().to_string();
+        log::info!(
+            "Requesting room key {} in {} ({})",
+            body.session_id,
+            room_id,
+            request_id
+        );
+        self.send_to_own_devices(
+            "m.room_key_request",
+            RoomKeyRequest {
+                action: String::from("request"),
+                body: Some(body.clone()),
+                request_id: request_id.clone(),
+                requesting_device_id: self.device_id.clone(),
+            },
+        )
+        .await?;
+        self.store.save_outgoing_key_request(
+            room_id,
+            &body.sender_key,
+            &body.session_id,
+            &request_id,
+        )
+    }
+
+    /// Imports a key we requested from one of our own verified devices,
+    /// cancels the request and returns the pending events it decrypts.
+    pub async fn receive_forwarded_room_key(
+        &mut self,
+        event: &DecryptedOlmEvent,
+    ) -> Result<Vec<DecryptedRoomEvent>, Error> {
+        let content: ForwardedRoomKey = serde_json::from_value(event.content.clone())
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        let sender_device = event.sender_device.clone().unwrap_or_default();
+        let forwarder = format!("{} ({})", event.sender, sender_device);
+
+        let known_device = self
+            .store
+            .load_device_keys(&event.sender)?
+            .remove(&sender_device);
+        if event.sender != self.user_id
+            || sender_device == self.device_id
+            || known_device.and_then(|device| device.curve25519_key().map(str::to_owned))
+                != Some(event.sender_key.clone())
+            || !self.is_device_trusted(&event.sender, &sender_device)?
+        {
+            return Err(Error::UnknownDevice(forwarder));
+        }
+
+        let request_id = self
+            .store
+            .load_outgoing_key_request(&content.room_id, &content.sender_key, &content.session_id)?
+            .ok_or_else(|| Error::UnrequestedKey(content.session_id.clone()))?;
+
+        let session_key = megolm::ExportedSessionKey::from_base64(&content.session_key)
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        let session =
+            megolm::InboundGroupSession::import(&session_key, megolm::SessionConfig::version_1());
+        if session.session_id() != content.session_id {
+            return Err(Error::DecryptionError(String::from(
+                "session ID doesn't match the session key",
+            )));
+        }
+
+        log::info!(
+            "Received room key {} in {} from {}",
+            content.session_id,
+            content.room_id,
+            forwarder
+        );
+        let mut forwarding_chain = content.forwarding_curve25519_key_chain.clone();
+        forwarding_chain.push(event.sender_key.clone());
+        self.add_inbound_group_session(
+            &content.room_id,
+            &content.sender_key,
+            session,
+            &content.sender_claimed_ed25519_key,
+            &forwarding_chain,
+        )?;
+
+        self.store.remove_outgoing_key_request(
+            &content.room_id,
+            &content.sender_key,
+            &content.session_id,
+        )?;
+        // The key is imported either way, other devices just keep working on
+        // a request that no longer matters.
+        if let Err(e) = self
+            .send_to_own_devices(
+                "m.room_key_request",
+                RoomKeyRequest {
+                    action: String::from("request_cancellation"),
+                    body: None,
+                    request_id: request_id.clone(),
+                    requesting_device_id: self.device_id.clone(),
+                },
+            )
+            .await
+        {
+            log::warn!("Failed to cancel key request {}: {}", request_id, e);
+        }
+
+        Ok(self
+            .retry_pending_room_events(&content.room_id, &content.sender_key, &content.session_id)
+            .await)
+    }
+
+    /// Decrypts the events that were waiting for the given session, dropping
+    /// the ones that still fail.
+    async fn retry_pending_room_events(
+        &mut self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Vec<DecryptedRoomEvent> {
+        let pending = self
+            .pending_room_events
+            .remove(&store_key(&[room_id, sender_key, session_id]))
+            .unwrap_or_default();
+
+        let mut decrypted = Vec::new();
+        for event in pending {
+            match self.decrypt_room_event(room_id, &event).await {
+                Ok(event) => decrypted.push(event),
+                Err(e) => log::warn!("Failed to decrypt {} again: {}", event.event_id, e),
+            }
+        }
+        decrypted
+    }
+
+    /// Sends an unencrypted to-device event to all our devices.
+    async fn send_to_own_devices<T: serde::Serialize>(
+        &self,
+        event_type: &str,
+        content: T,
+    ) -> Result<(), Error> {
+        self.backend_api
+            .send_to_device(
+                event_type,
+                HashMap::from([(
+                    self.user_id.clone(),
+                    HashMap::from([(String::from("*"), content)]),
+                )]),
+            )
+            .await
+    }
+
+    /// Applies the key forwarding policy to a request of `device`: the key to
+    /// forward, or the reason for not forwarding it.
+    pub fn forwardable_key(
+        &self,
+        sender: &str,
+        device: &DeviceKey,
+        body: &RequestedKeyInfo,
+    ) -> Result<Result<ForwardedRoomKey, WithheldCode>, Error> {
+        if body.algorithm != "m.megolm.v1.aes-sha2" {
+            return Ok(Err(WithheldCode::Unavailable));
+        }
+        let (mut session, (sender_claimed_ed25519_key, forwarding_chain)) = match (
+            self.store.load_inbound_group_session(
+                &body.room_id,
+                &body.sender_key,
+                &body.session_id,
+            )?,
+            self.store.load_inbound_session_info(
+                &body.room_id,
+                &body.sender_key,
+                &body.session_id,
+            )?,
+        ) {
+            (Some(session), Some(info)) => (session, info),
+            _ => return Ok(Err(WithheldCode::Unavailable)),
+        };
+
+        if self.store.load_trust(sender, &device.device_id)? == TrustState::Blacklisted {
+            return Ok(Err(WithheldCode::Blacklisted));
+        }
+        let message_index = if sender == self.user_id {
+            if !self.is_device_trusted(sender, &device.device_id)? {
+                return Ok(Err(WithheldCode::Unverified));
+            }
+            session.first_known_index()
+        } else {
+            // Other users only get what we gave them in the first place.
+            if body.sender_key != self.curve25519_key() {
+                return Ok(Err(WithheldCode::Unauthorised));
+            }
+            let shared_with = self
+                .store
+                .load_shared_with(&body.room_id, &body.session_id)?;
+            match shared_with
+                .get(sender)
+                .and_then(|devices| devices.get(&device.device_id))
+            {
+                Some(share_info)
+                    if device.curve25519_key() == Some(share_info.curve25519_key.as_str()) =>
+                {
+                    share_info.message_index
+                }
+                _ => return Ok(Err(WithheldCode::Unauthorised)),
+            }
+        };
+
+        match session.export_at(message_index) {
+            Some(session_key) => Ok(Ok(ForwardedRoomKey {
+                algorithm: body.algorithm.clone(),
+                room_id: body.room_id.clone(),
+                sender_key: body.sender_key.clone(),
+                session_id: body.session_id.clone(),
+                session_key: session_key.to_base64(),
+                sender_claimed_ed25519_key,
+                forwarding_curve25519_key_chain: forwarding_chain,
+            })),
+            None => Ok(Err(WithheldCode::Unavailable)),
+        }
+    }
+
+    /// The keys of a device, from the store or else from the server.
+    async fn known_device(
+        &mut self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<Option<DeviceKey>, Error> {
+        if let Some(device) = self.store.load_device_keys(user_id)?.remove(device_id) {
+            return Ok(Some(device));
+        }
+        Ok(self
+            .query_devices(vec![user_id.to_owned()])
+            .await?
+            .remove(user_id)
+            .and_then(|mut devices| devices.remove(device_id)))
+    }
+
+    /// The device of `user_id` with the given Curve25519 key, from the store
+    /// or else from the server.
+    async fn known_device_by_curve25519_key(
+        &mut self,
+        user_id: &str,
+        curve25519_key: &str,
+    ) -> Result<Option<DeviceKey>, Error> {
+        if let Some(device) = self.device_by_curve25519_key(user_id, curve25519_key)? {
+            return Ok(Some(device));
+        }
+        Ok(self
+            .query_device_keys(user_id)
+            .await?
+            .into_values()
+            .find(|device| device.curve25519_key() == Some(curve25519_key)))
+    }
+
+    /// Hands the cross-signing master keys of both users to a new flow, so
+    /// that it can offer QR codes. Keys we haven't seen are queried; if the
+    /// query fails the flow simply sticks to SAS.
+    async fn set_master_keys(&mut self, verification: &mut Verification) -> Result<(), Error> {
+        let mut user_ids = vec![self.user_id.clone()];
+        if verification.other_user_id != self.user_id {
+            user_ids.push(verification.other_user_id.clone());
+        }
+        let mut missing = Vec::new();
+        for user_id in &user_ids {
+            if *user_id == self.user_id && self.cross_signing.is_some() {
+                continue;
+            }
+            if !self
+                .store
+                .load_cross_signing_keys(user_id)?
+                .contains_key(MASTER)
+            {
+                missing.push(user_id.clone());
+            }
+        }
+        if !missing.is_empty() {
+            if let Err(e) = self.query_devices(missing).await {
+                log::warn!("Couldn't query master keys: {}", e);
+            }
+        }
+
+        let (own_master_key, own_master_key_trusted, other_master_key) =
+            self.qr_master_keys(&verification.other_user_id)?;
+        verification.set_master_keys(own_master_key, own_master_key_trusted, other_master_key);
+        Ok(())
+    }
+
+    /// Recovers from an Olm session the sender can't use any more, by opening
+    /// a new one with a freshly claimed one-time key and sending an `m.dummy`
+    /// over it. Happens at most once an hour per device, counted from the last
+    /// `m.dummy` that was sent; returns whether the new session was sent.
+    pub async fn unwedge_olm_session(
+        &mut self,
+        sender: &str,
+        sender_key: &str,
+    ) -> Result<bool, Error> {
+        let now = now_ms();
+        if let Some(last_unwedging) = self.store.load_last_unwedging(sender, sender_key)? {
+            if now.saturating_sub(last_unwedging) < UNWEDGING_INTERVAL_MS {
+                log::info!(
+                    "Not unwedging Olm session with {} ({}) again yet",
+                    sender,
+                    sender_key
+                );
+                return Ok(false);
+            }
+        }
+
+        let device = self
+            .known_device_by_curve25519_key(sender, sender_key)
+            .await?
+            .ok_or_else(|| Error::UnknownDevice(format!("{} ({})", sender, sender_key)))?;
+
+        let recipient_otk = self
+            .claim_one_time_keys(std::slice::from_ref(&device))
+            .await?
+            .remove(&store_key(&[&device.user_id, &device.device_id]))
+            .unwrap_or_else(|| {
+                Err(Error::MissingOneTimeKey(format!(
+                    "{} ({})",
+                    device.user_id, device.device_id
+                )))
+            })?;
+        let mut olm_session = self.create_outbound_olm_session(&device, recipient_otk)?;
+        let olm_exchange = OlmExchange::encrypt_event(
+            self,
+            &device,
+            &mut olm_session,
+            "m.dummy",
+            serde_json::json!({}),
+        );
+        self.cache_olm_session(&device, olm_session)?;
+
+        log::info!(
+            "Unwedging Olm session with {} ({})",
+            device.user_id,
+            device.device_id
+        );
+        self.backend_api
+            .send_olm(
+                device.user_id.clone(),
+                device.device_id.clone(),
+                olm_exchange,
+            )
+            .await?;
+        self.store.save_last_unwedging(sender, sender_key, now)?;
+        Ok(true)
+    }
+
+    /// The stored device of `user_id` with the given Curve25519 key.
+    fn device_by_curve25519_key(
+        &self,
+        user_id: &str,
+        curve25519_key: &str,
+    ) -> Result<Option<DeviceKey>, Error> {
+        Ok(self
+            .store
+            .load_device_keys(user_id)?
+            .into_values()
+            .find(|device| device.curve25519_key() == Some(curve25519_key)))
+    }
+
+    /// Asks `device_id` of `user_id` to verify this device interactively.
+    /// Returns the flow ID to follow the verification with.
+    pub async fn request_verification(
+        &mut self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<String, Error> {
+        let mut verification = Verification::new(
+            self,
+            uuid::Uuid::new_v4().to_string(),
+            user_id.to_owned(),
+            Some(device_id.to_owned()),
+            true,
+        );
+        if let Some(device) = self.known_device(user_id, device_id).await? {
+            verification.set_other_device(device);
+        }
+        self.set_master_keys(&mut verification).await?;
+
+        let request = verification.request();
+        self.send_verification_messages(&verification, vec![request])
+            .await?;
+        let flow_id = verification.flow_id.clone();
+        self.verifications.insert(flow_id.clone(), verification);
+        Ok(flow_id)
+    }
+
+    /// Asks `user_id` to verify in the DM `room_id`, so that any of their
+    /// devices can answer. Returns the flow ID, the event ID of the request.
+    pub async fn request_verification_in_room(
+        &mut self,
+        room_id: &str,
+        user_id: &str,
+    ) -> Result<String, Error> {
+        let mut verification =
+            Verification::new(self, String::new(), user_id.to_owned(), None, true).in_room(room_id);
+        self.set_master_keys(&mut verification).await?;
+        let (event_type, content) = verification.request();
+        let flow_id = self
+            .send_encrypted_event(room_id, &event_type, content)
+            .await?;
+        verification.flow_id = flow_id.clone();
+        self.verifications.insert(flow_id.clone(), verification);
+        Ok(flow_id)
+    }
+
+    /// The verification flow with the given ID, to read its state, emoji and
+    /// decimals.
+    pub fn verification(&self, flow_id: &str) -> Option<&Verification> {
+        self.verifications.get(flow_id)
+    }
+
+    /// Accepts a verification request another device sent us.
+    pub async fn accept_verification(&mut self, flow_id: &str) -> Result<(), Error> {
+        self.advance_verification(flow_id, Verification::accept)
+            .await
+    }
+
+    /// Starts comparing emoji or decimals in a flow both sides are ready for.
+    pub async fn start_sas_verification(&mut self, flow_id: &str) -> Result<(), Error> {
+        self.advance_verification(flow_id, Verification::start_sas)
+            .await
+    }
+
+    /// Confirms that the emoji or decimals shown on both devices match, or
+    /// that the other device scanned our QR code. Once the other device
+    /// confirms too, it is marked as verified.
+    pub async fn confirm_verification(&mut self, flow_id: &str) -> Result<(), Error> {
+        self.advance_verification(flow_id, Verification::confirm)
+            .await
+    }
+
+    /// The QR code to show in a flow both sides are ready for, e.g. as
+    /// `to_png` or `to_terminal`. Needs the cross-signing master keys.
+    pub fn show_qr_code(&mut self, flow_id: &str) -> Result<QrCode, Error> {
+        self.verifications
+            .get_mut(flow_id)
+            .ok_or_else(|| Error::VerificationError(format!("unknown flow {}", flow_id)))?
+            .show_qr_code()
+    }
+
+    /// Answers the QR code another device shows, given its scanned bytes.
+    /// Once the other device confirms, it is marked as verified.
+    pub async fn scan_qr_code(&mut self, data: &[u8]) -> Result<(), Error> {
+        let qr_code = QrCode::from_bytes(data)?;
+        self.advance_verification(&qr_code.flow_id, |verification| {
+            verification.scan_qr_code(&qr_code)
+        })
+        .await
+    }
+
+    /// Cancels a verification, e.g. because the emoji don't match.
+    pub async fn cancel_verification(&mut self, flow_id: &str) -> Result<(), Error> {
+        self.advance_verification(flow_id, |verification| {
+            verification.cancel("m.user", "The user cancelled the verification")
+        })
+        .await
+    }
+
+    /// Feeds an `m.key.verification.*` to-device event into its flow.
+    pub async fn receive_verification_event(
+        &mut self,
+        sender: &str,
+        event_type: &str,
+        content: &serde_json::Value,
+    ) -> Result<(), Error> {
+        let flow_id = content["transaction_id"]
+            .as_str()
+            .ok_or_else(|| {
+                Error::VerificationError(format!("{} without transaction_id", event_type))
+            })?
+            .to_owned();
+
+        if event_type == "m.key.verification.request" {
+            let from_device = content["from_device"].as_str().unwrap_or_default();
+            if self.verifications.contains_key(&flow_id) {
+                log::info!("Ignoring request for existing flow {}", flow_id);
+                return Ok(());
+            }
+            let now = now_ms();
+            match content["timestamp"].as_u64() {
+                Some(timestamp)
+                    if timestamp + VERIFICATION_REQUEST_MAX_AGE_MS >= now
+                        && timestamp <= now + VERIFICATION_REQUEST_MAX_SKEW_MS => {}
+                _ => {
+                    log::info!(
+                        "Ignoring stale verification request {} from {} ({})",
+                        flow_id,
+                        sender,
+                        from_device
+                    );
+                    return Ok(());
+                }
+            }
+            log::info!(
+                "Verification request {} from {} ({})",
+                flow_id,
+                sender,
+                from_device
+            );
+            let mut verification = Verification::new(
+                self,
+                flow_id.clone(),
+                sender.to_owned(),
+                Some(from_device.to_owned()),
+                false,
+            );
+            if let Some(device) = self.known_device(sender, from_device).await? {
+                verification.set_other_device(device);
+            }
+            self.set_master_keys(&mut verification).await?;
+            self.verifications.insert(flow_id, verification);
+            return Ok(());
+        }
+
+        match self.verifications.get(&flow_id) {
+            Some(verification) if verification.other_user_id == sender => {}
+            _ => {
+                log::info!("Ignoring {} for unknown flow {}", event_type, flow_id);
+                return Ok(());
+            }
+        }
+        self.advance_verification(&flow_id, |verification| {
+            verification.receive(event_type, content)
+        })
+        .await
+    }
+
+    /// Feeds a decrypted room event into its verification flow, if it's a
+    /// request addressed to us or references one. Other events are ignored.
+    pub async fn receive_room_verification_event(
+        &mut self,
+        event: &DecryptedRoomEvent,
+    ) -> Result<(), Error> {
+        if event.sender == self.user_id {
+            return Ok(());
+        }
+        let from_device = event.content["from_device"].as_str().unwrap_or_default();
+
+        if event.r#type == "m.room.message" {
+            if event.content["msgtype"].as_str() != Some("m.key.verification.request")
+                || event.content["to"].as_str() != Some(self.user_id.as_str())
+                || self.verifications.contains_key(&event.event_id)
+            {
+                return Ok(());
+            }
+            log::info!(
+                "Verification request {} from {} ({}) in {}",
+                event.event_id,
+                event.sender,
+                from_device,
+                event.room_id
+            );
+            let mut verification = Verification::new(
+                self,
+                event.event_id.clone(),
+                event.sender.clone(),
+                Some(from_device.to_owned()),
+                false,
+            )
+            .in_room(&event.room_id);
+            if let Some(device) = self.known_device(&event.sender, from_device).await? {
+                verification.set_other_device(device);
+            }
+            self.set_master_keys(&mut verification).await?;
+            self.verifications
+                .insert(event.event_id.clone(), verification);
+            return Ok(());
+        }
+        if !event.r#type.starts_with("m.key.verification.") {
+            return Ok(());
+        }
+
+        let flow_id = event.content["m.relates_to"]["event_id"]
+            .as_str()
+            .unwrap_or_default();
+        let needs_device = match self.verifications.get(flow_id) {
+            Some(verification)
+                if verification.other_user_id == event.sender
+                    && verification.room_id.as_deref() == Some(event.room_id.as_str()) =>
+            {
+                verification.other_device().is_none()
+            }
+            _ => {
+                log::info!("Ignoring {} for unknown flow {}", event.r#type, flow_id);
+                return Ok(());
+            }
+        };
+        // Only the device answering our request tells us who it is.
+        if needs_device && !from_device.is_empty() {
+            if let Some(device) = self.known_device(&event.sender, from_device).await? {
+                if let Some(verification) = self.verifications.get_mut(flow_id) {
+                    verification.set_other_device(device);
+                }
+            }
+        }
+        self.advance_verification(flow_id, |verification| {
+            verification.receive(&event.r#type, &event.content)
+        })
+        .await
+    }
+
+    /// Runs one step of a verification flow, sends what it produced and
+    /// trusts the other device or master key once the flow proved it.
+    async fn advance_verification<F>(&mut self, flow_id: &str, step: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Verification) -> Vec<(String, serde_json::Value)>,
+    {
+        let mut verification = self
+            .verifications
+            .remove(flow_id)
+            .ok_or_else(|| Error::VerificationError(format!("unknown flow {}", flow_id)))?;
+        let was_verified = verification.is_verified();
+        let messages = step(&mut verification);
+        let sent = self
+            .send_verification_messages(&verification, messages)
+            .await;
+
+        if !was_verified {
+            if let Some(master_key) = verification.verified_master_key() {
+                log::info!(
+                    "Verified master key {} of {} in flow {}",
+                    master_key,
+                    verification.other_user_id,
+                    flow_id
+                );
+                if let Err(e) = self
+                    .trust_master_key(&verification.other_user_id, master_key)
+                    .await
+                {
+                    log::warn!(
+                        "Failed to trust master key of {}: {}",
+                        verification.other_user_id,
+                        e
+                    );
+                }
+            }
+        }
+        if verification.is_device_verified() {
+            if let Some(device_id) = &verification.other_device_id {
+                log::info!(
+                    "Verified {} ({}) in flow {}",
+                    verification.other_user_id,
+                    device_id,
+                    flow_id
+                );
+                self.store.save_trust(
+                    &verification.other_user_id,
+                    device_id,
+                    TrustState::Verified,
+                )?;
+            }
+        }
+        if let VerificationState::Cancelled { code, reason } = &verification.state {
+            log::info!("Verification {} cancelled: {} ({})", flow_id, reason, code);
+        }
+        self.verifications.insert(flow_id.to_owned(), verification);
+        sent
+    }
+
+    async fn send_verification_messages(
+        &mut self,
+        verification: &Verification,
+        messages: Vec<(String, serde_json::Value)>,
+    ) -> Result<(), Error> {
+        if let Some(room_id) = &verification.room_id {
+            for (event_type, content) in messages {
+                self.send_encrypted_event(room_id, &event_type, content)
+                    .await?;
+            }
+            return Ok(());
+        }
+        let device_id = verification
+            .other_device_id
+            .clone()
+            .unwrap_or_else(|| String::from("*"));
+        for (event_type, content) in messages {
+            self.backend_api
+                .send_to_device(
+                    &event_type,
+                    HashMap::from([(
+                        verification.other_user_id.clone(),
+                        HashMap::from([(device_id.clone(), content)]),
+                    )]),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the Megolm layer of an `m.room.encrypted` room event. The
+    /// session's sender key must belong to a device of the event's sender,
+    /// which is queried if we don't know it yet.
+    pub async fn decrypt_room_event(
+        &mut self,
+        room_id: &str,
+        event: &RoomEvent,
+    ) -> Result<DecryptedRoomEvent, Error> {
+        let content: MegolmMessage = serde_json::from_value(event.content.clone())
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        if content.algorithm != "m.megolm.v1.aes-sha2" {
+            return Err(Error::DecryptionError(format!(
+                "unsupported algorithm {}",
+                content.algorithm
+            )));
+        }
+
+        let mut session = self
+            .store
+            .load_inbound_group_session(room_id, &content.sender_key, &content.session_id)?
+            .ok_or_else(|| self.missing_session_error(room_id, &content))?;
+        // The server picks the sender of the event, the key is what we got
+        // the session from.
+        if self
+            .known_device_by_curve25519_key(&event.sender, &content.sender_key)
+            .await?
+            .is_none()
+        {
+            return Err(Error::UnknownDevice(format!(
+                "{} has no device with key {}",
+                event.sender, content.sender_key
+            )));
+        }
+        let message = megolm::MegolmMessage::from_base64(&content.ciphertext)
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        let decrypted = session.decrypt(&message).map_err(|e| match e {
+            megolm::DecryptionError::InvalidMAC(_) => Error::InvalidMac(content.session_id.clone()),
+            e => Error::DecryptionError(e.to_string()),
+        })?;
+
+        match self.store.load_message_index(
+            room_id,
+            &content.sender_key,
+            &content.session_id,
+            decrypted.message_index,
+        )? {
+            Some((event_id, origin_server_ts)) => {
+                if event_id != event.event_id || origin_server_ts != event.origin_server_ts {
+                    return Err(Error::ReplayedMessage(format!(
+                        "index {} of session {} was first used by {}",
+                        decrypted.message_index, content.session_id, event_id
+                    )));
+                }
+            }
+            None if decrypted.message_index
+                < self.store.load_message_index_floor(
+                    room_id,
+                    &content.sender_key,
+                    &content.session_id,
+                )? =>
+            {
+                return Err(Error::ReplayedMessage(format!(
+                    "index {} of session {} was used by an event we forgot",
+                    decrypted.message_index, content.session_id
+                )));
+            }
+            None => self.store.save_message_index(
+                room_id,
+                &content.sender_key,
+                &content.session_id,
+                decrypted.message_index,
+                &event.event_id,
+                event.origin_server_ts,
+            )?,
+        }
+
+        let plaintext: serde_json::Value = serde_json::from_slice(&decrypted.plaintext)
+            .map_err(|e| Error::DecryptionError(e.to_string()))?;
+        if plaintext["room_id"].as_str() != Some(room_id) {
+            return Err(Error::DecryptionError(String::from(
+                "event was encrypted for another room",
+            )));
+        }
+
+        Ok(DecryptedRoomEvent {
+            event_id: event.event_id.clone(),
+            room_id: room_id.to_owned(),
+            sender: event.sender.clone(),
+            sender_key: content.sender_key,
+            session_id: content.session_id,
+            message_index: decrypted.message_index,
+            r#type: plaintext["type"].as_str().unwrap_or_default().to_owned(),
+            content: plaintext["content"].clone(),
+        })
+    }
+
+    /// Fetches the device keys of `user_id`. Devices whose self-signature
+    /// doesn't verify, or whose ed25519 key changed since we first saw them,
+    /// are dropped.
+    pub async fn query_device_keys(
+        &mut self,
+        user_id: &str,
+    ) -> Result<HashMap<String, DeviceKey>, Error> {
+        Ok(self
+            .query_devices(vec![user_id.to_owned()])
+            .await?
+            .remove(user_id)
+            .unwrap_or_default())
+    }
+
+    /// Same as `query_device_keys` for several users in a single request.
+    pub async fn query_devices(
+        &mut self,
+        user_ids: Vec<String>,
+    ) -> Result<HashMap<String, HashMap<String, DeviceKey>>, Error> {
+        let response = self.backend_api.query_keys_for(user_ids).await?;
+
+        for (user_id, master_key) in response.master_keys {
+            let self_signing_key = response.self_signing_keys.get(&user_id).cloned();
+            let user_signing_key = response.user_signing_keys.get(&user_id).cloned();
+            self.verify_queried_cross_signing_keys(
+                &user_id,
+                master_key,
+                self_signing_key,
+                user_signing_key,
+            )?;
+        }
+
+        let mut users = HashMap::new();
+        for (user_id, queried_devices) in response.device_keys {
+            let devices = self.verify_queried_devices(&user_id, queried_devices)?;
+            users.insert(user_id, devices);
+        }
+        Ok(users)
+    }
+
+    fn verify_queried_devices(
+        &mut self,
+        user_id: &str,
+        queried_devices: HashMap<String, serde_json::Value>,
+    ) -> Result<HashMap<String, DeviceKey>, Error> {
+        let known_devices = self.store.load_signed_device_keys(user_id)?;
+
+        let mut devices = HashMap::new();
+        let mut tracked_devices = HashMap::new();
+        for (device_id, json) in queried_devices {
+            let device_key = match DeviceKey::from_signed_json(&json, user_id, &device_id) {
+                Ok(device_key) => device_key,
+                Err(e) => {
+                    log::warn!("Dropping device {} of {}: {}", device_id, user_id, e);
+                    continue;
+                }
+            };
+            if let Some(known) = known_devices.get(&device_id) {
+                let known_ed25519 = known["keys"][format!("ed25519:{}", device_id)].as_str();
+                if known_ed25519 != device_key.ed25519_key() {
+                    log::warn!(
+                        "Dropping device {} of {}: ed25519 key changed",
+                        device_id,
+                        user_id
+                    );
+                    tracked_devices.insert(device_id, known.clone());
+                    continue;
+                }
+            }
+            tracked_devices.insert(device_id.clone(), json);
+            devices.insert(device_id, device_key);
+        }
+
+        self.store.save_device_keys(user_id, &tracked_devices)?;
+        Ok(devices)
+    }
+
+    /// Stores the cross-signing keys of a user that are correctly signed by
+    /// its master key, and pins the first master key we see.
+    fn verify_queried_cross_signing_keys(
+        &mut self,
+        user_id: &str,
+        master_json: serde_json::Value,
+        self_signing_key: Option<serde_json::Value>,
+        user_signing_key: Option<serde_json::Value>,
+    ) -> Result<(), Error> {
+        let master_key =
+            match CrossSigningKey::from_signed_json(&master_json, user_id, MASTER, None) {
+                Ok(master_key) => master_key,
+                Err(e) => {
+                    log::warn!("Dropping master key of {}: {}", user_id, e);
+                    return Ok(());
+                }
+            };
+        let master_ed25519 = master_key.ed25519_key().unwrap_or_default().to_owned();
+        match self.store.load_pinned_master_key(user_id)? {
+            Some(pinned) if pinned != master_ed25519 => {
+                log::warn!("Master key of {} changed from the pinned one", user_id)
+            }
+            Some(_) => {}
+            None => self
+                .store
+                .save_pinned_master_key(user_id, &master_ed25519)?,
+        }
+
+        let mut keys = HashMap::new();
+        for (usage, key) in [
+            (SELF_SIGNING, self_signing_key),
+            (USER_SIGNING, user_signing_key),
+        ] {
+            if let Some(json) = key {
+                match CrossSigningKey::from_signed_json(&json, user_id, usage, Some(&master_key)) {
+                    Ok(_) => {
+                        keys.insert(usage.to_owned(), json);
+                    }
+                    Err(e) => log::warn!("Dropping {} key of {}: {}", usage, user_id, e),
+                }
+            }
+        }
+        keys.insert(MASTER.to_owned(), master_json);
+        self.store.save_cross_signing_keys(user_id, &keys)
+    }
+
+    /// How much we trust a device we know the keys of, from our own
+    /// verifications and the cross-signing keys of its user.
+    pub fn device_trust(&self, user_id: &str, device_id: &str) -> Result<DeviceTrust, Error> {
+        match self.store.load_trust(user_id, device_id)? {
+            TrustState::Verified => return Ok(DeviceTrust::Verified),
+            TrustState::Blacklisted => return Ok(DeviceTrust::Unverified),
+            TrustState::Unverified => {}
+        }
+        let device = match self
+            .store
+            .load_signed_device_keys(user_id)?
+            .remove(device_id)
+        {
+            Some(device) => device,
+            None => return Ok(DeviceTrust::Unverified),
+        };
+        let keys = self.store.load_cross_signing_keys(user_id)?;
+        let (master_key, self_signing_key) = match (keys.get(MASTER), keys.get(SELF_SIGNING)) {
+            (Some(master_key), Some(self_signing_key)) => (master_key, self_signing_key),
+            _ => return Ok(DeviceTrust::Unverified),
+        };
+        let self_signing_ed25519 = self_signing_key.ed25519_key().unwrap_or_default();
+        if verify_signed_json(
+            &device,
+            user_id,
+            &format!("ed25519:{}", self_signing_ed25519),
+            self_signing_ed25519,
+        )
+        .is_err()
+        {
+            return Ok(DeviceTrust::Unverified);
+        }
+
+        if self.is_master_key_verified(user_id)? {
+            return Ok(DeviceTrust::CrossSigned);
+        }
+        let master_ed25519 = master_key.ed25519_key();
+        if self.store.load_pinned_master_key(user_id)?.as_deref() == master_ed25519 {
+            return Ok(DeviceTrust::Pinned);
+        }
+        Ok(DeviceTrust::Unverified)
+    }
+
+    /// Records our own decision about a device: blacklisted devices never get
+    /// room keys, verified ones are trusted like after an interactive
+    /// verification.
+    pub fn set_device_trust(
+        &mut self,
+        user_id: &str,
+        device_id: &str,
+        trust: TrustState,
+    ) -> Result<(), Error> {
+        self.store.save_trust(user_id, device_id, trust)
+    }
+
+    /// Whether the device was verified by us or through cross-signing, as
+    /// the sharing and forwarding policies require.
+    fn is_device_trusted(&self, user_id: &str, device_id: &str) -> Result<bool, Error> {
+        Ok(matches!(
+            self.device_trust(user_id, device_id)?,
+            DeviceTrust::Verified | DeviceTrust::CrossSigned
+        ))
+    }
+
+    /// Whether we trust the stored master key of `user_id`: we verified it
+    /// with a QR code, or, for our own, we hold its private key or a device
+    /// we verified signed it; for anybody else's, our verified user-signing
+    /// key signed it.
+    fn is_master_key_verified(&self, user_id: &str) -> Result<bool, Error> {
+        let master_json = match self
+            .store
+            .load_signed_cross_signing_keys(user_id)?
+            .remove(MASTER)
+        {
+            Some(master_json) => master_json,
+            None => return Ok(false),
+        };
+        let master_key: CrossSigningKey = serde_json::from_value(master_json.clone())?;
+        let master_ed25519 = master_key.ed25519_key();
+        if master_ed25519.is_some()
+            && self.store.load_verified_master_key(user_id)?.as_deref() == master_ed25519
+        {
+            return Ok(true);
+        }
+        if user_id == self.user_id {
+            if let Some(identity) = &self.cross_signing {
+                return Ok(identity.master_key().ed25519_key() == master_ed25519);
+            }
+            let mut devices = self.store.load_device_keys(user_id)?;
+            devices.insert(
+                self.device_id.clone(),
+                DeviceKey::new(
+                    self.device_id.clone(),
+                    self.user_id.clone(),
+                    self.curve25519_key(),
+                    self.ed25519_key(),
+                ),
+            );
+            for (device_id, device) in devices {
+                if device_id != self.device_id
+                    && self.store.load_trust(user_id, &device_id)? != TrustState::Verified
+                {
+                    continue;
+                }
+                let signed = verify_signed_json(
+                    &master_json,
+                    user_id,
+                    &format!("ed25519:{}", device_id),
+                    device.ed25519_key().unwrap_or_default(),
+                );
+                if signed.is_ok() {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+
+        let user_signing_key = match &self.cross_signing {
+            Some(identity) => identity.user_signing_key()?,
+            None => {
+                match self
+                    .store
+                    .load_cross_signing_keys(&self.user_id)?
+                    .remove(USER_SIGNING)
+                {
+                    Some(user_signing_key) if self.is_master_key_verified(&self.user_id)? => {
+                        user_signing_key
+                    }
+                    _ => return Ok(false),
+                }
+            }
+        };
+        let user_signing_ed25519 = user_signing_key.ed25519_key().unwrap_or_default();
+        Ok(verify_signed_json(
+            &master_json,
+            &self.user_id,
+            &format!("ed25519:{}", user_signing_ed25519),
+            user_signing_ed25519,
+        )
+        .is_ok())
+    }
+
+    /// Trusts `master_key` as the master key of `user_id` after a
+    /// verification proved it. It has to be the one we know from
+    /// `/keys/query`. Another user's key is also signed with our user-signing
+    /// key, if we hold it, so that our other devices trust it as well.
+    async fn trust_master_key(&mut self, user_id: &str, master_key: &str) -> Result<(), Error> {
+        let known_master_key = match self.store.load_cross_signing_keys(user_id)?.remove(MASTER) {
+            Some(known) if known.ed25519_key() == Some(master_key) => known,
+            _ => {
+                return Err(Error::VerificationError(format!(
+                    "{} is not the master key we know for {}",
+                    master_key, user_id
+                )))
+            }
+        };
+        self.store.save_verified_master_key(user_id, master_key)?;
+
+        let identity = match &self.cross_signing {
+            Some(identity) if user_id != self.user_id => identity,
+            _ => return Ok(()),
+        };
+        let signed_master_key = identity.sign_user(known_master_key)?;
         let response = self
             .backend_api
-            .send_keys(device_key, one_time_keys)
+            .upload_signatures(HashMap::from([(
+                user_id.to_owned(),
+                HashMap::from([(
+                    master_key.to_owned(),
+                    serde_json::to_value(signed_master_key)?,
+                )]),
+            )]))
             .await?;
-        Ok(response.one_time_key_counts.signed_curve25519.unwrap_or(0))
+        if !response.failures.is_empty() {
+            return Err(Error::ApiError(format!(
+                "signature upload failed: {:?}",
+                response.failures
+            )));
+        }
+        Ok(())
     }
 
-    pub async fn create_megolm_session(
+    /// The master keys a QR code is built from and checked against: ours,
+    /// whether we verified it, and the one of `other_user_id`.
+    fn qr_master_keys(
         &self,
+        other_user_id: &str,
+    ) -> Result<(Option<String>, bool, Option<String>), Error> {
+        let own_keys = self.store.load_cross_signing_keys(&self.user_id)?;
+        let (own_master_key, own_master_key_trusted) = match &self.cross_signing {
+            Some(identity) => (identity.master_key().ed25519_key().map(str::to_owned), true),
+            None => match own_keys.get(MASTER) {
+                Some(master_key) => (
+                    master_key.ed25519_key().map(str::to_owned),
+                    self.is_master_key_verified(&self.user_id)?,
+                ),
+                None => (None, false),
+            },
+        };
+        let other_master_key = self
+            .store
+            .load_cross_signing_keys(other_user_id)?
+            .remove(MASTER)
+            .and_then(|master_key| master_key.ed25519_key().map(str::to_owned));
+        Ok((own_master_key, own_master_key_trusted, other_master_key))
+    }
+
+    /// Starts a new outbound session for `room_id` that is only shared with
+    /// one device of `user_id`, replacing the current session of the room.
+    pub async fn create_megolm_session(
+        &mut self,
         room_id: String,
         user_id: String,
         recipient_device_id: String,
-    ) -> Result<MegolmSession, Error> {
-        let queried_keys = self.backend_api.query_keys(user_id.clone()).await?;
-        let recipient_device: &DeviceKey =
-            &queried_keys.device_keys[&user_id][&recipient_device_id];
+    ) -> Result<(), Error> {
+        let mut queried_keys = self.query_device_keys(&user_id).await?;
+        let recipient_device = queried_keys.remove(&recipient_device_id).ok_or_else(|| {
+            Error::UnknownDevice(format!("{} ({})", user_id, recipient_device_id))
+        })?;
 
-        let claimed_otks = self
-            .backend_api
-            .claim_otk(user_id.clone(), recipient_device_id.clone())
+        // A device the strategy excludes gets a notice, not an Olm session.
+        let (devices, withheld) = self.apply_sharing_strategy(vec![recipient_device])?;
+        for device in &devices {
+            let olm_session = self.olm_session_for(device).await?;
+            self.cache_olm_session(device, olm_session)?;
+        }
+        let megolm_session = self
+            .new_megolm_session(room_id.clone(), devices, withheld)
             .await?;
-        let user_otk = claimed_otks.one_time_keys[&user_id][&recipient_device_id]
-            .values()
-            .last()
-            .unwrap()
-            .curve25519_key
-            .clone();
-
-        let outbound_group_session = self
-            .create_olm_exchange(recipient_device, user_otk, room_id.clone())
+        self.megolm_sessions.insert(room_id, megolm_session);
+        Ok(())
+    }
+
+    /// Starts a new outbound session for `room_id`, replacing the current one,
+    /// and sends its key to every device of every joined member, except this
+    /// one.
+    pub async fn share_room_key(&mut self, room_id: String) -> Result<(), Error> {
+        let recipient_devices = self.room_devices(&room_id).await?;
+        let (devices, withheld) = self.apply_sharing_strategy(recipient_devices)?;
+        let megolm_session = self
+            .new_megolm_session(room_id.clone(), devices, withheld)
             .await?;
-        Ok(MegolmSession::new(room_id, outbound_group_session))
+        self.megolm_sessions.insert(room_id, megolm_session);
+        Ok(())
+    }
+
+    /// The outbound session this device currently uses in `room_id`.
+    pub fn outbound_session(&self, room_id: &str) -> Option<&MegolmSession> {
+        self.megolm_sessions.get(room_id)
+    }
+
+    /// Reads the rotation settings from the `m.room.encryption` state event of
+    /// `room_id`, with the spec defaults for the ones it leaves out.
+    pub async fn rotation_policy(&self, room_id: &str) -> Result<RotationPolicy, Error> {
+        let encryption = self.backend_api.room_encryption(room_id.to_owned()).await?;
+        Ok(RotationPolicy::new(
+            encryption.rotation_period_ms,
+            encryption.rotation_period_msgs,
+        ))
+    }
+
+    /// Sends the key of the outbound session of `room_id` to the devices in
+    /// the room that do not hold it yet, such as newly joined members or new
+    /// devices of existing ones. Returns the devices that received the key.
+    pub async fn share_with_new_devices(&mut self, room_id: &str) -> Result<Vec<DeviceKey>, Error> {
+        let mut megolm_session = match self.megolm_sessions.remove(room_id) {
+            Some(megolm_session) => megolm_session,
+            None => return Ok(Vec::new()),
+        };
+        let shared = match self
+            .room_devices(room_id)
+            .await
+            .and_then(|devices| self.apply_sharing_strategy(devices))
+        {
+            Ok((devices, withheld)) => {
+                self.share_with_devices(&mut megolm_session, devices, withheld)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        self.megolm_sessions
+            .insert(room_id.to_owned(), megolm_session);
+        shared
     }
 
+    /// Encrypts `content` for `room_id` and sends it.
+    ///
+    /// The room's outbound session is created on first use, and replaced by a
+    /// fresh one when it reached the room's rotation limits or when a device
+    /// that holds its key is no longer in the room or no longer allowed by the
+    /// sharing strategy; otherwise devices that joined since get the current
+    /// key.
     pub async fn send_encrypted_message(
-        &self,
-        megolm_session: &mut MegolmSession,
+        &mut self,
+        room_id: &str,
         content: &str,
     ) -> Result<bool, Error> {
-        let message =
-            megolm_session.create_message(self.curve25519_key(), self.device_id.clone(), content);
+        self.send_encrypted_event(
+            room_id,
+            "m.room.message",
+            serde_json::json!({"msgtype": "m.text", "body": content}),
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Encrypts a room event of any type with the room's outbound session,
+    /// like `send_encrypted_message`, and returns its event ID.
+    pub async fn send_encrypted_event(
+        &mut self,
+        room_id: &str,
+        event_type: &str,
+        content: serde_json::Value,
+    ) -> Result<String, Error> {
+        let devices = self.room_devices(room_id).await?;
+        let (devices, withheld) = self.apply_sharing_strategy(devices)?;
+        let mut megolm_session = match self.megolm_sessions.remove(room_id) {
+            Some(mut megolm_session)
+                if !megolm_session.needs_rotation()
+                    && !megolm_session.has_removed_recipients(&devices) =>
+            {
+                if let Err(e) = self
+                    .share_with_devices(&mut megolm_session, devices, withheld)
+                    .await
+                {
+                    self.megolm_sessions
+                        .insert(room_id.to_owned(), megolm_session);
+                    return Err(e);
+                }
+                megolm_session
+            }
+            Some(megolm_session) => {
+                log::info!(
+                    "Rotating Megolm session {} in {}",
+                    megolm_session.ratchet.session_id(),
+                    room_id
+                );
+                self.new_megolm_session(room_id.to_owned(), devices, withheld)
+                    .await?
+            }
+            None => {
+                self.new_megolm_session(room_id.to_owned(), devices, withheld)
+                    .await?
+            }
+        };
+
+        let message = megolm_session.create_event(
+            self.curve25519_key(),
+            self.device_id.clone(),
+            event_type,
+            content,
+        );
+        self.store
+            .save_outbound_group_session(room_id, &megolm_session.ratchet)?;
+        self.megolm_sessions
+            .insert(room_id.to_owned(), megolm_session);
 
         self.backend_api
-            .send_message(megolm_session.room_id.clone(), message)
+            .send_message(room_id.to_owned(), message)
+            .await
+    }
+
+    /// Creates a new Megolm session for `room_id` following the room's
+    /// rotation policy, shares it with `devices` and sends the `withheld`
+    /// ones a notice.
+    async fn new_megolm_session(
+        &mut self,
+        room_id: String,
+        devices: Vec<DeviceKey>,
+        withheld: WithheldDevices,
+    ) -> Result<MegolmSession, Error> {
+        let rotation = self.rotation_policy(&room_id).await?;
+        let outbound_group_session = self.new_outbound_group_session(&room_id)?;
+        let mut megolm_session =
+            MegolmSession::new(room_id, outbound_group_session).with_rotation(rotation);
+        self.store.save_outbound_session_info(
+            &megolm_session.room_id,
+            &megolm_session.ratchet.session_id(),
+            megolm_session.created_at,
+            &megolm_session.rotation,
+        )?;
+        self.share_group_session(&mut megolm_session, devices, withheld)
             .await?;
+        Ok(megolm_session)
+    }
 
-        Ok(true)
+    /// Shares `megolm_session` with those of `devices` that do not hold it
+    /// yet, and tells the `withheld` ones why they won't get it, once per
+    /// reason.
+    async fn share_with_devices(
+        &mut self,
+        megolm_session: &mut MegolmSession,
+        devices: Vec<DeviceKey>,
+        withheld: WithheldDevices,
+    ) -> Result<Vec<DeviceKey>, Error> {
+        let new_devices: Vec<DeviceKey> = devices
+            .into_iter()
+            .filter(|device| !megolm_session.is_shared_with(&device.user_id, &device.device_id))
+            .collect();
+        let withheld: WithheldDevices = withheld
+            .into_iter()
+            .filter(|(device, code)| {
+                !megolm_session.is_withheld_from(&device.user_id, &device.device_id, *code)
+            })
+            .collect();
+
+        if new_devices.is_empty() && withheld.is_empty() {
+            return Ok(new_devices);
+        }
+        self.share_group_session(megolm_session, new_devices, withheld)
+            .await
     }
 
-    async fn create_olm_exchange(
+    /// Splits `devices` into the ones that may receive room keys under the
+    /// current sharing strategy and the ones that may not, with the reason.
+    pub fn apply_sharing_strategy(
         &self,
-        recipient_device: &DeviceKey,
-        user_otk: String,
-        room_id: String,
-    ) -> Result<megolm::GroupSession, Error> {
+        devices: Vec<DeviceKey>,
+    ) -> Result<(Vec<DeviceKey>, WithheldDevices), Error> {
+        let mut allowed = Vec::new();
+        let mut withheld = Vec::new();
+        let mut unverified = Vec::new();
+        for device in devices {
+            if self.store.load_trust(&device.user_id, &device.device_id)? == TrustState::Blacklisted
+            {
+                withheld.push((device, WithheldCode::Blacklisted));
+            } else if self.is_device_trusted(&device.user_id, &device.device_id)? {
+                allowed.push(device);
+            } else {
+                match self.sharing_strategy {
+                    SharingStrategy::AllDevices => allowed.push(device),
+                    SharingStrategy::VerifiedOnly => {
+                        withheld.push((device, WithheldCode::Unverified))
+                    }
+                    SharingStrategy::ErrorOnUnverified => {
+                        unverified.push(format!("{} ({})", device.user_id, device.device_id))
+                    }
+                }
+            }
+        }
+
+        if !unverified.is_empty() {
+            return Err(Error::UnverifiedDevices(unverified.join(", ")));
+        }
+        Ok((allowed, withheld))
+    }
+
+    /// Creates and stores a new outbound session, together with the inbound
+    /// copy we need to decrypt our own messages.
+    fn new_outbound_group_session(&mut self, room_id: &str) -> Result<megolm::GroupSession, Error> {
         let outbound_group_session = megolm::GroupSession::new(megolm::SessionConfig::version_1());
+        self.store
+            .save_outbound_group_session(room_id, &outbound_group_session)?;
+        self.add_inbound_group_session(
+            room_id,
+            &self.curve25519_key(),
+            megolm::InboundGroupSession::new(
+                &outbound_group_session.session_key(),
+                megolm::SessionConfig::version_1(),
+            ),
+            &self.ed25519_key(),
+            &[],
+        )?;
+        Ok(outbound_group_session)
+    }
 
-        let recipient_curve25519 = vodozemac::Curve25519PublicKey::from_base64(
-            recipient_device.keys[format!("curve25519:{}", recipient_device.device_id)]
-                .as_str()
-                .unwrap(),
-        )
-        .unwrap();
+    /// Every device of every joined member of `room_id`, except this one.
+    /// Members and device keys we know are reused, until sync reports that
+    /// they changed.
+    async fn room_devices(&mut self, room_id: &str) -> Result<Vec<DeviceKey>, Error> {
+        let members = match self.room_members.get(room_id) {
+            Some(members) => members.clone(),
+            None => {
+                let members: Vec<String> = self
+                    .backend_api
+                    .joined_members(room_id.to_owned())
+                    .await?
+                    .joined
+                    .into_keys()
+                    .collect();
+                self.room_members
+                    .insert(room_id.to_owned(), members.clone());
+                members
+            }
+        };
+
+        let tracked_users: HashSet<String> = self.store.tracked_users()?.into_iter().collect();
+        let outdated: Vec<String> = members
+            .iter()
+            .filter(|user_id| {
+                !tracked_users.contains(*user_id) || self.outdated_device_lists.contains(*user_id)
+            })
+            .cloned()
+            .collect();
+        let mut devices = if outdated.is_empty() {
+            HashMap::new()
+        } else {
+            self.query_devices(outdated).await?
+        };
+        for user_id in &members {
+            if devices.contains_key(user_id) {
+                self.outdated_device_lists.remove(user_id);
+            } else {
+                devices.insert(user_id.clone(), self.store.load_device_keys(user_id)?);
+            }
+        }
+
+        Ok(devices
+            .into_values()
+            .flat_map(|devices| devices.into_values())
+            .filter(|device| {
+                !(device.user_id == self.user_id && device.device_id == self.device_id)
+            })
+            .collect())
+    }
+
+    /// Sends the key of `megolm_session` to all `devices` in a single
+    /// `sendToDevice` request. One-time keys are claimed in one batch for the
+    /// devices we have no Olm session with yet; devices without a usable
+    /// one-time key are skipped. The devices that received the key are
+    /// recorded in the session and the store, and returned. Each of the
+    /// `withheld` devices gets an `m.room_key.withheld` notice instead, and so
+    /// do devices we can't open an Olm session with (`m.no_olm`).
+    async fn share_group_session(
+        &mut self,
+        megolm_session: &mut MegolmSession,
+        devices: Vec<DeviceKey>,
+        mut withheld: WithheldDevices,
+    ) -> Result<Vec<DeviceKey>, Error> {
+        let mut sessions = Vec::new();
+        let mut missing_sessions = Vec::new();
+        for device in devices {
+            match self.take_olm_session(&device)? {
+                Some(session) => sessions.push((device, session)),
+                None => missing_sessions.push(device),
+            }
+        }
+
+        let mut claimed_otks = self.claim_one_time_keys(&missing_sessions).await?;
+        for device in missing_sessions {
+            let claimed_otk = claimed_otks
+                .remove(&store_key(&[&device.user_id, &device.device_id]))
+                .unwrap_or_else(|| {
+                    Err(Error::MissingOneTimeKey(format!(
+                        "{} ({})",
+                        device.user_id, device.device_id
+                    )))
+                });
+            match claimed_otk.and_then(|otk| self.create_outbound_olm_session(&device, otk)) {
+                Ok(session) => sessions.push((device, session)),
+                Err(e) => {
+                    log::warn!(
+                        "Not sharing room key with {} ({}): {}",
+                        device.user_id,
+                        device.device_id,
+                        e
+                    );
+                    if !megolm_session.is_withheld_from(
+                        &device.user_id,
+                        &device.device_id,
+                        WithheldCode::NoOlm,
+                    ) {
+                        withheld.push((device, WithheldCode::NoOlm));
+                    }
+                }
+            }
+        }
+
+        let mut messages: HashMap<String, HashMap<String, OlmExchange>> = HashMap::new();
+        let mut recipients = Vec::new();
+        for (device, mut session) in sessions {
+            let olm_exchange_payload = OlmExchange::new(
+                self,
+                &device,
+                &megolm_session.ratchet,
+                &mut session,
+                megolm_session.room_id.clone(),
+            );
+            self.cache_olm_session(&device, session)?;
+            messages
+                .entry(device.user_id.clone())
+                .or_default()
+                .insert(device.device_id.clone(), olm_exchange_payload);
+            recipients.push(device);
+        }
+
+        if !messages.is_empty() {
+            self.backend_api.send_olm_batch(messages).await?;
+        }
+        self.send_withheld_notices(megolm_session, withheld).await?;
+
+        for device in &recipients {
+            megolm_session.mark_shared_with(device);
+        }
+        self.store.save_shared_with(
+            &megolm_session.room_id,
+            &megolm_session.ratchet.session_id(),
+            &megolm_session.shared_with,
+        )?;
+        Ok(recipients)
+    }
+
+    /// Sends one `m.room_key.withheld` notice per device in a single
+    /// `sendToDevice` request, and remembers them in the session.
+    async fn send_withheld_notices(
+        &mut self,
+        megolm_session: &mut MegolmSession,
+        withheld: WithheldDevices,
+    ) -> Result<(), Error> {
+        if withheld.is_empty() {
+            return Ok(());
+        }
+
+        let mut messages: HashMap<String, HashMap<String, RoomKeyWithheld>> = HashMap::new();
+        for (device, code) in &withheld {
+            log::info!(
+                "Withholding room key {} from {} ({}): {}",
+                megolm_session.ratchet.session_id(),
+                device.user_id,
+                device.device_id,
+                code
+            );
+            messages.entry(device.user_id.clone()).or_default().insert(
+                device.device_id.clone(),
+                megolm_session.withheld_notice(self.curve25519_key(), *code),
+            );
+        }
+        self.backend_api
+            .send_to_device("m.room_key.withheld", messages)
+            .await?;
+
+        for (device, code) in withheld {
+            megolm_session.mark_withheld_from(&device, code);
+        }
+        Ok(())
+    }
+
+    /// Takes the Olm session we use to talk to `recipient_device` out of the
+    /// cache, falling back to the store. A one-time key is only claimed when
+    /// we never established a session with that device.
+    async fn olm_session_for(
+        &mut self,
+        recipient_device: &DeviceKey,
+    ) -> Result<olm::Session, Error> {
+        if let Some(session) = self.take_olm_session(recipient_device)? {
+            return Ok(session);
+        }
+
+        let recipient_otk = self
+            .claim_one_time_keys(std::slice::from_ref(recipient_device))
+            .await?
+            .remove(&store_key(&[
+                &recipient_device.user_id,
+                &recipient_device.device_id,
+            ]))
+            .unwrap_or_else(|| {
+                Err(Error::MissingOneTimeKey(format!(
+                    "{} ({})",
+                    recipient_device.user_id, recipient_device.device_id
+                )))
+            })?;
+        self.create_outbound_olm_session(recipient_device, recipient_otk)
+    }
+
+    /// The session to encrypt for `recipient_device` with: the cached one,
+    /// or else the stored one that was used last.
+    fn take_olm_session(
+        &mut self,
+        recipient_device: &DeviceKey,
+    ) -> Result<Option<olm::Session>, Error> {
+        let recipient_curve25519 = recipient_device.curve25519_key().ok_or_else(|| {
+            Error::UnknownDevice(format!(
+                "{} ({}) has no curve25519 key",
+                recipient_device.user_id, recipient_device.device_id
+            ))
+        })?;
+        let cache_key = store_key(&[&recipient_device.user_id, recipient_curve25519]);
 
-        let recipient_otk = vodozemac::Curve25519PublicKey::from_base64(&user_otk).unwrap();
+        if let Some(session) = self.olm_sessions.remove(&cache_key) {
+            return Ok(Some(session));
+        }
+        Ok(self
+            .store
+            .load_olm_sessions(recipient_curve25519)?
+            .into_iter()
+            .next()
+            .map(|(session, _)| session))
+    }
 
-        let mut outbound_olm_session = self.olm_account.create_outbound_session(
+    fn create_outbound_olm_session(
+        &self,
+        recipient_device: &DeviceKey,
+        recipient_otk: vodozemac::Curve25519PublicKey,
+    ) -> Result<olm::Session, Error> {
+        let recipient_curve25519 = vodozemac::Curve25519PublicKey::from_base64(
+            recipient_device.curve25519_key().unwrap_or_default(),
+        )
+        .map_err(|e| Error::UnknownDevice(e.to_string()))?;
+        Ok(self.olm_account.create_outbound_session(
             olm::SessionConfig::version_1(),
             recipient_curve25519,
             recipient_otk,
-        );
+        ))
+    }
 
-        let olm_exchange_payload = OlmExchange::new(
-            self,
-            recipient_device,
-            &outbound_group_session,
-            &mut outbound_olm_session,
-            room_id,
+    fn cache_olm_session(
+        &mut self,
+        recipient_device: &DeviceKey,
+        session: olm::Session,
+    ) -> Result<(), Error> {
+        let recipient_curve25519 = recipient_device.curve25519_key().unwrap_or_default();
+        self.store
+            .save_olm_session(recipient_curve25519, &session, now_ms())?;
+        self.olm_sessions.insert(
+            store_key(&[&recipient_device.user_id, recipient_curve25519]),
+            session,
         );
+        Ok(())
+    }
 
-        self.backend_api
-            .send_olm(
-                recipient_device.user_id.clone(),
-                recipient_device.device_id.clone(),
-                olm_exchange_payload,
-            )
-            .await?;
-        Ok(outbound_group_session)
+    /// Claims one signed one-time key for each of `devices` in a single
+    /// request. The result is keyed by user and device ID, every key has its
+    /// signature checked against the device's ed25519 key.
+    async fn claim_one_time_keys(
+        &self,
+        devices: &[DeviceKey],
+    ) -> Result<HashMap<String, Result<vodozemac::Curve25519PublicKey, Error>>, Error> {
+        if devices.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut request: HashMap<String, HashMap<String, String>> = HashMap::new();
+        for device in devices {
+            request
+                .entry(device.user_id.clone())
+                .or_default()
+                .insert(device.device_id.clone(), String::from("signed_curve25519"));
+        }
+        let claimed_otks = self.backend_api.claim_otks(request).await?;
+
+        let mut result = HashMap::new();
+        for device in devices {
+            let claimed_otk = claimed_otks
+                .one_time_keys
+                .get(&device.user_id)
+                .and_then(|devices| devices.get(&device.device_id))
+                .and_then(|keys| keys.iter().last())
+                .ok_or_else(|| {
+                    Error::MissingOneTimeKey(format!("{} ({})", device.user_id, device.device_id))
+                })
+                .and_then(|(key_id, otk)| check_claimed_key(device, key_id, otk));
+            result.insert(
+                store_key(&[&device.user_id, &device.device_id]),
+                claimed_otk,
+            );
+        }
+        Ok(result)
     }
 }
+
+fn check_claimed_key(
+    device: &DeviceKey,
+    key_id: &str,
+    json: &serde_json::Value,
+) -> Result<vodozemac::Curve25519PublicKey, Error> {
+    if !key_id.starts_with("signed_curve25519:") {
+        return Err(Error::InvalidOneTimeKey(format!(
+            "unexpected key algorithm {}",
+            key_id
+        )));
+    }
+    let otk = OneTimeKey::from_signed_json(
+        json,
+        &device.user_id,
+        &device.device_id,
+        device.ed25519_key().unwrap_or_default(),
+    )?;
+
+    vodozemac::Curve25519PublicKey::from_base64(&otk.curve25519_key)
+        .map_err(|e| Error::InvalidOneTimeKey(e.to_string()))
+}
@@ -2,6 +2,25 @@
 pub enum Error {
     ApiError(String),
     HTTPInternalError(String),
+    PickleError(String),
+    IOError(String),
+    StoreError(String),
+    DeviceMismatch(String),
+    MissingOneTimeKey(String),
+    UnknownOlmSession(String),
+    InvalidMac(String),
+    DecryptionError(String),
+    UnknownMegolmSession(String),
+    ReplayedMessage(String),
+    InvalidSignature(String),
+    UnknownDevice(String),
+    InvalidOneTimeKey(String),
+    UnverifiedDevices(String),
+    KeyWithheld(String),
+    UnrequestedKey(String),
+    VerificationError(String),
+    AuthRequired(String),
+    CrossSigningKeysExist(String),
 }
 
 impl std::error::Error for Error {}
@@ -11,6 +30,33 @@ impl std::fmt::Display for Error {
         match self {
             Error::ApiError(resp) => write!(f, "Error response: {:?}", resp),
             Error::HTTPInternalError(resp) => write!(f, "HTTP Request failed: {:?}", resp),
+            Error::PickleError(resp) => write!(f, "Unable to unpickle: {:?}", resp),
+            Error::IOError(resp) => write!(f, "I/O error: {:?}", resp),
+            Error::StoreError(resp) => write!(f, "Crypto store error: {:?}", resp),
+            Error::DeviceMismatch(resp) => write!(f, "Device does not match: {:?}", resp),
+            Error::MissingOneTimeKey(resp) => write!(f, "No one-time key available: {:?}", resp),
+            Error::UnknownOlmSession(resp) => write!(f, "No Olm session found: {:?}", resp),
+            Error::InvalidMac(resp) => write!(f, "Message has an invalid MAC: {:?}", resp),
+            Error::DecryptionError(resp) => write!(f, "Unable to decrypt: {:?}", resp),
+            Error::UnknownMegolmSession(resp) => write!(f, "No Megolm session found: {:?}", resp),
+            Error::ReplayedMessage(resp) => write!(f, "Message index was already used: {:?}", resp),
+            Error::InvalidSignature(resp) => write!(f, "Signature check failed: {:?}", resp),
+            Error::UnknownDevice(resp) => write!(f, "Unknown or untrusted device: {:?}", resp),
+            Error::InvalidOneTimeKey(resp) => {
+                write!(f, "Claimed one-time key is invalid: {:?}", resp)
+            }
+            Error::UnverifiedDevices(resp) => {
+                write!(f, "Room has unverified devices: {:?}", resp)
+            }
+            Error::KeyWithheld(resp) => write!(f, "Room key was withheld: {:?}", resp),
+            Error::UnrequestedKey(resp) => write!(f, "Forwarded key was not requested: {:?}", resp),
+            Error::VerificationError(resp) => write!(f, "Verification failed: {:?}", resp),
+            Error::AuthRequired(resp) => {
+                write!(f, "Unsupported interactive authentication: {:?}", resp)
+            }
+            Error::CrossSigningKeysExist(resp) => {
+                write!(f, "Cross-signing keys already exist: {:?}", resp)
+            }
         }
     }
 }
@@ -23,6 +69,24 @@ impl From<reqwest::Error> for Error {
 
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
-        Error::HTTPInternalError(String::from(format!("Invalid response: {}", e.to_string())))
+        Error::HTTPInternalError(format!("Invalid response: {}", e))
+    }
+}
+
+impl From<vodozemac::PickleError> for Error {
+    fn from(e: vodozemac::PickleError) -> Self {
+        Error::PickleError(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IOError(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::StoreError(e.to_string())
     }
 }
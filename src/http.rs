@@ -1,11 +1,13 @@
 use crate::crypto::{DeviceKey, MegolmMessage, OneTimeKey};
 use crate::error::Error;
 use crate::payload::{
-    KeyPublishPayload, LoginIdentifierSP, LoginPayload, OLMExchangePayload,
-    RequestDeviceKeyPayload, RequestOTKPayload,
+    CrossSigningUploadPayload, KeyPublishPayload, LoginIdentifierSP, LoginPayload,
+    OLMExchangePayload, RequestDeviceKeyPayload, RequestOTKPayload, ToDevicePayload,
 };
 use crate::response::{
-    ClaimOTKResponse, ErrorResponse, KeyUploadResponse, LoginResponse, RequestDeviceKeyResponse,
+    ClaimOTKResponse, ErrorResponse, JoinedMembersResponse, KeyUploadResponse, LoginResponse,
+    RequestDeviceKeyResponse, RoomEncryptionResponse, SignatureUploadResponse, SyncResponse,
+    UserInteractiveAuthResponse, WhoAmIResponse,
 };
 
 use serde::de::DeserializeOwned;
@@ -45,8 +47,8 @@ impl HTTPBackend {
                 .proxy(reqwest::Proxy::https("http://127.0.0.1:8080").unwrap())
                 .build()
                 .unwrap(),
-            homeserver_uri: homeserver_uri,
-            access_token: access_token,
+            homeserver_uri,
+            access_token,
         }
     }
 
@@ -55,6 +57,20 @@ impl HTTPBackend {
         route: Route,
         data: Option<S>,
     ) -> Result<D, Error> {
+        let response = self.send(route, data).await?;
+        if response.status().is_success() {
+            let rjson = response.json::<D>().await?;
+            return Ok(rjson);
+        }
+        let ejson = response.json::<ErrorResponse>().await?;
+        Err(Error::ApiError(ejson.error))
+    }
+
+    async fn send<S: Serialize>(
+        &self,
+        route: Route,
+        data: Option<S>,
+    ) -> Result<reqwest::Response, Error> {
         let method = route.method;
         let url = format!("{}{}", self.homeserver_uri, route.path);
         let mut request = self
@@ -73,38 +89,96 @@ impl HTTPBackend {
             request = request.json(&data);
         }
 
-        let response = request.send().await?;
-        if response.status().is_success() {
-            let rjson = response.json::<D>().await?;
-            return Ok(rjson);
-        }
-        let ejson = response.json::<ErrorResponse>().await?;
-        Err(Error::ApiError(ejson.error))
+        Ok(request.send().await?)
+    }
+
+    pub async fn whoami(&self) -> Result<WhoAmIResponse, Error> {
+        let response: WhoAmIResponse = self
+            .request(
+                Route::new("GET", "/_matrix/client/r0/account/whoami"),
+                None::<()>,
+            )
+            .await?;
+        Ok(response)
     }
 
     pub async fn send_keys(
         &self,
-        device_keys: DeviceKey,
+        device_keys: Option<DeviceKey>,
         one_time_keys: HashMap<String, OneTimeKey>,
+        fallback_keys: HashMap<String, OneTimeKey>,
     ) -> Result<KeyUploadResponse, Error> {
         let response: KeyUploadResponse = self
             .request(
                 Route::new("POST", "/_matrix/client/r0/keys/upload"),
                 Some(KeyPublishPayload {
-                    device_keys: device_keys,
-                    one_time_keys: one_time_keys,
+                    device_keys,
+                    one_time_keys,
+                    fallback_keys,
                 }),
             )
             .await?;
         Ok(response)
     }
 
+    /// Uploads our cross-signing keys. Returns the flows to authenticate
+    /// with when the server asks for user-interactive authentication.
+    pub async fn upload_cross_signing_keys(
+        &self,
+        payload: CrossSigningUploadPayload,
+    ) -> Result<Option<UserInteractiveAuthResponse>, Error> {
+        let response = self
+            .send(
+                Route::new("POST", "/_matrix/client/r0/keys/device_signing/upload"),
+                Some(payload),
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(None);
+        }
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            let body: serde_json::Value = response.json().await?;
+            if body.get("flows").is_some() {
+                return Ok(Some(serde_json::from_value(body)?));
+            }
+            return Err(Error::ApiError(
+                body["error"].as_str().unwrap_or_default().to_owned(),
+            ));
+        }
+        let ejson = response.json::<ErrorResponse>().await?;
+        Err(Error::ApiError(ejson.error))
+    }
+
+    /// Uploads signatures of keys, by user and device or key ID.
+    pub async fn upload_signatures(
+        &self,
+        signatures: HashMap<String, HashMap<String, serde_json::Value>>,
+    ) -> Result<SignatureUploadResponse, Error> {
+        let response: SignatureUploadResponse = self
+            .request(
+                Route::new("POST", "/_matrix/client/r0/keys/signatures/upload"),
+                Some(signatures),
+            )
+            .await?;
+        Ok(response)
+    }
+
     pub async fn query_keys(&self, user_id: String) -> Result<RequestDeviceKeyResponse, Error> {
+        self.query_keys_for(vec![user_id]).await
+    }
+
+    pub async fn query_keys_for(
+        &self,
+        user_ids: Vec<String>,
+    ) -> Result<RequestDeviceKeyResponse, Error> {
         let response: RequestDeviceKeyResponse = self
             .request(
                 Route::new("POST", "/_matrix/client/r0/keys/query"),
                 Some(RequestDeviceKeyPayload {
-                    device_keys: HashMap::from([(user_id, Vec::new())]),
+                    device_keys: user_ids
+                        .into_iter()
+                        .map(|user_id| (user_id, Vec::new()))
+                        .collect(),
                 }),
             )
             .await?;
@@ -115,16 +189,22 @@ impl HTTPBackend {
         &self,
         user_id: String,
         recipient_device_id: String,
+    ) -> Result<ClaimOTKResponse, Error> {
+        self.claim_otks(HashMap::from([(
+            user_id,
+            HashMap::from([(recipient_device_id, String::from("signed_curve25519"))]),
+        )]))
+        .await
+    }
+
+    pub async fn claim_otks(
+        &self,
+        one_time_keys: HashMap<String, HashMap<String, String>>,
     ) -> Result<ClaimOTKResponse, Error> {
         let response: ClaimOTKResponse = self
             .request(
                 Route::new("POST", "/_matrix/client/r0/keys/claim"),
-                Some(RequestOTKPayload {
-                    one_time_keys: HashMap::from([(
-                        user_id,
-                        HashMap::from([(recipient_device_id, String::from("signed_curve25519"))]),
-                    )]),
-                }),
+                Some(RequestOTKPayload { one_time_keys }),
             )
             .await?;
         Ok(response)
@@ -136,41 +216,114 @@ impl HTTPBackend {
         recipient_device_id: String,
         olm_exchange_payload: crate::crypto::OlmExchange,
     ) -> Result<(), Error> {
-        let response: HashMap<i8, i8> = self
+        self.send_olm_batch(HashMap::from([(
+            user_id,
+            HashMap::from([(recipient_device_id, olm_exchange_payload)]),
+        )]))
+        .await
+    }
+
+    pub async fn send_olm_batch(
+        &self,
+        messages: HashMap<String, HashMap<String, crate::crypto::OlmExchange>>,
+    ) -> Result<(), Error> {
+        let _response: HashMap<i8, i8> = self
             .request(
                 Route::new(
                     "PUT",
                     &format!(
                         "/_matrix/client/r0/sendToDevice/m.room.encrypted/{}",
-                        uuid::Uuid::new_v4().to_string()
+                        uuid::Uuid::new_v4()
                     ),
                 ),
-                Some(OLMExchangePayload {
-                    messages: HashMap::from([(
-                        user_id,
-                        HashMap::from([(recipient_device_id, olm_exchange_payload)]),
-                    )]),
-                }),
+                Some(OLMExchangePayload { messages }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Sends an unencrypted to-device event of type `event_type`.
+    pub async fn send_to_device<T: Serialize>(
+        &self,
+        event_type: &str,
+        messages: HashMap<String, HashMap<String, T>>,
+    ) -> Result<(), Error> {
+        let _response: HashMap<i8, i8> = self
+            .request(
+                Route::new(
+                    "PUT",
+                    &format!(
+                        "/_matrix/client/r0/sendToDevice/{}/{}",
+                        event_type,
+                        uuid::Uuid::new_v4()
+                    ),
+                ),
+                Some(ToDevicePayload { messages }),
             )
             .await?;
         Ok(())
     }
 
-    pub async fn send_message(&self, room_id: String, message: MegolmMessage) -> Result<(), Error> {
-        let response: HashMap<String, String> = self
+    pub async fn joined_members(&self, room_id: String) -> Result<JoinedMembersResponse, Error> {
+        let response: JoinedMembersResponse = self
+            .request(
+                Route::new(
+                    "GET",
+                    &format!("/_matrix/client/r0/rooms/{}/joined_members", room_id),
+                ),
+                None::<()>,
+            )
+            .await?;
+        Ok(response)
+    }
+
+    pub async fn room_encryption(&self, room_id: String) -> Result<RoomEncryptionResponse, Error> {
+        let response: RoomEncryptionResponse = self
+            .request(
+                Route::new(
+                    "GET",
+                    &format!(
+                        "/_matrix/client/r0/rooms/{}/state/m.room.encryption",
+                        room_id
+                    ),
+                ),
+                None::<()>,
+            )
+            .await?;
+        Ok(response)
+    }
+
+    /// Sends an encrypted room event and returns its event ID.
+    pub async fn send_message(
+        &self,
+        room_id: String,
+        message: MegolmMessage,
+    ) -> Result<String, Error> {
+        let mut response: HashMap<String, String> = self
             .request(
                 Route::new(
                     "PUT",
                     &format!(
                         "/_matrix/client/r0/rooms/{}/send/m.room.encrypted/{}",
                         room_id,
-                        uuid::Uuid::new_v4().to_string()
+                        uuid::Uuid::new_v4()
                     ),
                 ),
                 Some(message),
             )
             .await?;
-        Ok(())
+        response
+            .remove("event_id")
+            .ok_or_else(|| Error::ApiError(String::from("response has no event_id")))
+    }
+
+    pub async fn sync(&self, since: Option<String>) -> Result<SyncResponse, Error> {
+        let path = match since {
+            Some(since) => format!("/_matrix/client/r0/sync?timeout=30000&since={}", since),
+            None => String::from("/_matrix/client/r0/sync?timeout=0"),
+        };
+        let response: SyncResponse = self.request(Route::new("GET", &path), None::<()>).await?;
+        Ok(response)
     }
 
     pub async fn raw_login(
@@ -188,7 +341,7 @@ impl HTTPBackend {
                     r#type: String::from("m.id.user"),
                     user: username,
                 },
-                password: password,
+                password,
             })
             .send()
             .await?
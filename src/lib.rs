@@ -4,3 +4,4 @@ pub mod error;
 pub mod http;
 pub mod payload;
 pub mod response;
+pub mod store;
@@ -16,8 +16,12 @@ pub struct LoginIdentifierSP {
 
 #[derive(Debug, Serialize)]
 pub struct KeyPublishPayload {
-    pub device_keys: crate::crypto::DeviceKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_keys: Option<crate::crypto::DeviceKey>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub one_time_keys: HashMap<String, crate::crypto::OneTimeKey>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub fallback_keys: HashMap<String, crate::crypto::OneTimeKey>,
 }
 
 #[derive(Debug, Serialize)]
@@ -30,6 +34,23 @@ pub struct RequestOTKPayload {
     pub one_time_keys: HashMap<String, HashMap<String, String>>,
 }
 
+/// Body of `/keys/device_signing/upload`. `auth` answers the user-interactive
+/// authentication the server asks for.
+#[derive(Debug, Serialize)]
+pub struct CrossSigningUploadPayload {
+    pub master_key: crate::crypto::CrossSigningKey,
+    pub self_signing_key: crate::crypto::CrossSigningKey,
+    pub user_signing_key: crate::crypto::CrossSigningKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<serde_json::Value>,
+}
+
+/// Body of a `sendToDevice` request, by user and device ID.
+#[derive(Debug, Serialize)]
+pub struct ToDevicePayload<T> {
+    pub messages: HashMap<String, HashMap<String, T>>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct OLMExchangePayload {
     pub messages: HashMap<String, HashMap<String, crate::crypto::OlmExchange>>,
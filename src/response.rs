@@ -14,9 +14,19 @@ pub struct LoginResponse {
     pub access_token: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct WhoAmIResponse {
+    pub user_id: String,
+    pub device_id: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct KeyUploadResponse {
     pub one_time_key_counts: OneTimeKeyCounts,
+    /// Not sent by the server, filled in by `Device::publish_keypair`: whether
+    /// this device has a fallback key published and ready to be claimed.
+    #[serde(skip)]
+    pub unused_fallback_key_published: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,12 +35,131 @@ pub struct OneTimeKeyCounts {
     pub curve25519: Option<i16>,
 }
 
+/// The `401` body of an endpoint that needs user-interactive authentication.
+#[derive(Debug, Deserialize)]
+pub struct UserInteractiveAuthResponse {
+    pub session: Option<String>,
+    pub flows: Vec<AuthFlow>,
+    #[serde(default)]
+    pub completed: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthFlow {
+    pub stages: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignatureUploadResponse {
+    #[serde(default)]
+    pub failures: HashMap<String, HashMap<String, serde_json::Value>>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RequestDeviceKeyResponse {
-    pub device_keys: HashMap<String, HashMap<String, crate::crypto::DeviceKey>>,
+    /// Keys are kept as received, their signatures cover the exact JSON.
+    pub device_keys: HashMap<String, HashMap<String, serde_json::Value>>,
+    #[serde(default)]
+    pub master_keys: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub self_signing_keys: HashMap<String, serde_json::Value>,
+    /// Only returned for our own user.
+    #[serde(default)]
+    pub user_signing_keys: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JoinedMembersResponse {
+    pub joined: HashMap<String, serde_json::Value>,
+}
+
+/// Content of a room's `m.room.encryption` state event.
+#[derive(Debug, Deserialize)]
+pub struct RoomEncryptionResponse {
+    pub algorithm: String,
+    pub rotation_period_ms: Option<u64>,
+    pub rotation_period_msgs: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ClaimOTKResponse {
-    pub one_time_keys: HashMap<String, HashMap<String, HashMap<String, crate::crypto::OneTimeKey>>>,
+    /// Keys are kept as received, their signatures cover the exact JSON.
+    pub one_time_keys: HashMap<String, HashMap<String, HashMap<String, serde_json::Value>>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncResponse {
+    pub next_batch: String,
+    pub device_one_time_keys_count: Option<OneTimeKeyCounts>,
+    pub device_unused_fallback_key_types: Option<Vec<String>>,
+    #[serde(default)]
+    pub to_device: ToDeviceEvents,
+    #[serde(default)]
+    pub device_lists: DeviceLists,
+    #[serde(default)]
+    pub rooms: Rooms,
+    /// Not sent by the server, filled in by `Device::receive_sync_response`
+    /// with the to-device events it managed to decrypt.
+    #[serde(skip)]
+    pub decrypted_to_device: Vec<crate::crypto::DecryptedOlmEvent>,
+    /// Not sent by the server, filled in by `Device::receive_sync_response`
+    /// with earlier room events that became decryptable thanks to the room
+    /// keys in this response.
+    #[serde(skip)]
+    pub decrypted_room_events: Vec<crate::crypto::DecryptedRoomEvent>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ToDeviceEvents {
+    #[serde(default)]
+    pub events: Vec<ToDeviceEvent>,
+}
+
+/// Users whose devices changed since the last sync, or who no longer share
+/// an encrypted room with us.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeviceLists {
+    #[serde(default)]
+    pub changed: Vec<String>,
+    #[serde(default)]
+    pub left: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Rooms {
+    #[serde(default)]
+    pub join: HashMap<String, JoinedRoom>,
+    #[serde(default)]
+    pub leave: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct JoinedRoom {
+    #[serde(default)]
+    pub state: RoomEvents,
+    #[serde(default)]
+    pub timeline: RoomEvents,
+}
+
+/// Room events are kept as received, they include state events.
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomEvents {
+    #[serde(default)]
+    pub events: Vec<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ToDeviceEvent {
+    pub sender: String,
+    pub r#type: String,
+    pub content: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RoomEvent {
+    pub event_id: String,
+    pub sender: String,
+    pub origin_server_ts: u64,
+    pub r#type: String,
+    pub content: serde_json::Value,
 }
@@ -0,0 +1,130 @@
+use crate::crypto::megolm_sha2::now_ms;
+use crate::crypto::pickle::{decode_salt, derive_pickle_key, encode_salt, generate_salt};
+use crate::error::Error;
+use crate::store::{from_json, to_json, CryptoStore, CROSS_SIGNING_KEYS, DEVICE_KEYS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Changes to batched tables are written out at most this often, and on
+/// `flush` or drop.
+const FLUSH_INTERVAL_MS: u64 = 1_000;
+/// Tables the server can give us again. Changes to every other table, like
+/// the account, sessions and replay records, are written out immediately.
+const BATCHED_TABLES: &[&str] = &[DEVICE_KEYS, CROSS_SIGNING_KEYS];
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct FileContents {
+    salt: String,
+    tables: HashMap<String, HashMap<String, String>>,
+}
+
+/// Keeps crypto state in a single JSON file. The file is rewritten as a
+/// whole, so changes to keys queried from the server are batched and written
+/// at most once a second, when `flush` is called and when the store is
+/// dropped. Pickles inside the file are encrypted with a key derived from the
+/// passphrase.
+pub struct JsonFileStore {
+    path: PathBuf,
+    pickle_key: [u8; 32],
+    contents: FileContents,
+    dirty: bool,
+    written_at: u64,
+}
+
+impl JsonFileStore {
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, Error> {
+        let contents = if path.exists() {
+            from_json(&std::fs::read_to_string(path)?)?
+        } else {
+            FileContents {
+                salt: encode_salt(&generate_salt()),
+                tables: HashMap::new(),
+            }
+        };
+
+        let mut store = JsonFileStore {
+            path: path.to_owned(),
+            pickle_key: derive_pickle_key(passphrase, &decode_salt(&contents.salt)?),
+            contents,
+            dirty: false,
+            written_at: 0,
+        };
+        store.write()?;
+        Ok(store)
+    }
+
+    fn write(&mut self) -> Result<(), Error> {
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, to_json(&self.contents)?)?;
+        std::fs::rename(tmp_path, &self.path)?;
+        self.dirty = false;
+        self.written_at = now_ms();
+        Ok(())
+    }
+
+    fn changed(&mut self, table: &str) -> Result<(), Error> {
+        self.dirty = true;
+        if !BATCHED_TABLES.contains(&table)
+            || now_ms().saturating_sub(self.written_at) >= FLUSH_INTERVAL_MS
+        {
+            self.write()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for JsonFileStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::warn!("Failed to write {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl CryptoStore for JsonFileStore {
+    fn pickle_key(&self) -> &[u8; 32] {
+        &self.pickle_key
+    }
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .contents
+            .tables
+            .get(table)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn put(&mut self, table: &str, key: &str, value: String) -> Result<(), Error> {
+        self.contents
+            .tables
+            .entry(table.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value);
+        self.changed(table)
+    }
+
+    fn delete(&mut self, table: &str, key: &str) -> Result<(), Error> {
+        if let Some(entries) = self.contents.tables.get_mut(table) {
+            entries.remove(key);
+        }
+        self.changed(table)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    fn keys(&self, table: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .contents
+            .tables
+            .get(table)
+            .map(|entries| entries.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+}
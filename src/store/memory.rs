@@ -0,0 +1,64 @@
+use crate::error::Error;
+use crate::store::CryptoStore;
+use rand::RngCore;
+use std::collections::HashMap;
+
+/// Keeps all crypto state in memory, it is lost when the process exits.
+pub struct MemoryStore {
+    pickle_key: [u8; 32],
+    tables: HashMap<String, HashMap<String, String>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        let mut pickle_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut pickle_key);
+        MemoryStore {
+            pickle_key,
+            tables: HashMap::new(),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CryptoStore for MemoryStore {
+    fn pickle_key(&self) -> &[u8; 32] {
+        &self.pickle_key
+    }
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .tables
+            .get(table)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn put(&mut self, table: &str, key: &str, value: String) -> Result<(), Error> {
+        self.tables
+            .entry(table.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    fn delete(&mut self, table: &str, key: &str) -> Result<(), Error> {
+        if let Some(entries) = self.tables.get_mut(table) {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+
+    fn keys(&self, table: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .tables
+            .get(table)
+            .map(|entries| entries.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+}
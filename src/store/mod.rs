@@ -0,0 +1,595 @@
+use crate::crypto::megolm_sha2::now_ms;
+use crate::crypto::{
+    CrossSigningIdentity, CrossSigningKey, DeviceKey, RoomKeyWithheld, RotationPolicy, ShareInfo,
+};
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use vodozemac::{megolm, olm};
+
+pub mod memory;
+pub use memory::MemoryStore;
+
+pub mod file;
+pub use file::JsonFileStore;
+
+pub mod sqlite;
+pub use sqlite::SqliteStore;
+
+const ACCOUNT: &str = "account";
+const IDENTITY: &str = "identity";
+const CROSS_SIGNING: &str = "cross_signing";
+const PINNED_MASTER_KEYS: &str = "pinned_master_keys";
+const VERIFIED_MASTER_KEYS: &str = "verified_master_keys";
+const FALLBACK_KEY: &str = "fallback_key";
+const OLM_SESSIONS: &str = "olm_sessions";
+const OLM_SESSIONS_LAST_USED: &str = "olm_sessions_last_used";
+const OLM_UNWEDGING: &str = "olm_unwedging";
+const OUTBOUND_GROUP_SESSIONS: &str = "outbound_group_sessions";
+const OUTBOUND_SESSION_INFO: &str = "outbound_session_info";
+const SHARED_WITH: &str = "shared_with";
+const INBOUND_GROUP_SESSIONS: &str = "inbound_group_sessions";
+const INBOUND_SESSION_INFO: &str = "inbound_session_info";
+const WITHHELD: &str = "withheld";
+const OUTGOING_KEY_REQUESTS: &str = "outgoing_key_requests";
+const MESSAGE_INDEXES: &str = "message_indexes";
+const MESSAGE_INDEX_FLOORS: &str = "message_index_floors";
+const DEVICE_KEYS: &str = "device_keys";
+const TRUST: &str = "trust";
+const CROSS_SIGNING_KEYS: &str = "cross_signing_keys";
+
+/// Local trust decision for a single device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TrustState {
+    Unverified,
+    Verified,
+    Blacklisted,
+}
+
+pub(crate) fn store_key(parts: &[&str]) -> String {
+    parts.join("|")
+}
+
+pub(crate) fn to_json<T: Serialize>(value: &T) -> Result<String, Error> {
+    serde_json::to_string(value).map_err(|e| Error::StoreError(e.to_string()))
+}
+
+pub(crate) fn from_json<T: serde::de::DeserializeOwned>(json: &str) -> Result<T, Error> {
+    serde_json::from_str(json).map_err(|e| Error::StoreError(e.to_string()))
+}
+
+/// Storage for everything a `Device` needs to survive a restart.
+///
+/// Backends only have to provide a small string table interface plus the
+/// key used to encrypt pickles; the typed accessors are built on top of it.
+pub trait CryptoStore: Send + Sync {
+    fn pickle_key(&self) -> &[u8; 32];
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<String>, Error>;
+
+    fn put(&mut self, table: &str, key: &str, value: String) -> Result<(), Error>;
+
+    fn delete(&mut self, table: &str, key: &str) -> Result<(), Error>;
+
+    fn keys(&self, table: &str) -> Result<Vec<String>, Error>;
+
+    /// Writes out changes the backend batched, if it does.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn load_account(&self) -> Result<Option<olm::Account>, Error> {
+        match self.get(ACCOUNT, ACCOUNT)? {
+            Some(pickle) => Ok(Some(olm::Account::from_pickle(
+                olm::AccountPickle::from_encrypted(&pickle, self.pickle_key())?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn save_account(&mut self, account: &olm::Account) -> Result<(), Error> {
+        let pickle = account.pickle().encrypt(self.pickle_key());
+        self.put(ACCOUNT, ACCOUNT, pickle)
+    }
+
+    /// Whether the server holds an unused fallback key of the stored account,
+    /// as far as we know.
+    fn fallback_key_published(&self) -> Result<bool, Error> {
+        match self.get(ACCOUNT, FALLBACK_KEY)? {
+            Some(json) => from_json(&json),
+            None => Ok(false),
+        }
+    }
+
+    fn set_fallback_key_published(&mut self, published: bool) -> Result<(), Error> {
+        self.put(ACCOUNT, FALLBACK_KEY, to_json(&published)?)
+    }
+
+    /// The user and device the stored account belongs to.
+    fn load_identity(&self) -> Result<Option<(String, String)>, Error> {
+        match self.get(IDENTITY, IDENTITY)? {
+            Some(json) => Ok(Some(from_json(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_identity(&mut self, user_id: &str, device_id: &str) -> Result<(), Error> {
+        self.put(IDENTITY, IDENTITY, to_json(&(user_id, device_id))?)
+    }
+
+    fn load_cross_signing_identity(&self) -> Result<Option<CrossSigningIdentity>, Error> {
+        match self.get(CROSS_SIGNING, CROSS_SIGNING)? {
+            Some(pickle) => Ok(Some(CrossSigningIdentity::from_pickle(
+                &pickle,
+                self.pickle_key(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_cross_signing_identity(
+        &mut self,
+        identity: &CrossSigningIdentity,
+    ) -> Result<(), Error> {
+        let pickle = identity.pickle(self.pickle_key())?;
+        self.put(CROSS_SIGNING, CROSS_SIGNING, pickle)
+    }
+
+    /// The Olm sessions with a device, most recently used first, with when
+    /// they were last used in milliseconds since the Unix epoch.
+    fn load_olm_sessions(&self, sender_key: &str) -> Result<Vec<(olm::Session, u64)>, Error> {
+        let prefix = store_key(&[sender_key, ""]);
+        let mut sessions = Vec::new();
+        for key in self.keys(OLM_SESSIONS)? {
+            if !key.starts_with(&prefix) {
+                continue;
+            }
+            if let Some(pickle) = self.get(OLM_SESSIONS, &key)? {
+                let session = olm::Session::from_pickle(olm::SessionPickle::from_encrypted(
+                    &pickle,
+                    self.pickle_key(),
+                )?);
+                let last_used = match self.get(OLM_SESSIONS_LAST_USED, &key)? {
+                    Some(json) => from_json(&json)?,
+                    None => 0,
+                };
+                sessions.push((session, last_used));
+            }
+        }
+        sessions.sort_by(|(_, a), (_, b)| b.cmp(a));
+        Ok(sessions)
+    }
+
+    fn save_olm_session(
+        &mut self,
+        sender_key: &str,
+        session: &olm::Session,
+        last_used: u64,
+    ) -> Result<(), Error> {
+        let key = store_key(&[sender_key, &session.session_id()]);
+        let pickle = session.pickle().encrypt(self.pickle_key());
+        self.put(OLM_SESSIONS, &key, pickle)?;
+        self.put(OLM_SESSIONS_LAST_USED, &key, to_json(&last_used)?)
+    }
+
+    /// When we last replaced a broken Olm session with a device, in
+    /// milliseconds since the Unix epoch.
+    fn load_last_unwedging(&self, user_id: &str, sender_key: &str) -> Result<Option<u64>, Error> {
+        match self.get(OLM_UNWEDGING, &store_key(&[user_id, sender_key]))? {
+            Some(json) => Ok(Some(from_json(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_last_unwedging(
+        &mut self,
+        user_id: &str,
+        sender_key: &str,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.put(
+            OLM_UNWEDGING,
+            &store_key(&[user_id, sender_key]),
+            to_json(&timestamp)?,
+        )
+    }
+
+    fn load_outbound_group_sessions(&self) -> Result<HashMap<String, megolm::GroupSession>, Error> {
+        let mut sessions = HashMap::new();
+        for room_id in self.keys(OUTBOUND_GROUP_SESSIONS)? {
+            if let Some(pickle) = self.get(OUTBOUND_GROUP_SESSIONS, &room_id)? {
+                let session = megolm::GroupSession::from_pickle(
+                    megolm::GroupSessionPickle::from_encrypted(&pickle, self.pickle_key())?,
+                );
+                sessions.insert(room_id, session);
+            }
+        }
+        Ok(sessions)
+    }
+
+    fn save_outbound_group_session(
+        &mut self,
+        room_id: &str,
+        session: &megolm::GroupSession,
+    ) -> Result<(), Error> {
+        let pickle = session.pickle().encrypt(self.pickle_key());
+        self.put(OUTBOUND_GROUP_SESSIONS, room_id, pickle)
+    }
+
+    fn remove_outbound_group_session(&mut self, room_id: &str) -> Result<(), Error> {
+        self.delete(OUTBOUND_GROUP_SESSIONS, room_id)?;
+        self.delete(OUTBOUND_SESSION_INFO, room_id)
+    }
+
+    /// The creation time and rotation policy of the outbound session of a
+    /// room, together with the ID of the session they belong to.
+    fn load_outbound_session_info(
+        &self,
+        room_id: &str,
+    ) -> Result<Option<(String, u64, RotationPolicy)>, Error> {
+        match self.get(OUTBOUND_SESSION_INFO, room_id)? {
+            Some(json) => Ok(Some(from_json(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_outbound_session_info(
+        &mut self,
+        room_id: &str,
+        session_id: &str,
+        created_at: u64,
+        rotation: &RotationPolicy,
+    ) -> Result<(), Error> {
+        self.put(
+            OUTBOUND_SESSION_INFO,
+            room_id,
+            to_json(&(session_id, created_at, rotation))?,
+        )
+    }
+
+    /// Devices an outbound session was shared with, by user and device ID.
+    fn load_shared_with(
+        &self,
+        room_id: &str,
+        session_id: &str,
+    ) -> Result<HashMap<String, HashMap<String, ShareInfo>>, Error> {
+        match self.get(SHARED_WITH, &store_key(&[room_id, session_id]))? {
+            Some(json) => from_json(&json),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn save_shared_with(
+        &mut self,
+        room_id: &str,
+        session_id: &str,
+        shared_with: &HashMap<String, HashMap<String, ShareInfo>>,
+    ) -> Result<(), Error> {
+        self.put(
+            SHARED_WITH,
+            &store_key(&[room_id, session_id]),
+            to_json(shared_with)?,
+        )
+    }
+
+    fn load_inbound_group_session(
+        &self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<megolm::InboundGroupSession>, Error> {
+        match self.get(
+            INBOUND_GROUP_SESSIONS,
+            &store_key(&[room_id, sender_key, session_id]),
+        )? {
+            Some(pickle) => Ok(Some(megolm::InboundGroupSession::from_pickle(
+                megolm::InboundGroupSessionPickle::from_encrypted(&pickle, self.pickle_key())?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn save_inbound_group_session(
+        &mut self,
+        room_id: &str,
+        sender_key: &str,
+        session: &megolm::InboundGroupSession,
+    ) -> Result<(), Error> {
+        let pickle = session.pickle().encrypt(self.pickle_key());
+        self.put(
+            INBOUND_GROUP_SESSIONS,
+            &store_key(&[room_id, sender_key, &session.session_id()]),
+            pickle,
+        )
+    }
+
+    /// The Ed25519 key the creator of an inbound session claimed, and the
+    /// Curve25519 keys of the devices that forwarded it to us, oldest first.
+    fn load_inbound_session_info(
+        &self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<(String, Vec<String>)>, Error> {
+        match self.get(
+            INBOUND_SESSION_INFO,
+            &store_key(&[room_id, sender_key, session_id]),
+        )? {
+            Some(json) => Ok(Some(from_json(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_inbound_session_info(
+        &mut self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+        sender_claimed_ed25519_key: &str,
+        forwarding_curve25519_key_chain: &[String],
+    ) -> Result<(), Error> {
+        self.put(
+            INBOUND_SESSION_INFO,
+            &store_key(&[room_id, sender_key, session_id]),
+            to_json(&(sender_claimed_ed25519_key, forwarding_curve25519_key_chain))?,
+        )
+    }
+
+    /// The `m.room_key.withheld` notice a sender sent us for a session. Notices
+    /// that are not about a single session, like `m.no_olm`, are stored with
+    /// an empty room and session ID.
+    fn load_withheld(
+        &self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheld>, Error> {
+        match self.get(WITHHELD, &store_key(&[room_id, sender_key, session_id]))? {
+            Some(json) => Ok(Some(from_json(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_withheld(&mut self, withheld: &RoomKeyWithheld) -> Result<(), Error> {
+        self.put(
+            WITHHELD,
+            &store_key(&[
+                withheld.room_id.as_deref().unwrap_or_default(),
+                &withheld.sender_key,
+                withheld.session_id.as_deref().unwrap_or_default(),
+            ]),
+            to_json(withheld)?,
+        )
+    }
+
+    /// The ID of the `m.room_key_request` we sent for a session and did not
+    /// cancel yet.
+    fn load_outgoing_key_request(
+        &self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<String>, Error> {
+        self.get(
+            OUTGOING_KEY_REQUESTS,
+            &store_key(&[room_id, sender_key, session_id]),
+        )
+    }
+
+    fn save_outgoing_key_request(
+        &mut self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+        request_id: &str,
+    ) -> Result<(), Error> {
+        self.put(
+            OUTGOING_KEY_REQUESTS,
+            &store_key(&[room_id, sender_key, session_id]),
+            request_id.to_owned(),
+        )
+    }
+
+    fn remove_outgoing_key_request(
+        &mut self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<(), Error> {
+        self.delete(
+            OUTGOING_KEY_REQUESTS,
+            &store_key(&[room_id, sender_key, session_id]),
+        )
+    }
+
+    /// The event ID and timestamp of the first event we decrypted at the given
+    /// message index of an inbound session.
+    fn load_message_index(
+        &self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<(String, u64)>, Error> {
+        match self.get(
+            MESSAGE_INDEXES,
+            &store_key(&[room_id, sender_key, session_id, &message_index.to_string()]),
+        )? {
+            Some(json) => {
+                let (event_id, origin_server_ts, _): (String, u64, u64) = from_json(&json)?;
+                Ok(Some((event_id, origin_server_ts)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Records the event that used a message index, along with when we
+    /// received it.
+    fn save_message_index(
+        &mut self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+        message_index: u32,
+        event_id: &str,
+        origin_server_ts: u64,
+    ) -> Result<(), Error> {
+        self.put(
+            MESSAGE_INDEXES,
+            &store_key(&[room_id, sender_key, session_id, &message_index.to_string()]),
+            to_json(&(event_id, origin_server_ts, now_ms()))?,
+        )
+    }
+
+    /// The lowest message index of an inbound session that may still be
+    /// unused: pruning forgets the events of lower indexes, so they count as
+    /// used.
+    fn load_message_index_floor(
+        &self,
+        room_id: &str,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<u32, Error> {
+        match self.get(
+            MESSAGE_INDEX_FLOORS,
+            &store_key(&[room_id, sender_key, session_id]),
+        )? {
+            Some(json) => from_json(&json),
+            None => Ok(0),
+        }
+    }
+
+    /// Forgets the message indexes of events we received before
+    /// `received_before`, raising the floor of their sessions above them, and
+    /// returns how many there were. The server sets event timestamps, so they
+    /// don't decide what is forgotten.
+    fn prune_message_indexes(&mut self, received_before: u64) -> Result<usize, Error> {
+        let mut pruned = 0;
+        let mut floors: HashMap<String, u32> = HashMap::new();
+        for key in self.keys(MESSAGE_INDEXES)? {
+            let json = match self.get(MESSAGE_INDEXES, &key)? {
+                Some(json) => json,
+                None => continue,
+            };
+            let (_, _, received_at): (String, u64, u64) = from_json(&json)?;
+            let (session, message_index) = key
+                .rsplit_once('|')
+                .and_then(|(session, index)| Some((session, index.parse::<u32>().ok()?)))
+                .ok_or_else(|| Error::StoreError(format!("invalid message index key {}", key)))?;
+            if received_at < received_before {
+                let floor = floors.entry(session.to_owned()).or_default();
+                *floor = (*floor).max(message_index + 1);
+                self.delete(MESSAGE_INDEXES, &key)?;
+                pruned += 1;
+            }
+        }
+
+        for (session, floor) in floors {
+            let floor = match self.get(MESSAGE_INDEX_FLOORS, &session)? {
+                Some(json) => floor.max(from_json(&json)?),
+                None => floor,
+            };
+            self.put(MESSAGE_INDEX_FLOORS, &session, to_json(&floor)?)?;
+        }
+        Ok(pruned)
+    }
+
+    fn tracked_users(&self) -> Result<Vec<String>, Error> {
+        self.keys(DEVICE_KEYS)
+    }
+
+    fn load_device_keys(&self, user_id: &str) -> Result<HashMap<String, DeviceKey>, Error> {
+        match self.get(DEVICE_KEYS, user_id)? {
+            Some(json) => from_json(&json),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// The device keys of a user as the server returned them, to check
+    /// signatures on.
+    fn load_signed_device_keys(&self, user_id: &str) -> Result<HashMap<String, Value>, Error> {
+        match self.get(DEVICE_KEYS, user_id)? {
+            Some(json) => from_json(&json),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn save_device_keys(
+        &mut self,
+        user_id: &str,
+        devices: &HashMap<String, Value>,
+    ) -> Result<(), Error> {
+        self.put(DEVICE_KEYS, user_id, to_json(devices)?)
+    }
+
+    /// The public cross-signing keys of a user, by usage.
+    fn load_cross_signing_keys(
+        &self,
+        user_id: &str,
+    ) -> Result<HashMap<String, CrossSigningKey>, Error> {
+        match self.get(CROSS_SIGNING_KEYS, user_id)? {
+            Some(json) => from_json(&json),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// The cross-signing keys of a user as the server returned them.
+    fn load_signed_cross_signing_keys(
+        &self,
+        user_id: &str,
+    ) -> Result<HashMap<String, Value>, Error> {
+        match self.get(CROSS_SIGNING_KEYS, user_id)? {
+            Some(json) => from_json(&json),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn save_cross_signing_keys(
+        &mut self,
+        user_id: &str,
+        keys: &HashMap<String, Value>,
+    ) -> Result<(), Error> {
+        self.put(CROSS_SIGNING_KEYS, user_id, to_json(keys)?)
+    }
+
+    /// The first master key we saw for a user.
+    fn load_pinned_master_key(&self, user_id: &str) -> Result<Option<String>, Error> {
+        match self.get(PINNED_MASTER_KEYS, user_id)? {
+            Some(json) => Ok(Some(from_json(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_pinned_master_key(&mut self, user_id: &str, master_key: &str) -> Result<(), Error> {
+        self.put(PINNED_MASTER_KEYS, user_id, to_json(&master_key)?)
+    }
+
+    /// The master key of `user_id` we verified interactively, if any.
+    fn load_verified_master_key(&self, user_id: &str) -> Result<Option<String>, Error> {
+        match self.get(VERIFIED_MASTER_KEYS, user_id)? {
+            Some(json) => Ok(Some(from_json(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_verified_master_key(&mut self, user_id: &str, master_key: &str) -> Result<(), Error> {
+        self.put(VERIFIED_MASTER_KEYS, user_id, to_json(&master_key)?)
+    }
+
+    fn load_trust(&self, user_id: &str, device_id: &str) -> Result<TrustState, Error> {
+        match self.get(TRUST, &store_key(&[user_id, device_id]))? {
+            Some(json) => from_json(&json),
+            None => Ok(TrustState::Unverified),
+        }
+    }
+
+    fn save_trust(
+        &mut self,
+        user_id: &str,
+        device_id: &str,
+        trust: TrustState,
+    ) -> Result<(), Error> {
+        self.put(TRUST, &store_key(&[user_id, device_id]), to_json(&trust)?)
+    }
+}
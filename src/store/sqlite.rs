@@ -0,0 +1,106 @@
+use crate::crypto::pickle::{decode_salt, derive_pickle_key, encode_salt, generate_salt};
+use crate::error::Error;
+use crate::store::CryptoStore;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Keeps crypto state in a SQLite database. Pickles are encrypted with a key
+/// derived from the passphrase, the salt lives in the `meta` table.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+    pickle_key: [u8; 32],
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, Error> {
+        SqliteStore::from_connection(Connection::open(path)?, passphrase)
+    }
+
+    pub fn open_in_memory(passphrase: &str) -> Result<Self, Error> {
+        SqliteStore::from_connection(Connection::open_in_memory()?, passphrase)
+    }
+
+    fn from_connection(connection: Connection, passphrase: &str) -> Result<Self, Error> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (
+                key TEXT PRIMARY KEY NOT NULL,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS crypto_store (
+                tbl TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (tbl, key)
+            );",
+        )?;
+
+        let salt: Option<String> = connection
+            .query_row("SELECT value FROM meta WHERE key = 'salt'", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let salt = match salt {
+            Some(salt) => salt,
+            None => {
+                let salt = encode_salt(&generate_salt());
+                connection.execute(
+                    "INSERT INTO meta (key, value) VALUES ('salt', ?1)",
+                    params![salt],
+                )?;
+                salt
+            }
+        };
+
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+            pickle_key: derive_pickle_key(passphrase, &decode_salt(&salt)?),
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap()
+    }
+}
+
+impl CryptoStore for SqliteStore {
+    fn pickle_key(&self) -> &[u8; 32] {
+        &self.pickle_key
+    }
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .connection()
+            .query_row(
+                "SELECT value FROM crypto_store WHERE tbl = ?1 AND key = ?2",
+                params![table, key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn put(&mut self, table: &str, key: &str, value: String) -> Result<(), Error> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO crypto_store (tbl, key, value) VALUES (?1, ?2, ?3)",
+            params![table, key, value],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, table: &str, key: &str) -> Result<(), Error> {
+        self.connection().execute(
+            "DELETE FROM crypto_store WHERE tbl = ?1 AND key = ?2",
+            params![table, key],
+        )?;
+        Ok(())
+    }
+
+    fn keys(&self, table: &str) -> Result<Vec<String>, Error> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT key FROM crypto_store WHERE tbl = ?1")?;
+        let keys = statement
+            .query_map(params![table], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(keys)
+    }
+}
@@ -1,12 +1,3220 @@
 extern crate e2e_matrix;
 
+use e2e_matrix::device::Device;
+
 #[test]
 fn megolm_session_creation() {
     let room_id = String::from("!#super_secret_room:matrix.org");
     let sender_key = String::from("OXIP!=S_ENDER_KEY");
     let device_id = String::from("PLAYROOM");
-    let mut megol_session = e2e_matrix::crypto::megolm_sha2::MegolmSession::new(room_id);
+    let ratchet =
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1());
+    let mut megol_session = e2e_matrix::crypto::megolm_sha2::MegolmSession::new(room_id, ratchet);
     let message = megol_session.create_message(sender_key, device_id, "Hello world");
 
     println!("{:#?}", message);
 }
+
+#[test]
+fn device_pickle_roundtrip() {
+    let device = test_device("@bot:matrix.org", "PLAYROOM");
+    let pickle = device.pickle("hunter2");
+
+    let restored = Device::from_pickle(
+        &pickle,
+        "hunter2",
+        String::from("token"),
+        String::from("https://matrix.org"),
+    )
+    .unwrap();
+    assert_eq!(restored.device_id, device.device_id);
+    assert_eq!(restored.curve25519_key(), device.curve25519_key());
+    assert_eq!(restored.ed25519_key(), device.ed25519_key());
+
+    assert!(Device::from_pickle(
+        &pickle,
+        "wrong passphrase",
+        String::from("token"),
+        String::from("https://matrix.org"),
+    )
+    .is_err());
+}
+
+#[test]
+fn crypto_stores_restore_device() {
+    use e2e_matrix::store::{CryptoStore, JsonFileStore, SqliteStore, TrustState};
+
+    let dir = std::env::temp_dir().join(format!("e2e_matrix_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let json_path = dir.join("store.json");
+    let sqlite_path = dir.join("store.sqlite3");
+
+    let open_stores = || -> Vec<Box<dyn CryptoStore>> {
+        vec![
+            Box::new(JsonFileStore::open(&json_path, "hunter2").unwrap()),
+            Box::new(SqliteStore::open(&sqlite_path, "hunter2").unwrap()),
+        ]
+    };
+
+    let mut identities = Vec::new();
+    for mut store in open_stores() {
+        store
+            .save_trust("@alice:matrix.org", "ALICEDEVICE", TrustState::Verified)
+            .unwrap();
+        let device = Device::with_store(
+            String::from("@bot:matrix.org"),
+            String::from("PLAYROOM"),
+            String::from("token"),
+            String::from("https://matrix.org"),
+            store,
+        )
+        .unwrap();
+        identities.push(device.curve25519_key());
+    }
+
+    for (store, curve25519_key) in open_stores().into_iter().zip(identities) {
+        assert_eq!(
+            store
+                .load_trust("@alice:matrix.org", "ALICEDEVICE")
+                .unwrap(),
+            TrustState::Verified
+        );
+        let device = Device::with_store(
+            String::from("@bot:matrix.org"),
+            String::from("PLAYROOM"),
+            String::from("token"),
+            String::from("https://matrix.org"),
+            store,
+        )
+        .unwrap();
+        assert_eq!(device.curve25519_key(), curve25519_key);
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn store_rejects_other_device() {
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+
+    let mut store = MemoryStore::new();
+    store.save_identity("@bot:matrix.org", "PLAYROOM").unwrap();
+
+    assert!(Device::with_store(
+        String::from("@bot:matrix.org"),
+        String::from("OTHERDEVICE"),
+        String::from("token"),
+        String::from("https://matrix.org"),
+        Box::new(store),
+    )
+    .is_err());
+}
+
+/// A homeserver on localhost that answers each request with the first
+/// canned response whose path starts with the given prefix, and records the
+/// requests it got. A response is used once while later ones for the same
+/// prefix are left, so `401` then `200` sequences can be scripted.
+struct MockHomeserver {
+    uri: String,
+    requests: std::sync::Arc<std::sync::Mutex<Vec<(String, serde_json::Value)>>>,
+}
+
+impl MockHomeserver {
+    fn start(responses: Vec<(&str, u16, serde_json::Value)>) -> Self {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::sync::{Arc, Mutex};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        let responses: Vec<(String, u16, serde_json::Value)> = responses
+            .into_iter()
+            .map(|(path, status, body)| (path.to_owned(), status, body))
+            .collect();
+        let responses = Arc::new(Mutex::new(responses));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let (responses, recorded) = (responses.clone(), recorded.clone());
+                std::thread::spawn(move || {
+                    let mut writer = stream.try_clone().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    loop {
+                        let mut request_line = String::new();
+                        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let path = request_line
+                            .split(' ')
+                            .nth(1)
+                            .unwrap_or_default()
+                            .to_owned();
+                        let mut content_length = 0;
+                        loop {
+                            let mut header = String::new();
+                            reader.read_line(&mut header).unwrap();
+                            if header.trim().is_empty() {
+                                break;
+                            }
+                            let (name, value) = header.split_once(':').unwrap();
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap();
+                            }
+                        }
+                        let mut body = vec![0; content_length];
+                        reader.read_exact(&mut body).unwrap();
+                        let body = serde_json::from_slice(&body).unwrap_or_default();
+                        recorded.lock().unwrap().push((path.clone(), body));
+
+                        let (status, response) = {
+                            let mut responses = responses.lock().unwrap();
+                            let matching: Vec<usize> = (0..responses.len())
+                                .filter(|index| path.starts_with(&responses[*index].0))
+                                .collect();
+                            match matching.first() {
+                                Some(index) if matching.len() > 1 => {
+                                    let (_, status, body) = responses.remove(*index);
+                                    (status, body)
+                                }
+                                Some(index) => (responses[*index].1, responses[*index].2.clone()),
+                                None => (
+                                    404,
+                                    serde_json::json!({"errcode": "M_UNRECOGNIZED", "error": path}),
+                                ),
+                            }
+                        };
+                        let response = response.to_string();
+                        write!(
+                            writer,
+                            "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            status,
+                            response.len(),
+                            response
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+        });
+        MockHomeserver { uri, requests }
+    }
+
+    /// The bodies of the requests to paths starting with `prefix`.
+    fn requests(&self, prefix: &str) -> Vec<serde_json::Value> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(path, _)| path.starts_with(prefix))
+            .map(|(_, body)| body.clone())
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn access_token_session_checks_published_keys() {
+    use e2e_matrix::store::{CryptoStore, SqliteStore};
+
+    let path = std::env::temp_dir().join(format!("e2e_matrix_{}.sqlite3", uuid::Uuid::new_v4()));
+    let whoami = (
+        "/_matrix/client/r0/account/whoami",
+        200,
+        serde_json::json!({"user_id": "@bot:matrix.org", "device_id": "PLAYROOM"}),
+    );
+
+    // Keys of another account were uploaded for the device: nothing is saved.
+    let other = vodozemac::olm::Account::new();
+    let server = MockHomeserver::start(vec![
+        whoami.clone(),
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {"@bot:matrix.org": {"PLAYROOM": signed_device_key(&other, "@bot:matrix.org", "PLAYROOM")}}}),
+        ),
+    ]);
+    let store = SqliteStore::open(&path, "hunter2").unwrap();
+    assert!(matches!(
+        Device::from_access_token(server.uri.clone(), String::from("token"), Box::new(store)).await,
+        Err(e2e_matrix::error::Error::DeviceMismatch(_))
+    ));
+    assert_eq!(
+        server.requests("/_matrix/client/r0/keys/query"),
+        vec![serde_json::json!({"device_keys": {"@bot:matrix.org": []}})]
+    );
+    let store = SqliteStore::open(&path, "hunter2").unwrap();
+    assert!(store.load_identity().unwrap().is_none());
+    assert!(store.load_account().unwrap().is_none());
+
+    // No keys uploaded yet: the new account is kept.
+    let server = MockHomeserver::start(vec![
+        whoami,
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {}}),
+        ),
+    ]);
+    let device =
+        Device::from_access_token(server.uri.clone(), String::from("token"), Box::new(store))
+            .await
+            .unwrap();
+    let store = SqliteStore::open(&path, "hunter2").unwrap();
+    assert!(store.load_identity().unwrap().is_some());
+    assert_eq!(
+        store
+            .load_account()
+            .unwrap()
+            .unwrap()
+            .curve25519_key()
+            .to_base64(),
+        device.curve25519_key()
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn one_time_key_replenish_threshold() {
+    let mut device = test_device("@bot:matrix.org", "PLAYROOM");
+    assert!(device.should_upload_keys());
+
+    let counts: e2e_matrix::response::OneTimeKeyCounts =
+        serde_json::from_str(r#"{"signed_curve25519": 50}"#).unwrap();
+    device.receive_one_time_key_counts(&counts);
+    assert!(!device.should_upload_keys());
+
+    let counts: e2e_matrix::response::OneTimeKeyCounts =
+        serde_json::from_str(r#"{"signed_curve25519": 3}"#).unwrap();
+    device.receive_one_time_key_counts(&counts);
+    assert!(device.should_upload_keys());
+}
+
+#[test]
+fn fallback_key_is_signed_with_flag() {
+    use e2e_matrix::crypto::OneTimeKey;
+
+    let account = vodozemac::olm::Account::new();
+    let fallback_key = OneTimeKey::new_fallback(String::from("AAAAAQ"), String::from("key")).sign(
+        &account,
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+    );
+
+    let json = serde_json::to_value(&fallback_key).unwrap();
+    assert_eq!(json["fallback"], serde_json::json!(true));
+    assert!(json["signatures"]["@bot:matrix.org"]["ed25519:PLAYROOM"].is_string());
+}
+
+#[tokio::test]
+async fn fallback_key_rotates_once_claimed() {
+    let server = MockHomeserver::start(vec![(
+        "/_matrix/client/r0/keys/upload",
+        200,
+        serde_json::json!({"one_time_key_counts": {"signed_curve25519": 50}}),
+    )]);
+    let mut device = Device::new(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+    let uploaded_fallback_keys = || -> Vec<String> {
+        server
+            .requests("/_matrix/client/r0/keys/upload")
+            .iter()
+            .filter_map(|body| body["fallback_keys"].as_object())
+            .flat_map(|keys| keys.values())
+            .map(|key| key["key"].as_str().unwrap().to_owned())
+            .collect()
+    };
+    let sync = |unused_fallback_key_types: Vec<&str>| -> e2e_matrix::response::SyncResponse {
+        serde_json::from_value(serde_json::json!({
+            "next_batch": "s1",
+            "device_one_time_keys_count": {"signed_curve25519": 50},
+            "device_unused_fallback_key_types": unused_fallback_key_types,
+        }))
+        .unwrap()
+    };
+
+    let response = device.publish_keypair().await.unwrap();
+    assert!(response.unused_fallback_key_published);
+    assert_eq!(uploaded_fallback_keys().len(), 1);
+
+    // The server still holds it: nothing to upload.
+    device
+        .receive_sync_response(&mut sync(vec!["signed_curve25519"]))
+        .await
+        .unwrap();
+    assert_eq!(uploaded_fallback_keys().len(), 1);
+
+    // Once it was claimed, a new one replaces it.
+    device
+        .receive_sync_response(&mut sync(vec![]))
+        .await
+        .unwrap();
+    let fallback_keys = uploaded_fallback_keys();
+    assert_eq!(fallback_keys.len(), 2);
+    assert_ne!(fallback_keys[0], fallback_keys[1]);
+}
+
+/// A device with the given account that already tracks `known_devices`, as
+/// `/keys/query` returns them.
+fn device_from_account(
+    account: &vodozemac::olm::Account,
+    user_id: &str,
+    device_id: &str,
+    known_devices: &[serde_json::Value],
+) -> Device {
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+
+    let mut store = MemoryStore::new();
+    store.save_account(account).unwrap();
+    for device in known_devices {
+        let user_id = device["user_id"].as_str().unwrap();
+        let mut devices = store.load_signed_device_keys(user_id).unwrap();
+        devices.insert(
+            device["device_id"].as_str().unwrap().to_owned(),
+            device.clone(),
+        );
+        store.save_device_keys(user_id, &devices).unwrap();
+    }
+    Device::with_store(
+        String::from(user_id),
+        String::from(device_id),
+        String::from("token"),
+        String::from("https://matrix.org"),
+        Box::new(store),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn olm_pre_key_message_decryption() {
+    let sender_account = vodozemac::olm::Account::new();
+    let mut recipient_account = vodozemac::olm::Account::new();
+    recipient_account.generate_one_time_keys(1);
+    let recipient_otk = *recipient_account.one_time_keys().values().next().unwrap();
+    let mut recipient = device_from_account(
+        &recipient_account,
+        "@bot:matrix.org",
+        "PLAYROOM",
+        &[signed_device_key(
+            &sender_account,
+            "@alice:matrix.org",
+            "ALICEDEVICE",
+        )],
+    );
+
+    let mut session = sender_account.create_outbound_session(
+        vodozemac::olm::SessionConfig::version_1(),
+        recipient_account.curve25519_key(),
+        recipient_otk,
+    );
+    let mut encrypt = |ed25519_key: String| {
+        let plaintext = serde_json::json!({
+            "sender": "@alice:matrix.org",
+            "sender_device": "ALICEDEVICE",
+            "keys": {"ed25519": ed25519_key},
+            "recipient": "@bot:matrix.org",
+            "recipient_keys": {"ed25519": recipient.ed25519_key()},
+            "type": "m.dummy",
+            "content": {},
+        });
+        let (message_type, body) = session.encrypt(plaintext.to_string()).to_parts();
+        serde_json::from_value::<e2e_matrix::response::ToDeviceEvent>(serde_json::json!({
+            "sender": "@alice:matrix.org",
+            "type": "m.room.encrypted",
+            "content": {
+                "algorithm": "m.olm.v1.curve25519-aes-sha2",
+                "sender_key": sender_account.curve25519_key().to_base64(),
+                "ciphertext": {
+                    recipient.curve25519_key(): {"type": message_type, "body": body},
+                },
+            },
+        }))
+        .unwrap()
+    };
+    let event = encrypt(sender_account.ed25519_key().to_base64());
+    // The sender can't claim a signing key its device doesn't have.
+    let forged = encrypt(recipient.ed25519_key());
+
+    let decrypted = recipient.decrypt_olm_event(&event).await.unwrap();
+    assert_eq!(decrypted.r#type, "m.dummy");
+    assert_eq!(
+        decrypted.sender_key,
+        sender_account.curve25519_key().to_base64()
+    );
+
+    assert!(matches!(
+        recipient.decrypt_olm_event(&event).await,
+        Err(e2e_matrix::error::Error::ReplayedMessage(_))
+    ));
+    assert!(matches!(
+        recipient.decrypt_olm_event(&forged).await,
+        Err(e2e_matrix::error::Error::DecryptionError(_))
+    ));
+}
+
+fn room_key_event(
+    room_id: &str,
+    sender_key: &str,
+    session: &vodozemac::megolm::GroupSession,
+) -> e2e_matrix::crypto::DecryptedOlmEvent {
+    let mut event: e2e_matrix::crypto::DecryptedOlmEvent =
+        serde_json::from_value(serde_json::json!({
+            "sender": "@alice:matrix.org",
+            "sender_device": "ALICEDEVICE",
+            "keys": {"ed25519": "alice_ed25519"},
+            "recipient": "@bot:matrix.org",
+            "recipient_keys": {"ed25519": "bot_ed25519"},
+            "type": "m.room_key",
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "room_id": room_id,
+                "session_id": session.session_id(),
+                "session_key": session.session_key().to_base64(),
+            },
+        }))
+        .unwrap();
+    event.sender_key = sender_key.to_owned();
+    event
+}
+
+fn encrypted_room_event(
+    event_id: &str,
+    origin_server_ts: u64,
+    message: &e2e_matrix::crypto::MegolmMessage,
+) -> e2e_matrix::response::RoomEvent {
+    serde_json::from_value(serde_json::json!({
+        "event_id": event_id,
+        "sender": "@alice:matrix.org",
+        "origin_server_ts": origin_server_ts,
+        "type": "m.room.encrypted",
+        "content": message,
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn megolm_room_event_decryption() {
+    let room_id = "!room:matrix.org";
+    let alice_account = vodozemac::olm::Account::new();
+    let alice_curve25519 = alice_account.curve25519_key().to_base64();
+    let server = MockHomeserver::start(vec![(
+        "/_matrix/client/r0/keys/query",
+        200,
+        serde_json::json!({"device_keys": {"@alice:matrix.org": {
+            "ALICEDEVICE": signed_device_key(&alice_account, "@alice:matrix.org", "ALICEDEVICE"),
+        }}}),
+    )]);
+    let mut device = Device::new(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+
+    let mut outbound = e2e_matrix::crypto::MegolmSession::new(
+        String::from(room_id),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    );
+    let room_key = room_key_event(room_id, &alice_curve25519, &outbound.ratchet);
+    let message = outbound.create_message(
+        alice_curve25519.clone(),
+        String::from("ALICEDEVICE"),
+        "Hello",
+    );
+    let event = encrypted_room_event("$event", 1, &message);
+
+    assert!(matches!(
+        device.decrypt_room_event(room_id, &event).await,
+        Err(e2e_matrix::error::Error::UnknownMegolmSession(_))
+    ));
+
+    device.receive_room_key(&room_key).unwrap();
+    let decrypted = device.decrypt_room_event(room_id, &event).await.unwrap();
+    assert_eq!(decrypted.sender, "@alice:matrix.org");
+    assert_eq!(decrypted.content["body"], "Hello");
+    assert_eq!(decrypted.message_index, 0);
+    assert_eq!(decrypted.session_id, outbound.ratchet.session_id());
+    assert_eq!(server.requests("/_matrix/client/r0/keys/query").len(), 1);
+
+    // The server can't pass Alice's messages off as someone else's.
+    let mut forged = encrypted_room_event("$forged", 2, &message);
+    forged.sender = String::from("@mallory:matrix.org");
+    assert!(matches!(
+        device.decrypt_room_event(room_id, &forged).await,
+        Err(e2e_matrix::error::Error::UnknownDevice(_))
+    ));
+}
+
+#[tokio::test]
+async fn megolm_replayed_message_index() {
+    let room_id = "!room:matrix.org";
+    let alice_account = vodozemac::olm::Account::new();
+    let alice_curve25519 = alice_account.curve25519_key().to_base64();
+    let mut device = device_from_account(
+        &vodozemac::olm::Account::new(),
+        "@bot:matrix.org",
+        "PLAYROOM",
+        &[signed_device_key(
+            &alice_account,
+            "@alice:matrix.org",
+            "ALICEDEVICE",
+        )],
+    );
+
+    let mut outbound = e2e_matrix::crypto::MegolmSession::new(
+        String::from(room_id),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    );
+    device
+        .receive_room_key(&room_key_event(
+            room_id,
+            &alice_curve25519,
+            &outbound.ratchet,
+        ))
+        .unwrap();
+    let message = outbound.create_message(
+        alice_curve25519.clone(),
+        String::from("ALICEDEVICE"),
+        "!restart",
+    );
+
+    let event = encrypted_room_event("$event", 1, &message);
+    device.decrypt_room_event(room_id, &event).await.unwrap();
+    device.decrypt_room_event(room_id, &event).await.unwrap();
+
+    let replayed = encrypted_room_event("$replayed", 2, &message);
+    assert!(matches!(
+        device.decrypt_room_event(room_id, &replayed).await,
+        Err(e2e_matrix::error::Error::ReplayedMessage(_))
+    ));
+}
+
+#[tokio::test]
+async fn megolm_replay_is_detected_after_pruning() {
+    use e2e_matrix::store::{CryptoStore, JsonFileStore};
+
+    let room_id = "!room:matrix.org";
+    let path = std::env::temp_dir().join(format!("e2e_matrix_{}.json", uuid::Uuid::new_v4()));
+    let alice_account = vodozemac::olm::Account::new();
+    let alice_curve25519 = alice_account.curve25519_key().to_base64();
+    let open_device = || {
+        Device::with_store(
+            String::from("@bot:matrix.org"),
+            String::from("PLAYROOM"),
+            String::from("token"),
+            String::from("https://matrix.org"),
+            Box::new(JsonFileStore::open(&path, "hunter2").unwrap()),
+        )
+        .unwrap()
+    };
+
+    let mut store = JsonFileStore::open(&path, "hunter2").unwrap();
+    store
+        .save_device_keys(
+            "@alice:matrix.org",
+            &std::collections::HashMap::from([(
+                String::from("ALICEDEVICE"),
+                signed_device_key(&alice_account, "@alice:matrix.org", "ALICEDEVICE"),
+            )]),
+        )
+        .unwrap();
+    drop(store);
+    let mut outbound = e2e_matrix::crypto::MegolmSession::new(
+        String::from(room_id),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    );
+    let mut device = open_device();
+    device
+        .receive_room_key(&room_key_event(
+            room_id,
+            &alice_curve25519,
+            &outbound.ratchet,
+        ))
+        .unwrap();
+    // The server backdates the command so that its index is pruned early.
+    let message = outbound.create_message(
+        alice_curve25519.clone(),
+        String::from("ALICEDEVICE"),
+        "!restart",
+    );
+    device
+        .decrypt_room_event(room_id, &encrypted_room_event("$event", 1, &message))
+        .await
+        .unwrap();
+    drop(device);
+
+    // Indexes are pruned by when we received them, so nothing goes yet.
+    let mut store = JsonFileStore::open(&path, "hunter2").unwrap();
+    assert_eq!(store.prune_message_indexes(1_000).unwrap(), 0);
+    assert_eq!(store.prune_message_indexes(u64::MAX).unwrap(), 1);
+    drop(store);
+
+    let mut device = open_device();
+    assert!(matches!(
+        device
+            .decrypt_room_event(room_id, &encrypted_room_event("$replayed", 2, &message))
+            .await,
+        Err(e2e_matrix::error::Error::ReplayedMessage(_))
+    ));
+    let next = outbound.create_message(
+        alice_curve25519.clone(),
+        String::from("ALICEDEVICE"),
+        "!status",
+    );
+    device
+        .decrypt_room_event(room_id, &encrypted_room_event("$next", 3, &next))
+        .await
+        .unwrap();
+    drop(device);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn json_file_store_writes_crypto_state_through() {
+    use e2e_matrix::store::{CryptoStore, JsonFileStore};
+    use std::collections::HashMap;
+
+    let path = std::env::temp_dir().join(format!("e2e_matrix_{}.json", uuid::Uuid::new_v4()));
+    let reopen = || JsonFileStore::open(&path, "hunter2").unwrap();
+    let mut store = reopen();
+    for (message_index, origin_server_ts) in [(0, 1_000), (1, 2_000), (2, 3_000)] {
+        store
+            .save_message_index(
+                "!room:matrix.org",
+                "sender_key",
+                "session",
+                message_index,
+                &format!("$event{}", message_index),
+                origin_server_ts,
+            )
+            .unwrap();
+    }
+    // Device keys can be queried again, so they wait for the next flush.
+    let device_keys = |device_id: &str| {
+        HashMap::from([(
+            device_id.to_owned(),
+            signed_device_key(
+                &vodozemac::olm::Account::new(),
+                "@alice:matrix.org",
+                device_id,
+            ),
+        )])
+    };
+    store
+        .save_device_keys("@alice:matrix.org", &device_keys("ALICEDEVICE"))
+        .unwrap();
+    store
+        .save_device_keys("@alice:matrix.org", &device_keys("OTHERDEVICE"))
+        .unwrap();
+    // A crash skips flushing on drop.
+    std::mem::forget(store);
+
+    let mut store = reopen();
+    assert!(store
+        .load_message_index("!room:matrix.org", "sender_key", "session", 2)
+        .unwrap()
+        .is_some());
+    assert!(!store
+        .load_device_keys("@alice:matrix.org")
+        .unwrap()
+        .contains_key("OTHERDEVICE"));
+
+    assert_eq!(store.prune_message_indexes(u64::MAX).unwrap(), 3);
+    std::mem::forget(store);
+    let store = reopen();
+    for message_index in 0..3 {
+        assert!(store
+            .load_message_index("!room:matrix.org", "sender_key", "session", message_index)
+            .unwrap()
+            .is_none());
+    }
+    assert_eq!(
+        store
+            .load_message_index_floor("!room:matrix.org", "sender_key", "session")
+            .unwrap(),
+        3
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn device_key_self_signature() {
+    use e2e_matrix::crypto::DeviceKey;
+
+    let account = vodozemac::olm::Account::new();
+    let device_key = DeviceKey::new(
+        String::from("PLAYROOM"),
+        String::from("@bot:matrix.org"),
+        account.curve25519_key().to_base64(),
+        account.ed25519_key().to_base64(),
+    )
+    .sign(&account);
+    let json = serde_json::to_value(&device_key).unwrap();
+    DeviceKey::from_signed_json(&json, "@bot:matrix.org", "PLAYROOM").unwrap();
+    assert!(DeviceKey::from_signed_json(&json, "@bot:matrix.org", "OTHERDEVICE").is_err());
+
+    let mut forged = json.clone();
+    forged["keys"]["curve25519:PLAYROOM"] =
+        serde_json::json!(vodozemac::olm::Account::new().curve25519_key().to_base64());
+    assert!(DeviceKey::from_signed_json(&forged, "@bot:matrix.org", "PLAYROOM").is_err());
+
+    // Fields we don't know about are signed too, and checked as received.
+    let mut extended = json;
+    extended.as_object_mut().unwrap().remove("signatures");
+    extended["org.example.extra"] = serde_json::json!("signed");
+    let signature = account.sign(&cjson::to_string(&extended).unwrap());
+    extended["signatures"] = serde_json::json!({
+        "@bot:matrix.org": {"ed25519:PLAYROOM": signature.to_base64()}
+    });
+    DeviceKey::from_signed_json(&extended, "@bot:matrix.org", "PLAYROOM").unwrap();
+    extended["org.example.extra"] = serde_json::json!("forged");
+    assert!(DeviceKey::from_signed_json(&extended, "@bot:matrix.org", "PLAYROOM").is_err());
+}
+
+#[test]
+fn one_time_key_signature() {
+    use e2e_matrix::crypto::OneTimeKey;
+
+    let account = vodozemac::olm::Account::new();
+    let ed25519_key = account.ed25519_key().to_base64();
+    let otk = OneTimeKey::new(String::from("AAAAAQ"), String::from("key")).sign(
+        &account,
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+    );
+    let json = serde_json::to_value(&otk).unwrap();
+    OneTimeKey::from_signed_json(&json, "@bot:matrix.org", "PLAYROOM", &ed25519_key).unwrap();
+
+    let mut forged = json;
+    forged["key"] = serde_json::json!("forged");
+    assert!(matches!(
+        OneTimeKey::from_signed_json(&forged, "@bot:matrix.org", "PLAYROOM", &ed25519_key),
+        Err(e2e_matrix::error::Error::InvalidOneTimeKey(_))
+    ));
+}
+
+#[test]
+fn olm_exchange_uses_real_message_type() {
+    use e2e_matrix::crypto::{DeviceKey, OlmExchange};
+    use vodozemac::olm::{Account, OlmMessage, SessionConfig};
+
+    let alice = test_device("@alice:matrix.org", "ALICEDEVICE");
+    let mut bob_account = Account::new();
+    bob_account.generate_one_time_keys(1);
+    let bob_otk = *bob_account.one_time_keys().values().next().unwrap();
+    let bob_key = DeviceKey::new(
+        String::from("BOBDEVICE"),
+        String::from("@bob:matrix.org"),
+        bob_account.curve25519_key().to_base64(),
+        bob_account.ed25519_key().to_base64(),
+    );
+    let room_key =
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1());
+
+    let mut alice_session = Account::new().create_outbound_session(
+        SessionConfig::version_1(),
+        bob_account.curve25519_key(),
+        bob_otk,
+    );
+    let exchange = OlmExchange::new(
+        &alice,
+        &bob_key,
+        &room_key,
+        &mut alice_session,
+        String::from("!room:matrix.org"),
+    );
+    assert_eq!(
+        exchange.ciphertext[&bob_key.curve25519_key().unwrap().to_owned()].r#type,
+        0
+    );
+
+    // Once bob answered, alice switches to normal messages.
+    let pre_key = match alice_session.encrypt("hi") {
+        OlmMessage::PreKey(m) => m,
+        OlmMessage::Normal(_) => unreachable!(),
+    };
+    let mut bob_session = bob_account
+        .create_inbound_session(pre_key.identity_key(), &pre_key)
+        .unwrap()
+        .session;
+    alice_session
+        .decrypt(&bob_session.encrypt("hello"))
+        .unwrap();
+
+    let exchange = OlmExchange::new(
+        &alice,
+        &bob_key,
+        &room_key,
+        &mut alice_session,
+        String::from("!room:matrix.org"),
+    );
+    assert_eq!(
+        exchange.ciphertext[&bob_key.curve25519_key().unwrap().to_owned()].r#type,
+        1
+    );
+}
+
+#[tokio::test]
+async fn olm_session_used_last_is_reused() {
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+    use vodozemac::olm::{Account, OlmMessage, SessionConfig};
+
+    let mut bob_account = Account::new();
+    bob_account.generate_one_time_keys(2);
+    let bob_curve25519 = bob_account.curve25519_key();
+    let alice_account = Account::new();
+    let mut sessions = bob_account.one_time_keys().into_values().map(|otk| {
+        alice_account.create_outbound_session(SessionConfig::version_1(), bob_curve25519, otk)
+    });
+    let (used_last, created_last) = (sessions.next().unwrap(), sessions.next().unwrap());
+
+    let mut store = MemoryStore::new();
+    store.save_account(&alice_account).unwrap();
+    let bob_key = bob_curve25519.to_base64();
+    store.save_olm_session(&bob_key, &used_last, 2_000).unwrap();
+    store
+        .save_olm_session(&bob_key, &created_last, 1_000)
+        .unwrap();
+
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {"@bob:matrix.org": {
+                "BOBDEVICE": signed_device_key(&bob_account, "@bob:matrix.org", "BOBDEVICE"),
+            }}}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/",
+            200,
+            serde_json::json!({"algorithm": "m.megolm.v1.aes-sha2"}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            200,
+            serde_json::json!({}),
+        ),
+    ]);
+    let mut alice = Device::with_store(
+        String::from("@alice:matrix.org"),
+        String::from("ALICEDEVICE"),
+        String::from("token"),
+        server.uri.clone(),
+        Box::new(store),
+    )
+    .unwrap();
+    alice
+        .create_megolm_session(
+            String::from("!room:matrix.org"),
+            String::from("@bob:matrix.org"),
+            String::from("BOBDEVICE"),
+        )
+        .await
+        .unwrap();
+
+    let sent = server.requests("/_matrix/client/r0/sendToDevice/m.room.encrypted/");
+    let ciphertext = &sent[0]["messages"]["@bob:matrix.org"]["BOBDEVICE"]["ciphertext"][&bob_key];
+    let message = OlmMessage::from_parts(
+        ciphertext["type"].as_u64().unwrap() as usize,
+        ciphertext["body"].as_str().unwrap(),
+    )
+    .unwrap();
+    match message {
+        OlmMessage::PreKey(pre_key) => assert_eq!(pre_key.session_keys(), used_last.session_keys()),
+        OlmMessage::Normal(_) => unreachable!(),
+    }
+    assert!(server.requests("/_matrix/client/r0/keys/claim").is_empty());
+}
+
+#[tokio::test]
+async fn excluded_device_gets_no_olm_session() {
+    use e2e_matrix::store::TrustState;
+
+    let bob_account = vodozemac::olm::Account::new();
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {"@bob:matrix.org": {
+                "BOBDEVICE": signed_device_key(&bob_account, "@bob:matrix.org", "BOBDEVICE"),
+            }}}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/",
+            200,
+            serde_json::json!({"algorithm": "m.megolm.v1.aes-sha2"}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            200,
+            serde_json::json!({}),
+        ),
+    ]);
+    let mut alice = Device::new(
+        String::from("@alice:matrix.org"),
+        String::from("ALICEDEVICE"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+    alice
+        .set_device_trust("@bob:matrix.org", "BOBDEVICE", TrustState::Blacklisted)
+        .unwrap();
+    alice
+        .create_megolm_session(
+            String::from("!room:matrix.org"),
+            String::from("@bob:matrix.org"),
+            String::from("BOBDEVICE"),
+        )
+        .await
+        .unwrap();
+
+    assert!(server.requests("/_matrix/client/r0/keys/claim").is_empty());
+    assert!(server
+        .requests("/_matrix/client/r0/sendToDevice/m.room.encrypted/")
+        .is_empty());
+    let withheld = server.requests("/_matrix/client/r0/sendToDevice/m.room_key.withheld/");
+    assert_eq!(
+        withheld[0]["messages"]["@bob:matrix.org"]["BOBDEVICE"]["code"],
+        "m.blacklisted"
+    );
+}
+
+#[tokio::test]
+async fn room_key_is_shared_in_batches() {
+    use e2e_matrix::crypto::OneTimeKey;
+    use vodozemac::olm::Account;
+
+    let mut bob_accounts = [Account::new(), Account::new()];
+    let carol_account = Account::new();
+    let mut claimed = serde_json::Map::new();
+    for (account, device_id) in bob_accounts.iter_mut().zip(["BOB1", "BOB2"]) {
+        account.generate_one_time_keys(1);
+        let otk = account.one_time_keys().into_values().next().unwrap();
+        let otk = OneTimeKey::new(String::from("AAAAAQ"), otk.to_base64()).sign(
+            account,
+            String::from("@bob:matrix.org"),
+            device_id.to_owned(),
+        );
+        claimed.insert(
+            device_id.to_owned(),
+            serde_json::json!({"signed_curve25519:AAAAAQ": otk}),
+        );
+    }
+
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/rooms/!room:matrix.org/joined_members",
+            200,
+            serde_json::json!({"joined": {"@bob:matrix.org": {}, "@carol:matrix.org": {}}}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!room:matrix.org/state/m.room.encryption",
+            200,
+            serde_json::json!({"algorithm": "m.megolm.v1.aes-sha2"}),
+        ),
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {
+                "@bob:matrix.org": {
+                    "BOB1": signed_device_key(&bob_accounts[0], "@bob:matrix.org", "BOB1"),
+                    "BOB2": signed_device_key(&bob_accounts[1], "@bob:matrix.org", "BOB2"),
+                },
+                "@carol:matrix.org": {
+                    "CAROL1": signed_device_key(&carol_account, "@carol:matrix.org", "CAROL1"),
+                },
+            }}),
+        ),
+        (
+            "/_matrix/client/r0/keys/claim",
+            200,
+            serde_json::json!({"one_time_keys": {"@bob:matrix.org": claimed}}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            200,
+            serde_json::json!({}),
+        ),
+    ]);
+    let mut alice = Device::new(
+        String::from("@alice:matrix.org"),
+        String::from("ALICEDEVICE"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+    alice
+        .share_room_key(String::from("!room:matrix.org"))
+        .await
+        .unwrap();
+
+    assert_eq!(server.requests("/_matrix/client/r0/keys/query").len(), 1);
+    let claims = server.requests("/_matrix/client/r0/keys/claim");
+    assert_eq!(
+        claims,
+        vec![serde_json::json!({"one_time_keys": {
+            "@bob:matrix.org": {"BOB1": "signed_curve25519", "BOB2": "signed_curve25519"},
+            "@carol:matrix.org": {"CAROL1": "signed_curve25519"},
+        }})]
+    );
+
+    // Both of Bob's devices get the key in one request, Carol's device has
+    // no one-time key left and is told so instead.
+    let sent = server.requests("/_matrix/client/r0/sendToDevice/m.room.encrypted/");
+    assert_eq!(sent.len(), 1);
+    let recipients = sent[0]["messages"].as_object().unwrap();
+    assert_eq!(recipients.len(), 1);
+    let mut bob_devices: Vec<&String> = recipients["@bob:matrix.org"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .collect();
+    bob_devices.sort();
+    assert_eq!(bob_devices, ["BOB1", "BOB2"]);
+
+    let withheld = server.requests("/_matrix/client/r0/sendToDevice/m.room_key.withheld/");
+    assert_eq!(withheld.len(), 1);
+    assert_eq!(
+        withheld[0]["messages"]["@carol:matrix.org"]["CAROL1"]["code"],
+        "m.no_olm"
+    );
+
+    let outbound = alice.outbound_session("!room:matrix.org").unwrap();
+    assert!(outbound.is_shared_with("@bob:matrix.org", "BOB1"));
+    assert!(outbound.is_shared_with("@bob:matrix.org", "BOB2"));
+    assert!(!outbound.is_shared_with("@carol:matrix.org", "CAROL1"));
+}
+
+#[test]
+fn megolm_session_tracks_shared_devices() {
+    use e2e_matrix::crypto::{DeviceKey, MegolmSession};
+
+    let bob_key = DeviceKey::new(
+        String::from("BOBDEVICE"),
+        String::from("@bob:matrix.org"),
+        String::from("bob_curve25519"),
+        String::from("bob_ed25519"),
+    );
+    let mut outbound = MegolmSession::new(
+        String::from("!room:matrix.org"),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    );
+    outbound.create_message(
+        String::from("alice_curve25519"),
+        String::from("ALICEDEVICE"),
+        "before bob",
+    );
+    assert!(!outbound.is_shared_with("@bob:matrix.org", "BOBDEVICE"));
+
+    outbound.mark_shared_with(&bob_key);
+    assert!(outbound.is_shared_with("@bob:matrix.org", "BOBDEVICE"));
+    assert!(!outbound.is_shared_with("@bob:matrix.org", "OTHERDEVICE"));
+
+    let share_info = &outbound.shared_with["@bob:matrix.org"]["BOBDEVICE"];
+    assert_eq!(share_info.curve25519_key, "bob_curve25519");
+    assert_eq!(share_info.message_index, 1);
+}
+
+#[test]
+fn megolm_session_rotation_policy() {
+    use e2e_matrix::crypto::{DeviceKey, MegolmSession, RotationPolicy};
+
+    assert_eq!(
+        RotationPolicy::new(None, Some(2)),
+        RotationPolicy {
+            rotation_period_ms: 604_800_000,
+            rotation_period_msgs: 2,
+        }
+    );
+
+    let mut outbound = MegolmSession::new(
+        String::from("!room:matrix.org"),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    )
+    .with_rotation(RotationPolicy::new(None, Some(2)));
+    for body in ["one", "two"] {
+        assert!(!outbound.needs_rotation());
+        outbound.create_message(
+            String::from("alice_curve25519"),
+            String::from("ALICEDEVICE"),
+            body,
+        );
+    }
+    assert!(outbound.needs_rotation());
+
+    let mut expired = MegolmSession::new(
+        String::from("!room:matrix.org"),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    );
+    assert!(!expired.needs_rotation());
+    expired.created_at -= 604_800_000;
+    assert!(expired.needs_rotation());
+
+    let bob_key = DeviceKey::new(
+        String::from("BOBDEVICE"),
+        String::from("@bob:matrix.org"),
+        String::from("bob_curve25519"),
+        String::from("bob_ed25519"),
+    );
+    let bob_new_key = DeviceKey::new(
+        String::from("BOBDEVICE"),
+        String::from("@bob:matrix.org"),
+        String::from("other_curve25519"),
+        String::from("bob_ed25519"),
+    );
+    expired.mark_shared_with(&bob_key);
+    assert!(!expired.has_removed_recipients(std::slice::from_ref(&bob_key)));
+    assert!(expired.has_removed_recipients(&[]));
+    assert!(expired.has_removed_recipients(&[bob_new_key]));
+}
+
+#[test]
+fn outbound_sessions_restore_from_store() {
+    use e2e_matrix::crypto::{DeviceKey, MegolmSession, RotationPolicy};
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+
+    let bob_key = DeviceKey::new(
+        String::from("BOBDEVICE"),
+        String::from("@bob:matrix.org"),
+        String::from("bob_curve25519"),
+        String::from("bob_ed25519"),
+    );
+    let mut outbound = MegolmSession::new(
+        String::from("!room:matrix.org"),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    )
+    .with_rotation(RotationPolicy::new(Some(3_600_000), Some(10)));
+    outbound.mark_shared_with(&bob_key);
+    let session_id = outbound.ratchet.session_id();
+
+    let mut store = MemoryStore::new();
+    store
+        .save_outbound_group_session("!room:matrix.org", &outbound.ratchet)
+        .unwrap();
+    store
+        .save_outbound_session_info(
+            "!room:matrix.org",
+            &session_id,
+            outbound.created_at,
+            &outbound.rotation,
+        )
+        .unwrap();
+    store
+        .save_shared_with("!room:matrix.org", &session_id, &outbound.shared_with)
+        .unwrap();
+    store
+        .save_outbound_group_session(
+            "!other:matrix.org",
+            &vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+        )
+        .unwrap();
+
+    let device = Device::with_store(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        String::from("https://matrix.org"),
+        Box::new(store),
+    )
+    .unwrap();
+
+    let restored = device.outbound_session("!room:matrix.org").unwrap();
+    assert_eq!(restored.ratchet.session_id(), session_id);
+    assert_eq!(restored.created_at, outbound.created_at);
+    assert_eq!(restored.rotation, outbound.rotation);
+    assert!(restored.is_shared_with("@bob:matrix.org", "BOBDEVICE"));
+    assert!(!restored.needs_rotation());
+
+    // Sessions of unknown age are replaced before they are used again.
+    assert!(device
+        .outbound_session("!other:matrix.org")
+        .unwrap()
+        .needs_rotation());
+    assert!(device.outbound_session("!unknown:matrix.org").is_none());
+}
+
+#[test]
+fn room_key_withheld_notice() {
+    use e2e_matrix::crypto::{DeviceKey, MegolmSession, WithheldCode};
+    use e2e_matrix::device::SharingStrategy;
+
+    let device = test_device("@bot:matrix.org", "PLAYROOM");
+    assert_eq!(device.sharing_strategy, SharingStrategy::AllDevices);
+
+    let bob_key = DeviceKey::new(
+        String::from("BOBDEVICE"),
+        String::from("@bob:matrix.org"),
+        String::from("bob_curve25519"),
+        String::from("bob_ed25519"),
+    );
+    let mut outbound = MegolmSession::new(
+        String::from("!room:matrix.org"),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    );
+    outbound.mark_withheld_from(&bob_key, WithheldCode::Unverified);
+    assert!(outbound.is_withheld_from("@bob:matrix.org", "BOBDEVICE", WithheldCode::Unverified));
+    assert!(!outbound.is_withheld_from("@bob:matrix.org", "BOBDEVICE", WithheldCode::Blacklisted));
+    assert!(!outbound.is_shared_with("@bob:matrix.org", "BOBDEVICE"));
+
+    let notice = serde_json::to_value(
+        outbound.withheld_notice(device.curve25519_key(), WithheldCode::Blacklisted),
+    )
+    .unwrap();
+    assert_eq!(notice["algorithm"], "m.megolm.v1.aes-sha2");
+    assert_eq!(notice["code"], "m.blacklisted");
+    assert_eq!(notice["room_id"], "!room:matrix.org");
+    assert_eq!(notice["session_id"], outbound.ratchet.session_id().as_str());
+    assert_eq!(notice["sender_key"], device.curve25519_key().as_str());
+}
+
+#[test]
+fn sharing_strategy_filters_devices() {
+    use e2e_matrix::crypto::{DeviceKey, WithheldCode};
+    use e2e_matrix::device::SharingStrategy;
+    use e2e_matrix::store::TrustState;
+
+    let mut device = test_device("@bot:matrix.org", "PLAYROOM");
+    for (device_id, trust) in [
+        ("VERIFIED", TrustState::Verified),
+        ("BLACKLISTED", TrustState::Blacklisted),
+    ] {
+        device
+            .set_device_trust("@bob:matrix.org", device_id, trust)
+            .unwrap();
+    }
+    let bob_device = |device_id: &str| {
+        DeviceKey::new(
+            device_id.to_owned(),
+            String::from("@bob:matrix.org"),
+            format!("{}_curve25519", device_id),
+            format!("{}_ed25519", device_id),
+        )
+    };
+    let devices = || {
+        ["VERIFIED", "UNVERIFIED", "BLACKLISTED"]
+            .map(bob_device)
+            .to_vec()
+    };
+    let device_ids = |devices: &[DeviceKey]| -> Vec<String> {
+        devices
+            .iter()
+            .map(|device| device.device_id.clone())
+            .collect()
+    };
+    let withheld_ids = |withheld: &[(DeviceKey, WithheldCode)]| -> Vec<(String, WithheldCode)> {
+        withheld
+            .iter()
+            .map(|(device, code)| (device.device_id.clone(), *code))
+            .collect()
+    };
+
+    let (allowed, withheld) = device.apply_sharing_strategy(devices()).unwrap();
+    assert_eq!(device_ids(&allowed), ["VERIFIED", "UNVERIFIED"]);
+    assert_eq!(
+        withheld_ids(&withheld),
+        [(String::from("BLACKLISTED"), WithheldCode::Blacklisted)]
+    );
+
+    device.sharing_strategy = SharingStrategy::VerifiedOnly;
+    let (allowed, withheld) = device.apply_sharing_strategy(devices()).unwrap();
+    assert_eq!(device_ids(&allowed), ["VERIFIED"]);
+    assert_eq!(
+        withheld_ids(&withheld),
+        [
+            (String::from("UNVERIFIED"), WithheldCode::Unverified),
+            (String::from("BLACKLISTED"), WithheldCode::Blacklisted),
+        ]
+    );
+
+    device.sharing_strategy = SharingStrategy::ErrorOnUnverified;
+    match device.apply_sharing_strategy(devices()) {
+        Err(e2e_matrix::error::Error::UnverifiedDevices(devices)) => {
+            assert_eq!(devices, "@bob:matrix.org (UNVERIFIED)")
+        }
+        _ => panic!("the unverified device was not reported"),
+    }
+    // A blacklisted device is simply left out.
+    let (allowed, withheld) = device
+        .apply_sharing_strategy(["VERIFIED", "BLACKLISTED"].map(bob_device).to_vec())
+        .unwrap();
+    assert_eq!(device_ids(&allowed), ["VERIFIED"]);
+    assert_eq!(
+        withheld_ids(&withheld),
+        [(String::from("BLACKLISTED"), WithheldCode::Blacklisted)]
+    );
+}
+
+#[tokio::test]
+async fn session_rotates_when_recipient_is_no_longer_allowed() {
+    use e2e_matrix::crypto::OneTimeKey;
+    use e2e_matrix::device::SharingStrategy;
+    use e2e_matrix::store::TrustState;
+
+    let mut bob_account = vodozemac::olm::Account::new();
+    bob_account.generate_one_time_keys(1);
+    let otk = bob_account.one_time_keys().into_values().next().unwrap();
+    let otk = OneTimeKey::new(String::from("AAAAAQ"), otk.to_base64()).sign(
+        &bob_account,
+        String::from("@bob:matrix.org"),
+        String::from("BOBDEVICE"),
+    );
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/rooms/!room:matrix.org/joined_members",
+            200,
+            serde_json::json!({"joined": {"@bob:matrix.org": {}}}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!room:matrix.org/state/m.room.encryption",
+            200,
+            serde_json::json!({"algorithm": "m.megolm.v1.aes-sha2"}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!room:matrix.org/send/",
+            200,
+            serde_json::json!({"event_id": "$event"}),
+        ),
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {"@bob:matrix.org": {
+                "BOBDEVICE": signed_device_key(&bob_account, "@bob:matrix.org", "BOBDEVICE"),
+            }}}),
+        ),
+        (
+            "/_matrix/client/r0/keys/claim",
+            200,
+            serde_json::json!({"one_time_keys": {"@bob:matrix.org": {
+                "BOBDEVICE": {"signed_curve25519:AAAAAQ": otk},
+            }}}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            200,
+            serde_json::json!({}),
+        ),
+    ]);
+    let mut alice = Device::new(
+        String::from("@alice:matrix.org"),
+        String::from("ALICEDEVICE"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+    let session_id = |alice: &Device| {
+        alice
+            .outbound_session("!room:matrix.org")
+            .unwrap()
+            .ratchet
+            .session_id()
+    };
+
+    alice
+        .send_encrypted_message("!room:matrix.org", "hello")
+        .await
+        .unwrap();
+    let first_session = session_id(&alice);
+    assert!(alice
+        .outbound_session("!room:matrix.org")
+        .unwrap()
+        .is_shared_with("@bob:matrix.org", "BOBDEVICE"));
+    alice
+        .send_encrypted_message("!room:matrix.org", "hello again")
+        .await
+        .unwrap();
+    assert_eq!(session_id(&alice), first_session);
+
+    // Bob's unverified device holds the key but may no longer get it.
+    alice.sharing_strategy = SharingStrategy::VerifiedOnly;
+    alice
+        .send_encrypted_message("!room:matrix.org", "secret")
+        .await
+        .unwrap();
+    assert_ne!(session_id(&alice), first_session);
+    assert!(!alice
+        .outbound_session("!room:matrix.org")
+        .unwrap()
+        .is_shared_with("@bob:matrix.org", "BOBDEVICE"));
+    let withheld = server.requests("/_matrix/client/r0/sendToDevice/m.room_key.withheld/");
+    assert_eq!(withheld.len(), 1);
+    assert_eq!(
+        withheld[0]["messages"]["@bob:matrix.org"]["BOBDEVICE"]["code"],
+        "m.unverified"
+    );
+
+    // Once blacklisted, Bob's device is left out whatever the strategy.
+    alice.sharing_strategy = SharingStrategy::AllDevices;
+    alice
+        .set_device_trust("@bob:matrix.org", "BOBDEVICE", TrustState::Blacklisted)
+        .unwrap();
+    alice
+        .send_encrypted_message("!room:matrix.org", "not for bob")
+        .await
+        .unwrap();
+    assert!(!alice
+        .outbound_session("!room:matrix.org")
+        .unwrap()
+        .is_shared_with("@bob:matrix.org", "BOBDEVICE"));
+    let withheld = server.requests("/_matrix/client/r0/sendToDevice/m.room_key.withheld/");
+    assert_eq!(withheld.len(), 2);
+    assert_eq!(
+        withheld[1]["messages"]["@bob:matrix.org"]["BOBDEVICE"]["code"],
+        "m.blacklisted"
+    );
+}
+
+#[tokio::test]
+async fn room_members_and_devices_are_cached_until_sync() {
+    use e2e_matrix::crypto::OneTimeKey;
+
+    let mut bob_account = vodozemac::olm::Account::new();
+    bob_account.generate_one_time_keys(1);
+    let otk = bob_account.one_time_keys().into_values().next().unwrap();
+    let otk = OneTimeKey::new(String::from("AAAAAQ"), otk.to_base64()).sign(
+        &bob_account,
+        String::from("@bob:matrix.org"),
+        String::from("BOBDEVICE"),
+    );
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/rooms/!room:matrix.org/joined_members",
+            200,
+            serde_json::json!({"joined": {"@bob:matrix.org": {}}}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!room:matrix.org/state/m.room.encryption",
+            200,
+            serde_json::json!({"algorithm": "m.megolm.v1.aes-sha2"}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!room:matrix.org/send/",
+            200,
+            serde_json::json!({"event_id": "$event"}),
+        ),
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {"@bob:matrix.org": {
+                "BOBDEVICE": signed_device_key(&bob_account, "@bob:matrix.org", "BOBDEVICE"),
+            }}}),
+        ),
+        (
+            "/_matrix/client/r0/keys/claim",
+            200,
+            serde_json::json!({"one_time_keys": {"@bob:matrix.org": {
+                "BOBDEVICE": {"signed_curve25519:AAAAAQ": otk},
+            }}}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            200,
+            serde_json::json!({}),
+        ),
+    ]);
+    let mut alice = Device::new(
+        String::from("@alice:matrix.org"),
+        String::from("ALICEDEVICE"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+    let requests = || {
+        (
+            server
+                .requests("/_matrix/client/r0/rooms/!room:matrix.org/joined_members")
+                .len(),
+            server.requests("/_matrix/client/r0/keys/query").len(),
+        )
+    };
+    let sync = |changes: serde_json::Value| {
+        let mut response = serde_json::json!({
+            "next_batch": "s1",
+            "device_one_time_keys_count": {"signed_curve25519": 50},
+            "device_unused_fallback_key_types": ["signed_curve25519"],
+        });
+        response
+            .as_object_mut()
+            .unwrap()
+            .extend(changes.as_object().unwrap().clone());
+        serde_json::from_value::<e2e_matrix::response::SyncResponse>(response).unwrap()
+    };
+
+    for body in ["hello", "hello again"] {
+        alice
+            .send_encrypted_message("!room:matrix.org", body)
+            .await
+            .unwrap();
+    }
+    assert_eq!(requests(), (1, 1));
+
+    // A sync that changes nothing keeps both cached.
+    alice
+        .receive_sync_response(&mut sync(serde_json::json!({})))
+        .await
+        .unwrap();
+    alice
+        .send_encrypted_message("!room:matrix.org", "still here")
+        .await
+        .unwrap();
+    assert_eq!(requests(), (1, 1));
+
+    // Bob's devices changed.
+    alice
+        .receive_sync_response(&mut sync(serde_json::json!({
+            "device_lists": {"changed": ["@bob:matrix.org"]},
+        })))
+        .await
+        .unwrap();
+    alice
+        .send_encrypted_message("!room:matrix.org", "new device?")
+        .await
+        .unwrap();
+    assert_eq!(requests(), (1, 2));
+
+    // Someone joined the room.
+    alice
+        .receive_sync_response(&mut sync(serde_json::json!({
+            "rooms": {"join": {"!room:matrix.org": {"timeline": {"events": [{
+                "type": "m.room.member",
+                "state_key": "@carol:matrix.org",
+                "sender": "@carol:matrix.org",
+                "content": {"membership": "join"},
+            }]}}}},
+        })))
+        .await
+        .unwrap();
+    alice
+        .send_encrypted_message("!room:matrix.org", "welcome")
+        .await
+        .unwrap();
+    assert_eq!(requests(), (2, 2));
+}
+
+#[tokio::test]
+async fn withheld_reason_in_decryption_error() {
+    use e2e_matrix::crypto::{MegolmSession, RoomKeyWithheld, WithheldCode};
+
+    let room_id = "!room:matrix.org";
+    let alice_account = vodozemac::olm::Account::new();
+    let alice_curve25519 = alice_account.curve25519_key().to_base64();
+    let server = MockHomeserver::start(vec![(
+        "/_matrix/client/r0/keys/query",
+        200,
+        serde_json::json!({"device_keys": {"@alice:matrix.org": {
+            "ALICEDEVICE": signed_device_key(&alice_account, "@alice:matrix.org", "ALICEDEVICE"),
+        }}}),
+    )]);
+    let mut device = Device::new(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+    let mut outbound = MegolmSession::new(
+        String::from(room_id),
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1()),
+    );
+    let message = outbound.create_message(
+        alice_curve25519.clone(),
+        String::from("ALICEDEVICE"),
+        "secret",
+    );
+    let event = encrypted_room_event("$event", 1, &message);
+    assert!(matches!(
+        device.decrypt_room_event(room_id, &event).await,
+        Err(e2e_matrix::error::Error::UnknownMegolmSession(_))
+    ));
+
+    // Only Alice can tell why she withholds her keys.
+    let no_olm = outbound.withheld_notice(alice_curve25519.clone(), WithheldCode::NoOlm);
+    assert!(no_olm.room_id.is_none() && no_olm.session_id.is_none());
+    assert!(matches!(
+        device
+            .receive_room_key_withheld("@mallory:matrix.org", &no_olm)
+            .await,
+        Err(e2e_matrix::error::Error::UnknownDevice(_))
+    ));
+    assert!(matches!(
+        device.decrypt_room_event(room_id, &event).await,
+        Err(e2e_matrix::error::Error::UnknownMegolmSession(_))
+    ));
+
+    // A device-wide notice applies to every session of that sender.
+    device
+        .receive_room_key_withheld("@alice:matrix.org", &no_olm)
+        .await
+        .unwrap();
+    match device.decrypt_room_event(room_id, &event).await {
+        Err(e2e_matrix::error::Error::KeyWithheld(reason)) => assert!(reason.contains("m.no_olm")),
+        other => panic!("unexpected result: {:?}", other.map(|e| e.event_id)),
+    }
+
+    let withheld: RoomKeyWithheld = serde_json::from_value(serde_json::json!({
+        "algorithm": "m.megolm.v1.aes-sha2",
+        "room_id": room_id,
+        "session_id": outbound.ratchet.session_id(),
+        "sender_key": alice_curve25519,
+        "code": "m.unverified",
+        "reason": "Device not verified",
+    }))
+    .unwrap();
+    device
+        .receive_room_key_withheld("@alice:matrix.org", &withheld)
+        .await
+        .unwrap();
+    match device.decrypt_room_event(room_id, &event).await {
+        Err(e2e_matrix::error::Error::KeyWithheld(reason)) => {
+            assert!(reason.contains("m.unverified") && reason.contains("Device not verified"))
+        }
+        other => panic!("unexpected result: {:?}", other.map(|e| e.event_id)),
+    }
+}
+
+#[tokio::test]
+async fn forwarded_room_key_over_olm() {
+    use e2e_matrix::crypto::{DeviceKey, ForwardedRoomKey, OlmExchange, RoomKeyRequest};
+
+    let alice_account = vodozemac::olm::Account::new();
+    let alice = device_from_account(&alice_account, "@alice:matrix.org", "ALICEDEVICE", &[]);
+    let mut bob_account = vodozemac::olm::Account::new();
+    bob_account.generate_one_time_keys(1);
+    let bob_otk = *bob_account.one_time_keys().values().next().unwrap();
+    let mut bob = device_from_account(
+        &bob_account,
+        "@alice:matrix.org",
+        "BOBDEVICE",
+        &[signed_device_key(
+            &alice_account,
+            "@alice:matrix.org",
+            "ALICEDEVICE",
+        )],
+    );
+    let bob_key = DeviceKey::new(
+        String::from("BOBDEVICE"),
+        String::from("@alice:matrix.org"),
+        bob.curve25519_key(),
+        bob.ed25519_key(),
+    );
+
+    let request: RoomKeyRequest = serde_json::from_value(serde_json::json!({
+        "action": "request",
+        "body": {
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "room_id": "!room:matrix.org",
+            "sender_key": alice.curve25519_key(),
+            "session_id": "session",
+        },
+        "request_id": "1495474790150.19",
+        "requesting_device_id": "BOBDEVICE",
+    }))
+    .unwrap();
+    let body = request.body.unwrap();
+
+    let room_key =
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1());
+    let inbound = vodozemac::megolm::InboundGroupSession::new(
+        &room_key.session_key(),
+        vodozemac::megolm::SessionConfig::version_1(),
+    );
+    let mut olm_session = alice_account.create_outbound_session(
+        vodozemac::olm::SessionConfig::version_1(),
+        bob_account.curve25519_key(),
+        bob_otk,
+    );
+    let exchange = OlmExchange::encrypt_event(
+        &alice,
+        &bob_key,
+        &mut olm_session,
+        "m.forwarded_room_key",
+        ForwardedRoomKey {
+            algorithm: body.algorithm,
+            room_id: body.room_id,
+            sender_key: body.sender_key,
+            session_id: room_key.session_id(),
+            session_key: inbound.export_at_first_known_index().to_base64(),
+            sender_claimed_ed25519_key: alice.ed25519_key(),
+            forwarding_curve25519_key_chain: Vec::new(),
+        },
+    );
+    let event: e2e_matrix::response::ToDeviceEvent = serde_json::from_value(serde_json::json!({
+        "sender": "@alice:matrix.org",
+        "type": "m.room.encrypted",
+        "content": exchange,
+    }))
+    .unwrap();
+
+    let decrypted = bob.decrypt_olm_event(&event).await.unwrap();
+    assert_eq!(decrypted.r#type, "m.forwarded_room_key");
+    let forwarded: ForwardedRoomKey = serde_json::from_value(decrypted.content).unwrap();
+    assert_eq!(forwarded.session_id, room_key.session_id());
+    assert_eq!(forwarded.sender_claimed_ed25519_key, alice.ed25519_key());
+}
+
+#[test]
+fn room_key_forwarding_policy() {
+    use e2e_matrix::crypto::{DeviceKey, RequestedKeyInfo, ShareInfo, WithheldCode};
+    use e2e_matrix::store::{CryptoStore, MemoryStore, TrustState};
+    use std::collections::HashMap;
+
+    let room_id = "!room:matrix.org";
+    let account = vodozemac::olm::Account::new();
+    let sender_key = account.curve25519_key().to_base64();
+    let mut room_key =
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1());
+    let inbound = vodozemac::megolm::InboundGroupSession::new(
+        &room_key.session_key(),
+        vodozemac::megolm::SessionConfig::version_1(),
+    );
+    room_key.encrypt("before Bob joined");
+
+    let key_of = |user_id: &str, device_id: &str| {
+        DeviceKey::new(
+            device_id.to_owned(),
+            user_id.to_owned(),
+            format!("{}_curve25519", device_id),
+            format!("{}_ed25519", device_id),
+        )
+    };
+    let bob_device = key_of("@bob:matrix.org", "BOBDEVICE");
+
+    let mut store = MemoryStore::new();
+    store.save_account(&account).unwrap();
+    store
+        .save_inbound_group_session(room_id, &sender_key, &inbound)
+        .unwrap();
+    store
+        .save_inbound_session_info(
+            room_id,
+            &sender_key,
+            &room_key.session_id(),
+            &account.ed25519_key().to_base64(),
+            &[],
+        )
+        .unwrap();
+    store
+        .save_shared_with(
+            room_id,
+            &room_key.session_id(),
+            &HashMap::from([(
+                String::from("@bob:matrix.org"),
+                HashMap::from([(
+                    String::from("BOBDEVICE"),
+                    ShareInfo {
+                        curve25519_key: bob_device.curve25519_key().unwrap().to_owned(),
+                        message_index: room_key.message_index(),
+                    },
+                )]),
+            )]),
+        )
+        .unwrap();
+    store
+        .save_trust("@bot:matrix.org", "VERIFIED", TrustState::Verified)
+        .unwrap();
+    let device = Device::with_store(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        String::from("https://matrix.org"),
+        Box::new(store),
+    )
+    .unwrap();
+
+    let request = |session_id: String| RequestedKeyInfo {
+        algorithm: String::from("m.megolm.v1.aes-sha2"),
+        room_id: room_id.to_owned(),
+        sender_key: sender_key.clone(),
+        session_id,
+    };
+    let first_known_index = |forwarded: e2e_matrix::crypto::ForwardedRoomKey| {
+        let session_key =
+            vodozemac::megolm::ExportedSessionKey::from_base64(&forwarded.session_key).unwrap();
+        vodozemac::megolm::InboundGroupSession::import(
+            &session_key,
+            vodozemac::megolm::SessionConfig::version_1(),
+        )
+        .first_known_index()
+    };
+
+    // Our own verified device gets everything we have.
+    let forwarded = device
+        .forwardable_key(
+            "@bot:matrix.org",
+            &key_of("@bot:matrix.org", "VERIFIED"),
+            &request(room_key.session_id()),
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(first_known_index(forwarded), 0);
+
+    // Our own unverified device gets nothing.
+    assert_eq!(
+        device
+            .forwardable_key(
+                "@bot:matrix.org",
+                &key_of("@bot:matrix.org", "UNVERIFIED"),
+                &request(room_key.session_id()),
+            )
+            .unwrap()
+            .unwrap_err(),
+        WithheldCode::Unverified
+    );
+
+    // Bob gets the session he was given, from where he got it.
+    let forwarded = device
+        .forwardable_key(
+            "@bob:matrix.org",
+            &bob_device,
+            &request(room_key.session_id()),
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(first_known_index(forwarded), 1);
+
+    // But not from another device, or under another identity key.
+    for other_device in [
+        key_of("@bob:matrix.org", "OTHERDEVICE"),
+        DeviceKey::new(
+            String::from("BOBDEVICE"),
+            String::from("@bob:matrix.org"),
+            String::from("new_curve25519"),
+            String::from("new_ed25519"),
+        ),
+    ] {
+        assert_eq!(
+            device
+                .forwardable_key(
+                    "@bob:matrix.org",
+                    &other_device,
+                    &request(room_key.session_id()),
+                )
+                .unwrap()
+                .unwrap_err(),
+            WithheldCode::Unauthorised
+        );
+    }
+    // Nobody else gets it, and sessions we do not hold are unavailable.
+    assert_eq!(
+        device
+            .forwardable_key(
+                "@mallory:matrix.org",
+                &key_of("@mallory:matrix.org", "BOBDEVICE"),
+                &request(room_key.session_id()),
+            )
+            .unwrap()
+            .unwrap_err(),
+        WithheldCode::Unauthorised
+    );
+    assert_eq!(
+        device
+            .forwardable_key(
+                "@bob:matrix.org",
+                &bob_device,
+                &request(String::from("unknown_session")),
+            )
+            .unwrap()
+            .unwrap_err(),
+        WithheldCode::Unavailable
+    );
+}
+
+#[tokio::test]
+async fn forwarded_room_key_needs_verified_own_device() {
+    use e2e_matrix::crypto::DeviceKey;
+    use e2e_matrix::store::{CryptoStore, MemoryStore, TrustState};
+
+    let room_id = "!room:matrix.org";
+    let forwarder = vodozemac::olm::Account::new();
+    let room_key =
+        vodozemac::megolm::GroupSession::new(vodozemac::megolm::SessionConfig::version_1());
+
+    let device_with_trust = |trust: TrustState| {
+        let mut store = MemoryStore::new();
+        let forwarder_key = DeviceKey::new(
+            String::from("OTHERDEVICE"),
+            String::from("@bot:matrix.org"),
+            forwarder.curve25519_key().to_base64(),
+            forwarder.ed25519_key().to_base64(),
+        );
+        store
+            .save_device_keys(
+                "@bot:matrix.org",
+                &std::collections::HashMap::from([(
+                    String::from("OTHERDEVICE"),
+                    serde_json::to_value(forwarder_key).unwrap(),
+                )]),
+            )
+            .unwrap();
+        store
+            .save_trust("@bot:matrix.org", "OTHERDEVICE", trust)
+            .unwrap();
+        Device::with_store(
+            String::from("@bot:matrix.org"),
+            String::from("PLAYROOM"),
+            String::from("token"),
+            String::from("https://matrix.org"),
+            Box::new(store),
+        )
+        .unwrap()
+    };
+    let forwarded_key_event = |sender: &str| {
+        let mut event: e2e_matrix::crypto::DecryptedOlmEvent =
+            serde_json::from_value(serde_json::json!({
+                "sender": sender,
+                "sender_device": "OTHERDEVICE",
+                "keys": {"ed25519": forwarder.ed25519_key().to_base64()},
+                "recipient": "@bot:matrix.org",
+                "recipient_keys": {"ed25519": "bot_ed25519"},
+                "type": "m.forwarded_room_key",
+                "content": {
+                    "algorithm": "m.megolm.v1.aes-sha2",
+                    "room_id": room_id,
+                    "sender_key": "alice_curve25519",
+                    "session_id": room_key.session_id(),
+                    "session_key": vodozemac::megolm::InboundGroupSession::new(
+                        &room_key.session_key(),
+                        vodozemac::megolm::SessionConfig::version_1(),
+                    )
+                    .export_at_first_known_index()
+                    .to_base64(),
+                    "sender_claimed_ed25519_key": "alice_ed25519",
+                    "forwarding_curve25519_key_chain": [],
+                },
+            }))
+            .unwrap();
+        event.sender_key = forwarder.curve25519_key().to_base64();
+        event
+    };
+
+    // Another user, or our own device before we verified it.
+    let mut device = device_with_trust(TrustState::Unverified);
+    for sender in ["@mallory:matrix.org", "@bot:matrix.org"] {
+        assert!(matches!(
+            device
+                .receive_forwarded_room_key(&forwarded_key_event(sender))
+                .await,
+            Err(e2e_matrix::error::Error::UnknownDevice(_))
+        ));
+    }
+
+    // Keys we never asked for are ignored even from a verified device.
+    let mut device = device_with_trust(TrustState::Verified);
+    assert!(matches!(
+        device
+            .receive_forwarded_room_key(&forwarded_key_event("@bot:matrix.org"))
+            .await,
+        Err(e2e_matrix::error::Error::UnrequestedKey(_))
+    ));
+}
+
+#[tokio::test]
+async fn olm_unwedging_is_rate_limited() {
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let mut store = MemoryStore::new();
+    store
+        .save_last_unwedging("@alice:matrix.org", "alice_curve25519", now - 60_000)
+        .unwrap();
+    let mut device = Device::with_store(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        String::from("https://matrix.org"),
+        Box::new(store),
+    )
+    .unwrap();
+
+    assert!(!device
+        .unwedge_olm_session("@alice:matrix.org", "alice_curve25519")
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn olm_unwedging_only_for_broken_sessions() {
+    use e2e_matrix::crypto::OneTimeKey;
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+    use vodozemac::olm::{Account, SessionConfig};
+
+    let mut bot_account = Account::new();
+    bot_account.generate_one_time_keys(1);
+    let bot_otk = *bot_account.one_time_keys().values().next().unwrap();
+    let bot_curve25519 = bot_account.curve25519_key().to_base64();
+    let mut alice_account = Account::new();
+    alice_account.generate_one_time_keys(1);
+    let alice_otk = *alice_account.one_time_keys().values().next().unwrap();
+    let carol_account = Account::new();
+
+    let encrypted_event = |sender: &str, account: &Account, message: vodozemac::olm::OlmMessage| {
+        let (message_type, body) = message.to_parts();
+        serde_json::from_value::<e2e_matrix::response::ToDeviceEvent>(serde_json::json!({
+            "sender": sender,
+            "type": "m.room.encrypted",
+            "content": {
+                "algorithm": "m.olm.v1.curve25519-aes-sha2",
+                "sender_key": account.curve25519_key().to_base64(),
+                "ciphertext": {&bot_curve25519: {"type": message_type, "body": body}},
+            },
+        }))
+        .unwrap()
+    };
+    // Carol's pre-key message, delivered twice.
+    let pre_key_message = carol_account
+        .create_outbound_session(
+            SessionConfig::version_1(),
+            bot_account.curve25519_key(),
+            bot_otk,
+        )
+        .encrypt(
+            serde_json::json!({
+                "sender": "@carol:matrix.org",
+                "keys": {"ed25519": carol_account.ed25519_key().to_base64()},
+                "recipient": "@bot:matrix.org",
+                "recipient_keys": {"ed25519": bot_account.ed25519_key().to_base64()},
+                "type": "m.dummy",
+                "content": {},
+            })
+            .to_string(),
+        );
+    let replayed = encrypted_event("@carol:matrix.org", &carol_account, pre_key_message);
+    // A message of a session Alice has and we lost.
+    let mut lost_session = Account::new().create_outbound_session(
+        SessionConfig::version_1(),
+        alice_account.curve25519_key(),
+        alice_otk,
+    );
+    let vodozemac::olm::OlmMessage::PreKey(pre_key) = lost_session.encrypt("hello") else {
+        unreachable!()
+    };
+    let mut alice_session = alice_account
+        .create_inbound_session(lost_session.session_keys().identity_key, &pre_key)
+        .unwrap()
+        .session;
+    let wedged = encrypted_event(
+        "@alice:matrix.org",
+        &alice_account,
+        alice_session.encrypt("hello"),
+    );
+
+    alice_account.generate_one_time_keys(1);
+    let (key_id, otk) = alice_account.one_time_keys().into_iter().next().unwrap();
+    let otk = OneTimeKey::new(key_id.to_base64(), otk.to_base64()).sign(
+        &alice_account,
+        String::from("@alice:matrix.org"),
+        String::from("ALICEDEVICE"),
+    );
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {
+                "@alice:matrix.org": {"ALICEDEVICE": signed_device_key(
+                    &alice_account,
+                    "@alice:matrix.org",
+                    "ALICEDEVICE",
+                )},
+                "@carol:matrix.org": {"CAROLDEVICE": signed_device_key(
+                    &carol_account,
+                    "@carol:matrix.org",
+                    "CAROLDEVICE",
+                )},
+            }}),
+        ),
+        (
+            "/_matrix/client/r0/keys/claim",
+            200,
+            serde_json::json!({"one_time_keys": {"@alice:matrix.org": {
+                "ALICEDEVICE": {format!("signed_curve25519:{}", key_id.to_base64()): otk},
+            }}}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            500,
+            serde_json::json!({"errcode": "M_UNKNOWN"}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            200,
+            serde_json::json!({}),
+        ),
+    ]);
+    let mut store = MemoryStore::new();
+    store.save_account(&bot_account).unwrap();
+    let mut bot = Device::with_store(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        server.uri.clone(),
+        Box::new(store),
+    )
+    .unwrap();
+    let sync = |event: &e2e_matrix::response::ToDeviceEvent| {
+        let mut response: e2e_matrix::response::SyncResponse =
+            serde_json::from_value(serde_json::json!({
+                "next_batch": "s1",
+                "device_one_time_keys_count": {"signed_curve25519": 50},
+                "device_unused_fallback_key_types": ["signed_curve25519"],
+            }))
+            .unwrap();
+        response.to_device.events.push(event.clone());
+        response
+    };
+    let dummies_sent = || {
+        server
+            .requests("/_matrix/client/r0/sendToDevice/m.room.encrypted/")
+            .len()
+    };
+    // A replayed message is not a broken session.
+    for _ in 0..2 {
+        bot.receive_sync_response(&mut sync(&replayed))
+            .await
+            .unwrap();
+    }
+    assert!(server.requests("/_matrix/client/r0/keys/claim").is_empty());
+
+    // The first m.dummy doesn't go out, so the next broken message retries.
+    bot.receive_sync_response(&mut sync(&wedged)).await.unwrap();
+    assert_eq!(dummies_sent(), 1);
+    bot.receive_sync_response(&mut sync(&wedged)).await.unwrap();
+    assert_eq!(dummies_sent(), 2);
+    bot.receive_sync_response(&mut sync(&wedged)).await.unwrap();
+    assert_eq!(dummies_sent(), 2);
+}
+
+/// The self-signed device key of `account`, as `/keys/query` returns it.
+fn signed_device_key(
+    account: &vodozemac::olm::Account,
+    user_id: &str,
+    device_id: &str,
+) -> serde_json::Value {
+    let device_key = e2e_matrix::crypto::DeviceKey::new(
+        device_id.to_owned(),
+        user_id.to_owned(),
+        account.curve25519_key().to_base64(),
+        account.ed25519_key().to_base64(),
+    )
+    .sign(account);
+    serde_json::to_value(device_key).unwrap()
+}
+
+/// A device that never talks to a homeserver.
+fn test_device(user_id: &str, device_id: &str) -> Device {
+    Device::new(
+        user_id.to_owned(),
+        device_id.to_owned(),
+        String::from("token"),
+        String::from("https://matrix.org"),
+    )
+}
+
+fn device_key_of(device: &Device) -> e2e_matrix::crypto::DeviceKey {
+    e2e_matrix::crypto::DeviceKey::new(
+        device.device_id.clone(),
+        device.user_id.clone(),
+        device.curve25519_key(),
+        device.ed25519_key(),
+    )
+}
+
+/// Delivers the messages of one side to the other until both go quiet.
+fn exchange_verification_messages(
+    a: &mut e2e_matrix::crypto::Verification,
+    b: &mut e2e_matrix::crypto::Verification,
+    messages: Vec<(String, serde_json::Value)>,
+) {
+    let mut pending = messages;
+    let (mut from, mut to) = (a, b);
+    while !pending.is_empty() {
+        let mut replies = Vec::new();
+        for (event_type, content) in pending {
+            replies.extend(to.receive(&event_type, &content));
+        }
+        pending = replies;
+        std::mem::swap(&mut from, &mut to);
+    }
+}
+
+#[test]
+fn sas_verification_flow() {
+    use e2e_matrix::crypto::{Verification, VerificationState};
+
+    let alice = test_device("@alice:matrix.org", "ALICEDEVICE");
+    let bob = test_device("@bob:matrix.org", "BOBDEVICE");
+
+    let mut alice_flow = Verification::new(
+        &alice,
+        String::from("flow"),
+        bob.user_id.clone(),
+        Some(bob.device_id.clone()),
+        true,
+    );
+    alice_flow.set_other_device(device_key_of(&bob));
+    let (_, request) = alice_flow.request();
+    assert_eq!(request["transaction_id"], "flow");
+
+    let mut bob_flow = Verification::new(
+        &bob,
+        String::from("flow"),
+        alice.user_id.clone(),
+        request["from_device"].as_str().map(str::to_owned),
+        false,
+    );
+    bob_flow.set_other_device(device_key_of(&alice));
+
+    // ready -> start -> accept -> key -> key
+    let ready = bob_flow.accept();
+    exchange_verification_messages(&mut bob_flow, &mut alice_flow, ready);
+    assert_eq!(alice_flow.state, VerificationState::KeysExchanged);
+    assert_eq!(bob_flow.state, VerificationState::KeysExchanged);
+    assert_eq!(alice_flow.emoji().unwrap().len(), 7);
+    assert_eq!(alice_flow.emoji(), bob_flow.emoji());
+    assert_eq!(alice_flow.decimals(), bob_flow.decimals());
+
+    let alice_mac = alice_flow.confirm();
+    exchange_verification_messages(&mut alice_flow, &mut bob_flow, alice_mac);
+    assert!(!alice_flow.is_verified() && !bob_flow.is_verified());
+
+    let bob_mac = bob_flow.confirm();
+    exchange_verification_messages(&mut bob_flow, &mut alice_flow, bob_mac);
+    assert!(alice_flow.is_verified() && bob_flow.is_verified());
+    assert_eq!(alice_flow.state, VerificationState::Done);
+    assert_eq!(bob_flow.state, VerificationState::Done);
+}
+
+#[tokio::test]
+async fn verification_requests_are_checked() {
+    use e2e_matrix::crypto::VerificationState;
+
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {}}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            200,
+            serde_json::json!({}),
+        ),
+    ]);
+    let mut bot = Device::new(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let request = |flow_id: &str, timestamp: Option<u64>| {
+        let mut content = serde_json::json!({
+            "from_device": "ALICEDEVICE",
+            "methods": ["m.sas.v1"],
+            "transaction_id": flow_id,
+        });
+        if let Some(timestamp) = timestamp {
+            content["timestamp"] = serde_json::json!(timestamp);
+        }
+        content
+    };
+
+    bot.receive_verification_event(
+        "@alice:matrix.org",
+        "m.key.verification.request",
+        &request("flow", Some(now)),
+    )
+    .await
+    .unwrap();
+    bot.accept_verification("flow").await.unwrap();
+    assert_eq!(
+        bot.verification("flow").unwrap().state,
+        VerificationState::Ready
+    );
+
+    // The same flow ID doesn't start over.
+    bot.receive_verification_event(
+        "@mallory:matrix.org",
+        "m.key.verification.request",
+        &request("flow", Some(now)),
+    )
+    .await
+    .unwrap();
+    let verification = bot.verification("flow").unwrap();
+    assert_eq!(verification.other_user_id, "@alice:matrix.org");
+    assert_eq!(verification.state, VerificationState::Ready);
+
+    // Requests from more than 10 minutes ago, more than 5 minutes ahead or
+    // without a timestamp are dropped.
+    for (flow_id, timestamp) in [
+        ("old", Some(now - 11 * 60_000)),
+        ("future", Some(now + 6 * 60_000)),
+        ("undated", None),
+    ] {
+        bot.receive_verification_event(
+            "@alice:matrix.org",
+            "m.key.verification.request",
+            &request(flow_id, timestamp),
+        )
+        .await
+        .unwrap();
+        assert!(bot.verification(flow_id).is_none());
+    }
+}
+
+#[test]
+fn sas_verification_in_room() {
+    use e2e_matrix::crypto::{Verification, VerificationState};
+
+    let alice = test_device("@alice:matrix.org", "ALICEDEVICE");
+    let bob = test_device("@bob:matrix.org", "BOBDEVICE");
+
+    let mut alice_flow =
+        Verification::new(&alice, String::new(), bob.user_id.clone(), None, true).in_room("!dm");
+    let (event_type, request) = alice_flow.request();
+    assert_eq!(event_type, "m.room.message");
+    assert_eq!(request["msgtype"], "m.key.verification.request");
+    assert_eq!(request["to"], bob.user_id.as_str());
+    // The event ID of the request identifies the flow.
+    alice_flow.flow_id = String::from("$request");
+
+    let mut bob_flow = Verification::new(
+        &bob,
+        String::from("$request"),
+        alice.user_id.clone(),
+        request["from_device"].as_str().map(str::to_owned),
+        false,
+    )
+    .in_room("!dm");
+    bob_flow.set_other_device(device_key_of(&alice));
+
+    let ready = bob_flow.accept();
+    for (_, content) in &ready {
+        assert_eq!(content["m.relates_to"]["rel_type"], "m.reference");
+        assert_eq!(content["m.relates_to"]["event_id"], "$request");
+        assert!(content.get("transaction_id").is_none());
+    }
+    alice_flow.set_other_device(device_key_of(&bob));
+    exchange_verification_messages(&mut bob_flow, &mut alice_flow, ready);
+    assert_eq!(alice_flow.emoji(), bob_flow.emoji());
+
+    let alice_mac = alice_flow.confirm();
+    exchange_verification_messages(&mut alice_flow, &mut bob_flow, alice_mac);
+    let bob_mac = bob_flow.confirm();
+    exchange_verification_messages(&mut bob_flow, &mut alice_flow, bob_mac);
+    assert_eq!(alice_flow.state, VerificationState::Done);
+    assert_eq!(bob_flow.state, VerificationState::Done);
+}
+
+#[tokio::test]
+async fn room_verification_events_are_routed() {
+    use e2e_matrix::crypto::{DecryptedRoomEvent, OneTimeKey, VerificationState};
+
+    let mut alice_account = vodozemac::olm::Account::new();
+    alice_account.generate_one_time_keys(1);
+    let (key_id, otk) = alice_account.one_time_keys().into_iter().next().unwrap();
+    let otk = OneTimeKey::new(key_id.to_base64(), otk.to_base64()).sign(
+        &alice_account,
+        String::from("@alice:matrix.org"),
+        String::from("ALICEDEVICE"),
+    );
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/rooms/!dm:matrix.org/joined_members",
+            200,
+            serde_json::json!({"joined": {"@alice:matrix.org": {}}}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!dm:matrix.org/state/m.room.encryption",
+            200,
+            serde_json::json!({"algorithm": "m.megolm.v1.aes-sha2"}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!dm:matrix.org/send/",
+            200,
+            serde_json::json!({"event_id": "$request"}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!dm:matrix.org/send/",
+            200,
+            serde_json::json!({"event_id": "$start"}),
+        ),
+        (
+            "/_matrix/client/r0/rooms/!dm:matrix.org/send/",
+            200,
+            serde_json::json!({}),
+        ),
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({"device_keys": {
+                "@alice:matrix.org": {"ALICEDEVICE": signed_device_key(
+                    &alice_account,
+                    "@alice:matrix.org",
+                    "ALICEDEVICE",
+                )},
+            }}),
+        ),
+        (
+            "/_matrix/client/r0/keys/claim",
+            200,
+            serde_json::json!({"one_time_keys": {"@alice:matrix.org": {
+                "ALICEDEVICE": {format!("signed_curve25519:{}", key_id.to_base64()): otk},
+            }}}),
+        ),
+        (
+            "/_matrix/client/r0/sendToDevice/",
+            200,
+            serde_json::json!({}),
+        ),
+    ]);
+    let mut bot = Device::new(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        server.uri.clone(),
+    );
+    let event = |event_id: &str, sender: &str, r#type: &str, content: serde_json::Value| {
+        DecryptedRoomEvent {
+            event_id: event_id.to_owned(),
+            room_id: String::from("!dm:matrix.org"),
+            sender: sender.to_owned(),
+            sender_key: alice_account.curve25519_key().to_base64(),
+            session_id: String::from("session"),
+            message_index: 0,
+            r#type: r#type.to_owned(),
+            content,
+        }
+    };
+    let ready = |event_id: &str| {
+        serde_json::json!({
+            "from_device": "ALICEDEVICE",
+            "methods": ["m.sas.v1"],
+            "m.relates_to": {"rel_type": "m.reference", "event_id": event_id},
+        })
+    };
+
+    let flow_id = bot
+        .request_verification_in_room("!dm:matrix.org", "@alice:matrix.org")
+        .await
+        .unwrap();
+    assert_eq!(flow_id, "$request");
+
+    // Answers to another flow, or from somebody else, are ignored.
+    for (sender, related_event_id) in [
+        ("@alice:matrix.org", "$unknown"),
+        ("@mallory:matrix.org", "$request"),
+    ] {
+        bot.receive_room_verification_event(&event(
+            "$ready",
+            sender,
+            "m.key.verification.ready",
+            ready(related_event_id),
+        ))
+        .await
+        .unwrap();
+        let verification = bot.verification("$request").unwrap();
+        assert_eq!(verification.state, VerificationState::Requested);
+        assert!(verification.other_device().is_none());
+    }
+
+    // The device that answers is the one we verify, and SAS starts right
+    // away since it's the only method both sides know.
+    bot.receive_room_verification_event(&event(
+        "$ready",
+        "@alice:matrix.org",
+        "m.key.verification.ready",
+        ready("$request"),
+    ))
+    .await
+    .unwrap();
+    let verification = bot.verification("$request").unwrap();
+    assert_eq!(verification.state, VerificationState::Started);
+    assert_eq!(
+        verification.other_device().unwrap().device_id,
+        "ALICEDEVICE"
+    );
+
+    // The relation is visible to the server.
+    let sent = server.requests("/_matrix/client/r0/rooms/!dm:matrix.org/send/");
+    assert!(sent[0].get("m.relates_to").is_none());
+    assert_eq!(
+        sent[1]["m.relates_to"],
+        serde_json::json!({"rel_type": "m.reference", "event_id": "$request"})
+    );
+
+    // Requests addressed to somebody else are not ours to answer.
+    for (event_id, to) in [
+        ("$other", "@carol:matrix.org"),
+        ("$ours", "@bot:matrix.org"),
+    ] {
+        bot.receive_room_verification_event(&event(
+            event_id,
+            "@alice:matrix.org",
+            "m.room.message",
+            serde_json::json!({
+                "msgtype": "m.key.verification.request",
+                "body": "Alice wants to verify",
+                "from_device": "ALICEDEVICE",
+                "methods": ["m.sas.v1"],
+                "to": to,
+            }),
+        ))
+        .await
+        .unwrap();
+    }
+    assert!(bot.verification("$other").is_none());
+    assert!(bot.verification("$ours").is_some());
+
+    // A send the server didn't give an event ID for failed.
+    assert!(matches!(
+        bot.send_encrypted_event(
+            "!dm:matrix.org",
+            "m.room.message",
+            serde_json::json!({"msgtype": "m.text", "body": "hello"}),
+        )
+        .await,
+        Err(e2e_matrix::error::Error::ApiError(_))
+    ));
+}
+
+#[test]
+fn sas_verification_rejects_wrong_device_key() {
+    use e2e_matrix::crypto::{Verification, VerificationState};
+
+    let alice = test_device("@alice:matrix.org", "ALICEDEVICE");
+    let bob = test_device("@bob:matrix.org", "BOBDEVICE");
+    let impostor = test_device("@bob:matrix.org", "BOBDEVICE");
+
+    let mut alice_flow = Verification::new(
+        &alice,
+        String::from("flow"),
+        bob.user_id.clone(),
+        Some(bob.device_id.clone()),
+        true,
+    );
+    // Alice expects bob's keys, but talks to someone else holding the same
+    // device ID.
+    alice_flow.set_other_device(device_key_of(&bob));
+    let mut impostor_flow = Verification::new(
+        &impostor,
+        String::from("flow"),
+        alice.user_id.clone(),
+        Some(alice.device_id.clone()),
+        false,
+    );
+    impostor_flow.set_other_device(device_key_of(&alice));
+
+    let ready = impostor_flow.accept();
+    exchange_verification_messages(&mut impostor_flow, &mut alice_flow, ready);
+    let alice_mac = alice_flow.confirm();
+    exchange_verification_messages(&mut alice_flow, &mut impostor_flow, alice_mac);
+    let impostor_mac = impostor_flow.confirm();
+    exchange_verification_messages(&mut impostor_flow, &mut alice_flow, impostor_mac);
+
+    assert!(!alice_flow.is_verified());
+    assert!(matches!(
+        &alice_flow.state,
+        VerificationState::Cancelled { code, .. } if code == "m.key_mismatch"
+    ));
+}
+
+#[test]
+fn qr_code_payload_roundtrip() {
+    use e2e_matrix::crypto::{QrCode, QrMode};
+
+    let first_key = vodozemac::Ed25519Keypair::new().public_key().to_base64();
+    let second_key = vodozemac::Ed25519Keypair::new().public_key().to_base64();
+    let qr_code = QrCode::new(
+        QrMode::SelfVerifyingMasterKeyUntrusted,
+        String::from("flow"),
+        first_key,
+        second_key,
+    );
+
+    let bytes = qr_code.to_bytes().unwrap();
+    assert_eq!(&bytes[..10], b"MATRIX\x02\x02\x00\x04");
+    assert_eq!(QrCode::from_bytes(&bytes).unwrap(), qr_code);
+    assert!(QrCode::from_bytes(&bytes[..bytes.len() - 10]).is_err());
+    assert!(QrCode::from_bytes(b"NOTMATRIX").is_err());
+
+    assert!(qr_code.to_png().unwrap().starts_with(b"\x89PNG"));
+    assert!(!qr_code.to_terminal().unwrap().is_empty());
+}
+
+#[test]
+fn qr_code_verification_flow() {
+    use e2e_matrix::crypto::{QrCode, Verification, VerificationState};
+
+    let alice = test_device("@alice:matrix.org", "ALICEDEVICE");
+    let bob = test_device("@bob:matrix.org", "BOBDEVICE");
+    let alice_master = vodozemac::Ed25519Keypair::new().public_key().to_base64();
+    let bob_master = vodozemac::Ed25519Keypair::new().public_key().to_base64();
+
+    let mut alice_flow = Verification::new(
+        &alice,
+        String::from("flow"),
+        bob.user_id.clone(),
+        Some(bob.device_id.clone()),
+        true,
+    );
+    alice_flow.set_other_device(device_key_of(&bob));
+    // QR codes are only offered once the master keys are known.
+    assert_eq!(
+        alice_flow.request().1["methods"],
+        serde_json::json!(["m.sas.v1"])
+    );
+    alice_flow.set_master_keys(Some(alice_master.clone()), true, Some(bob_master.clone()));
+    assert_eq!(alice_flow.request().1["methods"][3], "m.reciprocate.v1");
+    let mut bob_flow = Verification::new(
+        &bob,
+        String::from("flow"),
+        alice.user_id.clone(),
+        Some(alice.device_id.clone()),
+        false,
+    );
+    bob_flow.set_other_device(device_key_of(&alice));
+    bob_flow.set_master_keys(Some(bob_master.clone()), true, Some(alice_master.clone()));
+
+    // Alice starts SAS on ready, but Bob shows a QR code she scans instead.
+    let (_, ready) = bob_flow.accept().remove(0);
+    alice_flow.receive("m.key.verification.ready", &ready);
+    assert_eq!(alice_flow.state, VerificationState::Started);
+    let scanned = bob_flow.show_qr_code().unwrap().to_bytes().unwrap();
+
+    let reciprocate = alice_flow.scan_qr_code(&QrCode::from_bytes(&scanned).unwrap());
+    assert_eq!(reciprocate[0].1["method"], "m.reciprocate.v1");
+    exchange_verification_messages(&mut alice_flow, &mut bob_flow, reciprocate);
+    assert_eq!(bob_flow.state, VerificationState::Reciprocated);
+    assert!(!alice_flow.is_verified() && !bob_flow.is_verified());
+
+    let done = bob_flow.confirm();
+    exchange_verification_messages(&mut bob_flow, &mut alice_flow, done);
+    assert!(alice_flow.is_verified() && bob_flow.is_verified());
+    // Verifying another user vouches for the master keys, not the devices.
+    assert!(!alice_flow.is_device_verified() && !bob_flow.is_device_verified());
+    assert_eq!(alice_flow.verified_master_key(), Some(bob_master.as_str()));
+    assert_eq!(bob_flow.verified_master_key(), Some(alice_master.as_str()));
+    assert_eq!(alice_flow.state, VerificationState::Done);
+    assert_eq!(bob_flow.state, VerificationState::Done);
+
+    // A code with the wrong secret is rejected.
+    let mut forged = QrCode::from_bytes(&scanned).unwrap();
+    forged.shared_secret = vec![0; 16];
+    let mut bob_flow = Verification::new(
+        &bob,
+        String::from("flow"),
+        alice.user_id.clone(),
+        Some(alice.device_id.clone()),
+        false,
+    );
+    bob_flow.set_master_keys(Some(bob_master), true, Some(alice_master));
+    bob_flow.accept();
+    bob_flow.show_qr_code().unwrap();
+    bob_flow.receive(
+        "m.key.verification.start",
+        &serde_json::json!({"method": "m.reciprocate.v1", "secret": forged.secret()}),
+    );
+    assert!(bob_flow.is_cancelled());
+}
+
+#[tokio::test]
+async fn qr_code_self_verification_trusts_master_key() {
+    use e2e_matrix::crypto::{CrossSigningIdentity, QrMode, Verification};
+    use e2e_matrix::device::DeviceTrust;
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+    use std::collections::HashMap;
+
+    let server = MockHomeserver::start(vec![(
+        "/_matrix/client/r0/sendToDevice/",
+        200,
+        serde_json::json!({}),
+    )]);
+    let other = test_device("@bot:matrix.org", "OTHERDEVICE");
+    let identity = CrossSigningIdentity::new(String::from("@bot:matrix.org"));
+    let master_key = identity.master_key().ed25519_key().unwrap().to_owned();
+
+    // We know our keys from the server but don't trust the master key yet.
+    let mut store = MemoryStore::new();
+    store
+        .save_device_keys(
+            "@bot:matrix.org",
+            &HashMap::from([(
+                other.device_id.clone(),
+                serde_json::to_value(identity.sign_device(device_key_of(&other)).unwrap()).unwrap(),
+            )]),
+        )
+        .unwrap();
+    store
+        .save_cross_signing_keys(
+            "@bot:matrix.org",
+            &HashMap::from([
+                (
+                    String::from("master"),
+                    serde_json::to_value(identity.master_key()).unwrap(),
+                ),
+                (
+                    String::from("self_signing"),
+                    serde_json::to_value(identity.self_signing_key().unwrap()).unwrap(),
+                ),
+            ]),
+        )
+        .unwrap();
+    let mut bot = Device::with_store(
+        String::from("@bot:matrix.org"),
+        String::from("PLAYROOM"),
+        String::from("token"),
+        server.uri.clone(),
+        Box::new(store),
+    )
+    .unwrap();
+    assert_eq!(
+        bot.device_trust("@bot:matrix.org", "OTHERDEVICE").unwrap(),
+        DeviceTrust::Unverified
+    );
+
+    let flow_id = bot
+        .request_verification("@bot:matrix.org", "OTHERDEVICE")
+        .await
+        .unwrap();
+    let mut other_flow = Verification::new(
+        &other,
+        flow_id.clone(),
+        bot.user_id.clone(),
+        Some(bot.device_id.clone()),
+        false,
+    );
+    other_flow.set_other_device(device_key_of(&bot));
+    other_flow.set_master_keys(Some(master_key.clone()), true, Some(master_key.clone()));
+    let (_, ready) = other_flow.accept().remove(0);
+    bot.receive_verification_event("@bot:matrix.org", "m.key.verification.ready", &ready)
+        .await
+        .unwrap();
+
+    // The other device trusts the master key and shows it with our key.
+    let qr_code = other_flow.show_qr_code().unwrap();
+    assert_eq!(qr_code.mode, QrMode::SelfVerifyingMasterKeyTrusted);
+    bot.scan_qr_code(&qr_code.to_bytes().unwrap())
+        .await
+        .unwrap();
+    let reciprocate = server
+        .requests("/_matrix/client/r0/sendToDevice/m.key.verification.start/")
+        .pop()
+        .unwrap()["messages"]["@bot:matrix.org"]["OTHERDEVICE"]
+        .clone();
+    assert_eq!(reciprocate["method"], "m.reciprocate.v1");
+    other_flow.receive("m.key.verification.start", &reciprocate);
+    let (_, done) = other_flow.confirm().remove(0);
+    assert!(other_flow.is_device_verified());
+    assert!(other_flow.verified_master_key().is_none());
+
+    // We learn that the master key is ours, not that the other device is.
+    bot.receive_verification_event("@bot:matrix.org", "m.key.verification.done", &done)
+        .await
+        .unwrap();
+    let verification = bot.verification(&flow_id).unwrap();
+    assert!(verification.is_verified() && !verification.is_device_verified());
+    assert_eq!(
+        verification.verified_master_key(),
+        Some(master_key.as_str())
+    );
+    assert_eq!(
+        bot.device_trust("@bot:matrix.org", "OTHERDEVICE").unwrap(),
+        DeviceTrust::CrossSigned
+    );
+
+    // There are no MACs in a QR code flow.
+    other_flow.receive(
+        "m.key.verification.mac",
+        &serde_json::json!({"transaction_id": flow_id, "mac": {}, "keys": ""}),
+    );
+    assert!(matches!(
+        &other_flow.state,
+        e2e_matrix::crypto::VerificationState::Cancelled { code, .. } if code == "m.unexpected_message"
+    ));
+}
+
+#[test]
+fn cross_signing_keys_are_signed_and_stored() {
+    use e2e_matrix::crypto::signature::verify_json;
+    use e2e_matrix::crypto::CrossSigningIdentity;
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+
+    let device = test_device("@alice:matrix.org", "ALICEDEVICE");
+    let identity = CrossSigningIdentity::new(device.user_id.clone());
+    let master_key = identity.master_key();
+    let master_ed25519 = master_key.ed25519_key().unwrap().to_owned();
+    let master_key_id = format!("ed25519:{}", master_ed25519);
+
+    for key in [
+        identity.self_signing_key().unwrap(),
+        identity.user_signing_key().unwrap(),
+    ] {
+        verify_json(
+            &key,
+            key.signatures.as_ref(),
+            &device.user_id,
+            &master_key_id,
+            &master_ed25519,
+        )
+        .unwrap();
+    }
+
+    let self_signing_key = identity.self_signing_key().unwrap();
+    let self_signing_ed25519 = self_signing_key.ed25519_key().unwrap();
+    let device_key = identity.sign_device(device_key_of(&device)).unwrap();
+    verify_json(
+        &device_key,
+        device_key.signatures.as_ref(),
+        &device.user_id,
+        &format!("ed25519:{}", self_signing_ed25519),
+        self_signing_ed25519,
+    )
+    .unwrap();
+
+    let mut store = MemoryStore::new();
+    assert!(store.load_cross_signing_identity().unwrap().is_none());
+    store.save_cross_signing_identity(&identity).unwrap();
+    let restored = store.load_cross_signing_identity().unwrap().unwrap();
+    assert_eq!(
+        restored.master_key().ed25519_key(),
+        Some(master_ed25519.as_str())
+    );
+}
+
+#[tokio::test]
+async fn cross_signing_bootstrap_authenticates() {
+    use e2e_matrix::crypto::CrossSigningIdentity;
+
+    let upload = "/_matrix/client/r0/keys/device_signing/upload";
+    let server = |stage: &str, master_keys: serde_json::Value| {
+        MockHomeserver::start(vec![
+            (
+                "/_matrix/client/r0/keys/query",
+                200,
+                serde_json::json!({"device_keys": {}, "master_keys": master_keys}),
+            ),
+            (
+                upload,
+                401,
+                serde_json::json!({"session": "uia", "flows": [{"stages": [stage]}]}),
+            ),
+            (upload, 200, serde_json::json!({})),
+            (
+                "/_matrix/client/r0/keys/signatures/upload",
+                200,
+                serde_json::json!({"failures": {}}),
+            ),
+        ])
+    };
+    let device = |server: &MockHomeserver| {
+        Device::new(
+            String::from("@bot:matrix.org"),
+            String::from("PLAYROOM"),
+            String::from("token"),
+            server.uri.clone(),
+        )
+    };
+
+    // The password answers the server's challenge on the second attempt.
+    let password_server = server("m.login.password", serde_json::json!({}));
+    let mut bot = device(&password_server);
+    bot.bootstrap_cross_signing(Some("hunter2")).await.unwrap();
+    let uploads = password_server.requests(upload);
+    assert_eq!(uploads.len(), 2);
+    assert!(uploads[0].get("auth").is_none());
+    assert_eq!(
+        uploads[1]["auth"],
+        serde_json::json!({
+            "type": "m.login.password",
+            "identifier": {"type": "m.id.user", "user": "@bot:matrix.org"},
+            "password": "hunter2",
+            "session": "uia",
+        })
+    );
+    let master_key = bot.master_key().unwrap();
+    assert_eq!(
+        uploads[1]["master_key"]["keys"][format!("ed25519:{}", master_key)],
+        master_key.as_str()
+    );
+    let signed = password_server.requests("/_matrix/client/r0/keys/signatures/upload");
+    assert!(
+        signed[0]["@bot:matrix.org"]["PLAYROOM"]["signatures"]["@bot:matrix.org"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .any(|key_id| key_id.starts_with("ed25519:") && key_id != "ed25519:PLAYROOM")
+    );
+
+    // Without a password only a dummy stage can be completed.
+    let dummy_server = server("m.login.dummy", serde_json::json!({}));
+    device(&dummy_server)
+        .bootstrap_cross_signing(None)
+        .await
+        .unwrap();
+    assert_eq!(
+        dummy_server.requests(upload)[1]["auth"],
+        serde_json::json!({"type": "m.login.dummy", "session": "uia"})
+    );
+    assert!(matches!(
+        device(&server("m.login.password", serde_json::json!({})))
+            .bootstrap_cross_signing(None)
+            .await,
+        Err(e2e_matrix::error::Error::AuthRequired(_))
+    ));
+
+    // Keys another device created are not replaced unless asked to.
+    let existing = CrossSigningIdentity::new(String::from("@bot:matrix.org")).master_key();
+    let existing_server = server(
+        "m.login.dummy",
+        serde_json::json!({"@bot:matrix.org": existing}),
+    );
+    let mut bot = device(&existing_server);
+    assert!(matches!(
+        bot.bootstrap_cross_signing(None).await,
+        Err(e2e_matrix::error::Error::CrossSigningKeysExist(_))
+    ));
+    assert!(existing_server.requests(upload).is_empty());
+    assert!(bot.master_key().is_none());
+    bot.reset_cross_signing(None).await.unwrap();
+    assert_eq!(existing_server.requests(upload).len(), 2);
+    assert_ne!(bot.master_key().as_deref(), existing.ed25519_key());
+}
+
+#[test]
+fn device_trust_levels() {
+    use e2e_matrix::crypto::CrossSigningIdentity;
+    use e2e_matrix::device::DeviceTrust;
+    use e2e_matrix::store::{CryptoStore, MemoryStore, TrustState};
+    use std::collections::HashMap;
+
+    let bob = test_device("@bob:matrix.org", "BOBDEVICE");
+    let alice_identity = CrossSigningIdentity::new(String::from("@alice:matrix.org"));
+    let bob_identity = CrossSigningIdentity::new(bob.user_id.clone());
+    let bob_master = bob_identity.master_key();
+
+    // Alice's store knows Bob's device and keys; `tweak` changes it per case.
+    let trust = |signed_device: bool, tweak: &dyn Fn(&mut MemoryStore)| {
+        let mut store = MemoryStore::new();
+        store.save_cross_signing_identity(&alice_identity).unwrap();
+        let device_key = match signed_device {
+            true => bob_identity.sign_device(device_key_of(&bob)).unwrap(),
+            false => device_key_of(&bob),
+        };
+        store
+            .save_device_keys(
+                &bob.user_id,
+                &HashMap::from([(
+                    bob.device_id.clone(),
+                    serde_json::to_value(device_key).unwrap(),
+                )]),
+            )
+            .unwrap();
+        store
+            .save_cross_signing_keys(
+                &bob.user_id,
+                &HashMap::from([
+                    (
+                        String::from("master"),
+                        serde_json::to_value(&bob_master).unwrap(),
+                    ),
+                    (
+                        String::from("self_signing"),
+                        serde_json::to_value(bob_identity.self_signing_key().unwrap()).unwrap(),
+                    ),
+                ]),
+            )
+            .unwrap();
+        store
+            .save_pinned_master_key(&bob.user_id, bob_master.ed25519_key().unwrap())
+            .unwrap();
+        tweak(&mut store);
+
+        Device::with_store(
+            String::from("@alice:matrix.org"),
+            String::from("ALICEDEVICE"),
+            String::from("token"),
+            String::from("https://matrix.org"),
+            Box::new(store),
+        )
+        .unwrap()
+        .device_trust(&bob.user_id, &bob.device_id)
+        .unwrap()
+    };
+
+    assert_eq!(trust(true, &|_| {}), DeviceTrust::Pinned);
+    assert_eq!(trust(false, &|_| {}), DeviceTrust::Unverified);
+    assert_eq!(
+        trust(true, &|store| {
+            let other_key = vodozemac::Ed25519Keypair::new().public_key().to_base64();
+            store
+                .save_pinned_master_key("@bob:matrix.org", &other_key)
+                .unwrap();
+        }),
+        DeviceTrust::Unverified
+    );
+    assert_eq!(
+        trust(true, &|store| {
+            let mut keys = store
+                .load_signed_cross_signing_keys("@bob:matrix.org")
+                .unwrap();
+            let master = serde_json::from_value(keys["master"].clone()).unwrap();
+            let signed_master = alice_identity.sign_user(master).unwrap();
+            keys.insert(
+                String::from("master"),
+                serde_json::to_value(signed_master).unwrap(),
+            );
+            store
+                .save_cross_signing_keys("@bob:matrix.org", &keys)
+                .unwrap();
+        }),
+        DeviceTrust::CrossSigned
+    );
+    assert_eq!(
+        trust(false, &|store| {
+            store
+                .save_trust("@bob:matrix.org", "BOBDEVICE", TrustState::Verified)
+                .unwrap();
+        }),
+        DeviceTrust::Verified
+    );
+}
+
+#[tokio::test]
+async fn queried_cross_signing_keys_are_checked() {
+    use e2e_matrix::crypto::signature::canonical_json;
+    use e2e_matrix::crypto::{CrossSigningIdentity, DeviceKey};
+    use e2e_matrix::device::DeviceTrust;
+    use e2e_matrix::store::{CryptoStore, MemoryStore};
+    use vodozemac::olm::Account;
+
+    let cross_signed_device =
+        |identity: &CrossSigningIdentity, account: &Account, user_id: &str, device_id: &str| {
+            let device_key = DeviceKey::new(
+                device_id.to_owned(),
+                user_id.to_owned(),
+                account.curve25519_key().to_base64(),
+                account.ed25519_key().to_base64(),
+            )
+            .sign(account);
+            serde_json::to_value(identity.sign_device(device_key).unwrap()).unwrap()
+        };
+    let alice_account = Account::new();
+    let (alice_identity, alice_other_account) = (
+        CrossSigningIdentity::new(String::from("@alice:matrix.org")),
+        Account::new(),
+    );
+    let bob_account = Account::new();
+    let (bob_identity, new_bob_identity) = (
+        CrossSigningIdentity::new(String::from("@bob:matrix.org")),
+        CrossSigningIdentity::new(String::from("@bob:matrix.org")),
+    );
+
+    // This device signed our master key, so we trust it.
+    let mut alice_master = serde_json::to_value(alice_identity.master_key()).unwrap();
+    alice_master["signatures"] = serde_json::json!({"@alice:matrix.org": {
+        "ed25519:ALICEDEVICE": alice_account
+            .sign(&canonical_json(&alice_master).unwrap())
+            .to_base64(),
+    }});
+    let mut forged = cross_signed_device(&bob_identity, &bob_account, "@bob:matrix.org", "FORGED");
+    forged["keys"]["curve25519:FORGED"] = serde_json::json!("forged");
+    let server = MockHomeserver::start(vec![
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({
+                "device_keys": {
+                    "@alice:matrix.org": {"ALICEOTHER": cross_signed_device(
+                        &alice_identity,
+                        &alice_other_account,
+                        "@alice:matrix.org",
+                        "ALICEOTHER",
+                    )},
+                    "@bob:matrix.org": {
+                        "BOBDEVICE": cross_signed_device(
+                            &bob_identity,
+                            &bob_account,
+                            "@bob:matrix.org",
+                            "BOBDEVICE",
+                        ),
+                        "FORGED": forged,
+                    },
+                },
+                "master_keys": {
+                    "@alice:matrix.org": alice_master,
+                    "@bob:matrix.org": bob_identity.master_key(),
+                },
+                "self_signing_keys": {
+                    "@alice:matrix.org": alice_identity.self_signing_key().unwrap(),
+                    "@bob:matrix.org": bob_identity.self_signing_key().unwrap(),
+                },
+            }),
+        ),
+        (
+            "/_matrix/client/r0/keys/query",
+            200,
+            serde_json::json!({
+                "device_keys": {"@bob:matrix.org": {"BOBDEVICE": cross_signed_device(
+                    &new_bob_identity,
+                    &bob_account,
+                    "@bob:matrix.org",
+                    "BOBDEVICE",
+                )}},
+                "master_keys": {"@bob:matrix.org": new_bob_identity.master_key()},
+                "self_signing_keys": {
+                    "@bob:matrix.org": new_bob_identity.self_signing_key().unwrap(),
+                },
+            }),
+        ),
+    ]);
+    let mut store = MemoryStore::new();
+    store.save_account(&alice_account).unwrap();
+    let mut alice = Device::with_store(
+        String::from("@alice:matrix.org"),
+        String::from("ALICEDEVICE"),
+        String::from("token"),
+        server.uri.clone(),
+        Box::new(store),
+    )
+    .unwrap();
+
+    let devices = alice
+        .query_devices(vec![
+            String::from("@alice:matrix.org"),
+            String::from("@bob:matrix.org"),
+        ])
+        .await
+        .unwrap();
+    let mut bob_devices: Vec<&String> = devices["@bob:matrix.org"].keys().collect();
+    bob_devices.sort();
+    assert_eq!(bob_devices, ["BOBDEVICE"]);
+    assert_eq!(
+        alice
+            .device_trust("@alice:matrix.org", "ALICEOTHER")
+            .unwrap(),
+        DeviceTrust::CrossSigned
+    );
+    // Bob's master key is pinned the first time we see it.
+    assert_eq!(
+        alice.device_trust("@bob:matrix.org", "BOBDEVICE").unwrap(),
+        DeviceTrust::Pinned
+    );
+
+    // A new master key is stored, but not trusted like the pinned one.
+    alice
+        .query_devices(vec![String::from("@bob:matrix.org")])
+        .await
+        .unwrap();
+    assert_eq!(
+        alice.device_trust("@bob:matrix.org", "BOBDEVICE").unwrap(),
+        DeviceTrust::Unverified
+    );
+}